@@ -0,0 +1,503 @@
+use libp2p::{
+    kad::{self, QueryId, QueryResult},
+    PeerId, Swarm,
+};
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
+
+use crate::events::SwapBytesBehaviour;
+use crate::files::FileMetadata;
+use crate::records::{self, PeerList};
+use crate::utils::NicknameMap;
+
+/// How long /list_files waits for the network before answering from the cache
+const LISTING_TIMEOUT: Duration = Duration::from_secs(5);
+const PAGE_SIZE: usize = 20;
+
+/// Provider key every uploader announces themselves under, so the whole network's publishers can
+/// be found even when we aren't connected to them
+pub const PUBLISHERS_KEY: &str = "publishers";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortBy {
+    Name,
+    Size,
+}
+
+/// How the user wants /list_files laid out
+#[derive(Debug, Clone)]
+pub struct ListOptions {
+    pub owner: Option<PeerId>,
+    pub sort: SortBy,
+    /// Starts at 1
+    pub page: usize,
+}
+
+/// A DHT lookup the catalog is waiting on
+enum Lookup {
+    Publishers,
+    Index(PeerId),
+    File(String),
+}
+
+/// A /list_files waiting for its lookups to come back
+struct Listing {
+    options: ListOptions,
+    deadline: Instant,
+    /// Owners whose file lists have already been asked for
+    asked: HashSet<PeerId>,
+}
+
+/// Everything we've learned about files on the network, from DHT lookups and upload announcements.
+/// Metadata is kept between listings so only files we haven't seen before need looking up.
+pub struct Catalog {
+    files: HashMap<String, FileMetadata>,
+    /// The hashes each owner last said they had
+    indexes: HashMap<String, Vec<String>>,
+    lookups: HashMap<QueryId, Lookup>,
+    listing: Option<Listing>,
+    /// Everyone who might have published files: the DHT's publisher registry, uploaders we've
+    /// heard announce over gossip, and peers found through rendezvous
+    known_peers: HashSet<PeerId>,
+}
+
+impl Catalog {
+    pub fn new() -> Self {
+        Catalog {
+            files: HashMap::new(),
+            indexes: HashMap::new(),
+            lookups: HashMap::new(),
+            listing: None,
+            known_peers: HashSet::new(),
+        }
+    }
+
+    /// Remember a peer so its files are included in future listings
+    pub fn add_known_peer(&mut self, peer: PeerId) {
+        self.known_peers.insert(peer);
+    }
+
+    /// Add or refresh a file's metadata, true if we hadn't seen it before
+    pub fn insert(&mut self, metadata: FileMetadata) -> bool {
+        if let Ok(owner) = metadata.owner.parse() {
+            self.known_peers.insert(owner);
+        }
+        let index = self.indexes.entry(metadata.owner.clone()).or_default();
+        if !index.contains(&metadata.hash) {
+            index.push(metadata.hash.clone());
+        }
+        self.files.insert(metadata.hash.clone(), metadata).is_none()
+    }
+
+    /// A file's metadata, which was signed by its owner when it came in
+    pub fn get(&self, hash: &str) -> Option<&FileMetadata> {
+        self.files.get(hash)
+    }
+
+    /// Every file we know a peer has shared, sorted by name
+    pub fn files_by(&self, owner: &PeerId) -> Vec<&FileMetadata> {
+        let mut files: Vec<&FileMetadata> = self
+            .indexes
+            .get(&owner.to_string())
+            .into_iter()
+            .flatten()
+            .filter_map(|hash| self.files.get(hash))
+            .collect();
+        files.sort_by_key(|file| file.filename.to_lowercase());
+        files
+    }
+
+    /// Refresh the file lists of the given owner, or of every publisher we know about or can find
+    /// in the DHT. The catalog is printed once they've answered, or after a few seconds with
+    /// whatever has arrived by then.
+    pub fn start_listing(&mut self, swarm: &mut Swarm<SwapBytesBehaviour>, options: ListOptions) {
+        let owners: Vec<PeerId> = match options.owner {
+            Some(owner) => vec![owner],
+            None => {
+                let queryid = swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .get_providers(kad::RecordKey::new(&PUBLISHERS_KEY));
+                self.lookups.insert(queryid, Lookup::Publishers);
+
+                let mut owners: HashSet<PeerId> = swarm.connected_peers().cloned().collect();
+                owners.extend(self.known_peers.iter().cloned());
+                owners.into_iter().collect()
+            }
+        };
+
+        self.listing = Some(Listing {
+            options,
+            deadline: Instant::now() + LISTING_TIMEOUT,
+            asked: HashSet::new(),
+        });
+        for owner in owners {
+            self.request_index(swarm, owner);
+        }
+    }
+
+    /// Look up an owner's file list, unless this listing already has
+    fn request_index(&mut self, swarm: &mut Swarm<SwapBytesBehaviour>, owner: PeerId) {
+        if owner == *swarm.local_peer_id() {
+            return;
+        }
+        let Some(listing) = &mut self.listing else {
+            return;
+        };
+        if !listing.asked.insert(owner) {
+            return;
+        }
+        let key = kad::RecordKey::new(&format!("file_index::{}", owner));
+        let queryid = swarm.behaviour_mut().kademlia.get_record(key);
+        self.lookups.insert(queryid, Lookup::Index(owner));
+    }
+
+    pub fn owns_query(&self, id: &QueryId) -> bool {
+        self.lookups.contains_key(id)
+    }
+
+    /// Merge a lookup result into the catalog, returning metadata for any files we hadn't seen
+    pub fn handle_query_result(
+        &mut self,
+        swarm: &mut Swarm<SwapBytesBehaviour>,
+        id: QueryId,
+        result: QueryResult,
+    ) -> Vec<FileMetadata> {
+        let mut found = Vec::new();
+        let peer_record = match result {
+            QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(peer_record))) => peer_record,
+
+            // More of the network's publishers, ask each for their file list
+            QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders {
+                providers,
+                ..
+            })) => {
+                for provider in providers {
+                    self.known_peers.insert(provider);
+                    self.request_index(swarm, provider);
+                }
+                return found;
+            }
+
+            // Nothing found, any cached copy will have to do
+            _ => {
+                self.lookups.remove(&id);
+                return found;
+            }
+        };
+
+        match self.lookups.get(&id) {
+            // Only trust an index that came from the network, our own copy may be stale
+            Some(Lookup::Index(_)) if peer_record.peer.is_none() => return found,
+            Some(_) => {}
+            None => return found,
+        }
+
+        // One answer is enough, stop the query so it doesn't report again
+        if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&id) {
+            query.finish();
+        }
+
+        let key = String::from_utf8_lossy(peer_record.record.key.as_ref()).to_string();
+        match self.lookups.remove(&id) {
+            Some(Lookup::Index(owner)) => {
+                match records::open::<PeerList<String>>(&key, &peer_record.record.value) {
+                    Ok((
+                        _,
+                        PeerList {
+                            entries: hashes, ..
+                        },
+                    )) => {
+                        // Only look up the files we don't already know about
+                        for hash in hashes.iter().filter(|hash| !self.files.contains_key(*hash)) {
+                            let key = kad::RecordKey::new(&format!("file::{}", hash));
+                            let queryid = swarm.behaviour_mut().kademlia.get_record(key);
+                            self.lookups.insert(queryid, Lookup::File(hash.clone()));
+                        }
+                        self.indexes.insert(owner.to_string(), hashes);
+                    }
+                    Err(e) => println!("Failed to parse file index for {key}: {e}"),
+                }
+            }
+
+            Some(Lookup::File(hash)) => {
+                let key = format!("file::{hash}");
+                match records::open_file_metadata(&key, &peer_record.record.value) {
+                    Ok((_, metadata)) => {
+                        if self.insert(metadata.clone()) {
+                            found.push(metadata);
+                        }
+                    }
+                    Err(e) => println!("Error deserializing file metadata: {e}"),
+                }
+            }
+
+            Some(Lookup::Publishers) | None => {}
+        }
+        found
+    }
+
+    /// Print the pending listing if every lookup has come back or it's waited long enough
+    pub fn poll_listing(&mut self, swarm: &Swarm<SwapBytesBehaviour>, nicknames: &NicknameMap) {
+        let Some(listing) = &self.listing else {
+            return;
+        };
+        let timed_out = Instant::now() >= listing.deadline;
+        if !self.lookups.is_empty() && !timed_out {
+            return;
+        }
+
+        if !self.lookups.is_empty() {
+            println!("Some peers are slow to answer, showing what's cached");
+        }
+        if let Some(listing) = self.listing.take() {
+            self.print(swarm, &listing.options, nicknames);
+        }
+    }
+
+    /// Print the catalog as a table grouped by owner, noting which owners are online
+    fn print(
+        &self,
+        swarm: &Swarm<SwapBytesBehaviour>,
+        options: &ListOptions,
+        nicknames: &NicknameMap,
+    ) {
+        let owner_filter = options.owner.map(|owner| owner.to_string());
+        let mut owners: Vec<&String> = self
+            .indexes
+            .keys()
+            .filter(|owner| owner_filter.as_ref().is_none_or(|filter| filter == *owner))
+            .collect();
+        owners.sort_by_key(|owner| nicknames.get(owner).to_lowercase());
+
+        // Rows keep the owner they were listed under, a reshared file shows up under each holder
+        let mut rows: Vec<(&String, &FileMetadata)> = Vec::new();
+        for owner in owners {
+            let mut files: Vec<&FileMetadata> = self.indexes[owner]
+                .iter()
+                .filter_map(|hash| self.files.get(hash))
+                .collect();
+            match options.sort {
+                SortBy::Name => files.sort_by_key(|file| file.filename.to_lowercase()),
+                SortBy::Size => files.sort_by_key(|file| Reverse(file.size)),
+            }
+            rows.extend(files.into_iter().map(|file| (owner, file)));
+        }
+
+        if rows.is_empty() {
+            println!("No files found");
+            return;
+        }
+
+        let pages = rows.len().div_ceil(PAGE_SIZE);
+        let page = options.page.clamp(1, pages);
+        println!(
+            "{} file{} on the network (page {page} of {pages}):",
+            rows.len(),
+            if rows.len() == 1 { "" } else { "s" }
+        );
+
+        let mut current_owner = None;
+        for (owner, file) in rows.iter().skip((page - 1) * PAGE_SIZE).take(PAGE_SIZE) {
+            if current_owner != Some(owner) {
+                current_owner = Some(owner);
+                let online = owner
+                    .parse::<PeerId>()
+                    .is_ok_and(|owner| swarm.is_connected(&owner));
+                println!(
+                    "{} ({}):",
+                    nicknames.get(owner),
+                    if online {
+                        "online"
+                    } else {
+                        "offline, try /providers for other holders"
+                    }
+                );
+            }
+            println!(
+                "\t{:<8}  {:<30}  {:>12} bytes  {}",
+                file.hash,
+                file.filename,
+                file.size,
+                file.description.as_deref().unwrap_or("No description")
+            );
+        }
+        if page < pages {
+            println!("Use --page {} to see more", page + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::get_swapbytes_behaviour;
+    use crate::files::LocalFileStore;
+    use libp2p::{identity::Keypair, noise, tcp, yamux};
+    use std::collections::BTreeMap;
+    use tempfile::TempDir;
+
+    fn test_swarm(dir: &TempDir) -> Swarm<SwapBytesBehaviour> {
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )
+            .unwrap()
+            .with_behaviour(|key| get_swapbytes_behaviour(key, dir.path()).unwrap())
+            .unwrap()
+            .build()
+    }
+
+    /// A file's metadata and the DHT record its owner would publish for it
+    fn signed_file(owner: &Keypair, data: &[u8]) -> (FileMetadata, kad::Record) {
+        let mut store = LocalFileStore::new();
+        let owner_id = owner.public().to_peer_id();
+        let hash = store.add_file(data.to_vec(), "file.txt", &owner_id, None);
+        let metadata = store.get_metadata(&hash).unwrap().clone();
+        let record = records::signed_record(owner, &format!("file::{hash}"), &metadata).unwrap();
+        (metadata, record)
+    }
+
+    fn found(record: kad::Record, peer: Option<PeerId>) -> QueryResult {
+        QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(kad::PeerRecord {
+            peer,
+            record,
+        })))
+    }
+
+    fn only_lookup(catalog: &Catalog) -> QueryId {
+        assert_eq!(catalog.lookups.len(), 1);
+        *catalog.lookups.keys().next().unwrap()
+    }
+
+    fn list_owner(catalog: &mut Catalog, swarm: &mut Swarm<SwapBytesBehaviour>, owner: PeerId) {
+        let options = ListOptions {
+            owner: Some(owner),
+            sort: SortBy::Name,
+            page: 1,
+        };
+        catalog.start_listing(swarm, options);
+    }
+
+    #[test]
+    fn insert_reports_new_files_once() {
+        let owner = Keypair::generate_ed25519();
+        let (metadata, _) = signed_file(&owner, b"data");
+
+        let mut catalog = Catalog::new();
+        assert!(catalog.insert(metadata.clone()));
+        assert!(!catalog.insert(metadata.clone()));
+        assert_eq!(catalog.indexes[&metadata.owner], [metadata.hash]);
+    }
+
+    #[tokio::test]
+    async fn indexes_from_the_network_fetch_unknown_files() {
+        let dir = TempDir::new().unwrap();
+        let mut swarm = test_swarm(&dir);
+        let owner = Keypair::generate_ed25519();
+        let owner_id = owner.public().to_peer_id();
+        let (known, _) = signed_file(&owner, b"known");
+        let (unknown, unknown_record) = signed_file(&owner, b"unknown");
+        let mut catalog = Catalog::new();
+        catalog.insert(known.clone());
+
+        list_owner(&mut catalog, &mut swarm, owner_id);
+        let index_query = only_lookup(&catalog);
+        let hashes = PeerList {
+            sequence: 1,
+            entries: vec![known.hash.clone(), unknown.hash.clone()],
+        };
+        let key = format!("file_index::{owner_id}");
+        let index = records::signed_record(&owner, &key, &hashes).unwrap();
+
+        // Our own copy of the index may be stale, so it doesn't answer the lookup
+        let new = catalog.handle_query_result(&mut swarm, index_query, found(index.clone(), None));
+        assert!(new.is_empty());
+        assert!(catalog.owns_query(&index_query));
+
+        let from = Some(PeerId::random());
+        catalog.handle_query_result(&mut swarm, index_query, found(index, from));
+        let file_query = only_lookup(&catalog);
+        assert!(matches!(
+            &catalog.lookups[&file_query],
+            Lookup::File(hash) if *hash == unknown.hash
+        ));
+
+        let new = catalog.handle_query_result(&mut swarm, file_query, found(unknown_record, from));
+        assert_eq!(new.len(), 1);
+        assert_eq!(new[0].hash, unknown.hash);
+        assert!(catalog.lookups.is_empty());
+    }
+
+    #[tokio::test]
+    async fn forged_metadata_is_ignored() {
+        let dir = TempDir::new().unwrap();
+        let mut swarm = test_swarm(&dir);
+        let (owner, forger) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let owner_id = owner.public().to_peer_id();
+        let (metadata, _) = signed_file(&owner, b"data");
+        let mut catalog = Catalog::new();
+
+        list_owner(&mut catalog, &mut swarm, owner_id);
+        let index_query = only_lookup(&catalog);
+        let key = format!("file_index::{owner_id}");
+        let hashes = PeerList {
+            sequence: 1,
+            entries: vec![metadata.hash.clone()],
+        };
+        let index = records::signed_record(&owner, &key, &hashes).unwrap();
+        let from = Some(PeerId::random());
+        catalog.handle_query_result(&mut swarm, index_query, found(index, from));
+
+        let file_query = only_lookup(&catalog);
+        let key = format!("file::{}", metadata.hash);
+        let forged = records::signed_record(&forger, &key, &metadata).unwrap();
+        let new = catalog.handle_query_result(&mut swarm, file_query, found(forged, from));
+        assert!(new.is_empty());
+        assert!(catalog.files.is_empty());
+    }
+
+    #[tokio::test]
+    async fn lookups_that_find_nothing_are_dropped() {
+        let dir = TempDir::new().unwrap();
+        let mut swarm = test_swarm(&dir);
+        let mut catalog = Catalog::new();
+        list_owner(&mut catalog, &mut swarm, PeerId::random());
+
+        let query = only_lookup(&catalog);
+        let finished =
+            QueryResult::GetRecord(Ok(kad::GetRecordOk::FinishedWithNoAdditionalRecord {
+                cache_candidates: BTreeMap::new(),
+            }));
+        assert!(catalog
+            .handle_query_result(&mut swarm, query, finished)
+            .is_empty());
+        assert!(!catalog.owns_query(&query));
+    }
+
+    #[test]
+    fn files_by_lists_an_owners_files_by_name() {
+        let owner = Keypair::generate_ed25519();
+        let mut catalog = Catalog::new();
+        let mut store = LocalFileStore::new();
+        let owner_id = owner.public().to_peer_id();
+        for name in ["b.txt", "A.txt", "c.txt"] {
+            let hash = store.add_file(name.as_bytes().to_vec(), name, &owner_id, None);
+            catalog.insert(store.get_metadata(&hash).unwrap().clone());
+        }
+
+        let names: Vec<&str> = catalog
+            .files_by(&owner_id)
+            .iter()
+            .map(|file| file.filename.as_str())
+            .collect();
+        assert_eq!(names, ["A.txt", "b.txt", "c.txt"]);
+        assert!(catalog.files_by(&PeerId::random()).is_empty());
+    }
+}
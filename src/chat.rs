@@ -0,0 +1,264 @@
+use chrono::{DateTime, Local};
+use libp2p::PeerId;
+use regex::Regex;
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::LazyLock,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::input::ChatMessage;
+use crate::utils::ChatState;
+
+/// How many chat messages are remembered for replies to refer back to
+const HISTORY_SIZE: usize = 500;
+
+/// Replies show this much of the message they're answering
+const QUOTE_LENGTH: usize = 40;
+
+/// An @ at the start of a word, and the name after it
+static MENTION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?:^|\s)@(\S+)").unwrap());
+
+/// A message in the log, and the private group it was sent to if it wasn't public
+pub struct LoggedMessage {
+    pub author: PeerId,
+    pub message: ChatMessage,
+    pub group: Option<String>,
+}
+
+/// Recent chat, ours included, so replies can show what they're replying to
+pub struct ChatLog {
+    messages: VecDeque<LoggedMessage>,
+}
+
+impl ChatLog {
+    pub fn new() -> Self {
+        ChatLog {
+            messages: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, author: PeerId, message: ChatMessage, group: Option<String>) {
+        if self.messages.len() == HISTORY_SIZE {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(LoggedMessage {
+            author,
+            message,
+            group,
+        });
+    }
+
+    /// A message by its author and ID, the latest if they've reused the ID
+    pub fn get(&self, author: &PeerId, id: &str) -> Option<&LoggedMessage> {
+        self.messages
+            .iter()
+            .rev()
+            .find(|logged| logged.author == *author && logged.message.id == id)
+    }
+
+    /// The latest message with an ID from each author that's used it
+    pub fn with_id(&self, id: &str) -> Vec<&LoggedMessage> {
+        let mut authors = HashSet::new();
+        self.messages
+            .iter()
+            .rev()
+            .filter(|logged| logged.message.id == id && authors.insert(logged.author))
+            .collect()
+    }
+}
+
+/// A short random ID for a new message
+pub fn new_message_id() -> String {
+    format!("{:08x}", rand::random::<u32>())
+}
+
+/// Seconds since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// Every name mentioned with an @ in a message, without trailing punctuation or repeats
+pub fn parse_mentions(text: &str) -> Vec<String> {
+    let mut mentions: Vec<String> = MENTION
+        .captures_iter(text)
+        .map(|cap| {
+            cap[1]
+                .trim_end_matches(|c: char| ",:;!?)".contains(c))
+                .to_string()
+        })
+        .filter(|name| !name.is_empty())
+        .collect();
+    mentions.sort();
+    mentions.dedup();
+    mentions
+}
+
+/// Print a chat message with its time and ID, under a quote of the message it replies to.
+/// Messages that mention or reply to us are highlighted, and group messages are marked with the
+/// group's name.
+pub fn print_message(
+    chat_state: &ChatState,
+    author: &PeerId,
+    chat: &ChatMessage,
+    group: Option<&str>,
+) {
+    let local_peer_id = chat_state.keypair.public().to_peer_id();
+    let original = chat.reply_to.as_ref().and_then(|reply_to| {
+        let author = reply_to.author.parse::<PeerId>().ok()?;
+        chat_state.chat_log.get(&author, &reply_to.id)
+    });
+
+    let indent = match &chat.reply_to {
+        Some(reply_to) => {
+            match original {
+                Some(LoggedMessage {
+                    author: original_author,
+                    message: original,
+                    ..
+                }) => println!(
+                    "  ↳ replying to {}: \"{}\"",
+                    if *original_author == local_peer_id {
+                        "you".to_string()
+                    } else {
+                        chat_state
+                            .nicknames
+                            .get(&original_author.to_string())
+                            .to_string()
+                    },
+                    quote(&original.message)
+                ),
+                None => println!("  ↳ replying to a message you didn't see ({})", reply_to.id),
+            }
+            "    "
+        }
+        None => "",
+    };
+
+    let line = format!(
+        "{indent}{}[{} {}] {}: {}",
+        group
+            .map(|group| format!("[#{group}] "))
+            .unwrap_or_default(),
+        format_time(chat.timestamp),
+        chat.id,
+        chat_state.nicknames.get(&author.to_string()),
+        chat.message
+    );
+    // Replies to our messages count as mentioning us
+    let mentions_us = chat.mentions.contains(&local_peer_id.to_string())
+        || parse_mentions(&chat.message).contains(&chat_state.nickname)
+        || original.is_some_and(|original| original.author == local_peer_id);
+    if mentions_us {
+        println!("\x1b[1;33m{line}\x1b[0m");
+    } else {
+        println!("{line}");
+    }
+}
+
+/// The start of a message, cut at a character boundary
+fn quote(message: &str) -> String {
+    if message.chars().count() <= QUOTE_LENGTH {
+        return message.to_string();
+    }
+    let start: String = message.chars().take(QUOTE_LENGTH).collect();
+    format!("{start}...")
+}
+
+/// Time of day as HH:MM, in our local time zone
+fn format_time(timestamp: u64) -> String {
+    i64::try_from(timestamp)
+        .ok()
+        .and_then(|seconds| DateTime::from_timestamp(seconds, 0))
+        .map(|time| time.with_timezone(&Local).format("%H:%M").to_string())
+        .unwrap_or_else(|| "--:--".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mentions_need_an_at_at_the_start_of_a_word() {
+        assert_eq!(
+            parse_mentions("@alice hi @bob.x1, mail me at me@example.com"),
+            vec!["alice", "bob.x1"]
+        );
+        assert!(parse_mentions("no mentions here @").is_empty());
+    }
+
+    #[test]
+    fn mentions_lose_trailing_punctuation() {
+        assert_eq!(parse_mentions("thanks @sam! (and @jo)"), vec!["jo", "sam"]);
+    }
+
+    #[test]
+    fn repeated_mentions_are_kept_once() {
+        assert_eq!(
+            parse_mentions("@sam @jo @sam, @jo: @sam?"),
+            vec!["jo", "sam"]
+        );
+    }
+
+    #[test]
+    fn quotes_are_cut_at_a_character_boundary() {
+        assert_eq!(quote("short"), "short");
+        let long = "é".repeat(QUOTE_LENGTH + 5);
+        assert_eq!(quote(&long), format!("{}...", "é".repeat(QUOTE_LENGTH)));
+    }
+
+    #[test]
+    fn times_are_shown_as_local_hours_and_minutes() {
+        use chrono::{TimeZone, Timelike};
+
+        let timestamp = 13 * 3600 + 7 * 60 + 59;
+        let local = Local.timestamp_opt(timestamp as i64, 0).unwrap();
+        assert_eq!(
+            format_time(timestamp),
+            format!("{:02}:{:02}", local.hour(), local.minute())
+        );
+        assert_eq!(format_time(u64::MAX), "--:--");
+    }
+
+    fn message(id: &str, text: &str) -> ChatMessage {
+        ChatMessage {
+            message: text.to_string(),
+            nickname: "sam".to_string(),
+            id: id.to_string(),
+            timestamp: 0,
+            reply_to: None,
+            mentions: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn log_finds_messages_by_author_and_id() {
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let mut log = ChatLog::new();
+        log.push(alice, message("0000000a", "first"), None);
+        log.push(bob, message("0000000a", "clash"), None);
+        log.push(alice, message("0000000a", "reused"), None);
+
+        assert_eq!(
+            log.get(&alice, "0000000a").unwrap().message.message,
+            "reused"
+        );
+        assert_eq!(log.get(&bob, "0000000a").unwrap().message.message, "clash");
+        assert!(log.get(&bob, "0000000b").is_none());
+        assert_eq!(log.with_id("0000000a").len(), 2);
+    }
+
+    #[test]
+    fn log_forgets_the_oldest_messages() {
+        let author = PeerId::random();
+        let mut log = ChatLog::new();
+        for i in 0..=HISTORY_SIZE {
+            log.push(author, message(&format!("{i:08x}"), "hi"), None);
+        }
+        assert!(log.get(&author, &format!("{:08x}", 0)).is_none());
+        assert!(log.get(&author, &format!("{:08x}", 1)).is_some());
+    }
+}
@@ -0,0 +1,363 @@
+use libp2p::{Multiaddr, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::storage::{read_cbor, write_cbor};
+
+/// Someone we've chosen to remember between sessions
+#[derive(Debug, Clone, Default)]
+pub struct Contact {
+    /// Our own name for them, shown instead of whatever nickname they pick
+    pub petname: Option<String>,
+    pub friend: bool,
+    /// Addresses to dial them on at startup, on top of whatever the DHT knows
+    pub addresses: Vec<Multiaddr>,
+    /// The nickname they were last seen using, so offline contacts can still be recognised
+    pub last_nickname: Option<String>,
+}
+
+impl Contact {
+    /// Nothing we chose to remember about them, only what we've picked up since
+    fn is_empty(&self) -> bool {
+        self.petname.is_none() && !self.friend && self.addresses.is_empty()
+    }
+}
+
+/// Serializable copy of a Contact, peer IDs and addresses are kept as strings
+#[derive(Serialize, Deserialize)]
+struct SavedContact {
+    peer: String,
+    petname: Option<String>,
+    friend: bool,
+    addresses: Vec<String>,
+    last_nickname: Option<String>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SavedContacts {
+    contacts: Vec<SavedContact>,
+    open_dms: bool,
+}
+
+/// Our contact book, saved to the data directory whenever it changes
+pub struct ContactBook {
+    contacts: HashMap<PeerId, Contact>,
+    /// Whether contacts can DM us when we aren't trading with them
+    open_dms: bool,
+    path: PathBuf,
+}
+
+impl ContactBook {
+    pub fn load(data_dir: &Path) -> Self {
+        let path = data_dir.join("contacts.cbor");
+        let saved = read_cbor::<SavedContacts>(&path).unwrap_or_default();
+        let contacts = saved
+            .contacts
+            .into_iter()
+            .filter_map(|saved| {
+                let peer = saved.peer.parse::<PeerId>().ok()?;
+                let contact = Contact {
+                    petname: saved.petname,
+                    friend: saved.friend,
+                    addresses: saved
+                        .addresses
+                        .iter()
+                        .filter_map(|a| a.parse().ok())
+                        .collect(),
+                    last_nickname: saved.last_nickname,
+                };
+                Some((peer, contact))
+            })
+            .collect();
+        ContactBook {
+            contacts,
+            open_dms: saved.open_dms,
+            path,
+        }
+    }
+
+    fn save(&self) {
+        let contacts = self
+            .contacts
+            .iter()
+            .map(|(peer, contact)| SavedContact {
+                peer: peer.to_string(),
+                petname: contact.petname.clone(),
+                friend: contact.friend,
+                addresses: contact.addresses.iter().map(|a| a.to_string()).collect(),
+                last_nickname: contact.last_nickname.clone(),
+            })
+            .collect();
+        let saved = SavedContacts {
+            contacts,
+            open_dms: self.open_dms,
+        };
+        write_cbor(&self.path, &saved);
+    }
+
+    pub fn open_dms(&self) -> bool {
+        self.open_dms
+    }
+
+    pub fn set_open_dms(&mut self, open_dms: bool) {
+        self.open_dms = open_dms;
+        self.save();
+    }
+
+    pub fn get(&self, peer: &PeerId) -> Option<&Contact> {
+        self.contacts.get(peer)
+    }
+
+    pub fn contains(&self, peer: &PeerId) -> bool {
+        self.contacts.contains_key(peer)
+    }
+
+    /// Every contact, sorted by the name we'd show for them
+    pub fn all(&self) -> Vec<(&PeerId, &Contact)> {
+        let mut contacts: Vec<(&PeerId, &Contact)> = self.contacts.iter().collect();
+        contacts.sort_by_key(|(peer, contact)| {
+            contact
+                .petname
+                .clone()
+                .or_else(|| contact.last_nickname.clone())
+                .unwrap_or_else(|| peer.to_string())
+                .to_lowercase()
+        });
+        contacts
+    }
+
+    /// The contact we've given a petname to
+    pub fn find_petname(&self, petname: &str) -> Option<PeerId> {
+        self.contacts
+            .iter()
+            .find(|(_, contact)| contact.petname.as_deref() == Some(petname))
+            .map(|(peer, _)| *peer)
+    }
+
+    /// Give a peer a petname, or clear it with None. Petnames have to be unique, so fails if
+    /// another contact already has it.
+    pub fn set_petname(&mut self, peer: PeerId, petname: Option<String>) -> Result<(), String> {
+        if let Some(petname) = &petname {
+            if let Some(holder) = self.find_petname(petname).filter(|holder| *holder != peer) {
+                return Err(format!("'{petname}' is already the petname of {holder}"));
+            }
+        }
+        let adding = petname.is_some();
+        self.update(peer, adding, |contact| contact.petname = petname);
+        Ok(())
+    }
+
+    pub fn set_friend(&mut self, peer: PeerId, friend: bool) {
+        self.update(peer, friend, |contact| contact.friend = friend);
+    }
+
+    /// Change a contact, only adding them if `adding` is set, so clearing something about a
+    /// stranger doesn't make them a contact. Contacts left with nothing to remember are dropped.
+    fn update(&mut self, peer: PeerId, adding: bool, change: impl FnOnce(&mut Contact)) {
+        let contact = if adding {
+            self.contacts.entry(peer).or_default()
+        } else {
+            let Some(contact) = self.contacts.get_mut(&peer) else {
+                return;
+            };
+            contact
+        };
+        change(contact);
+        if contact.is_empty() {
+            self.contacts.remove(&peer);
+        }
+        self.save();
+    }
+
+    /// Add addresses to dial a peer on, false if they were all pinned already
+    pub fn pin(&mut self, peer: PeerId, addresses: Vec<Multiaddr>) -> bool {
+        let contact = self.contacts.entry(peer).or_default();
+        let before = contact.addresses.len();
+        for address in addresses {
+            if !contact.addresses.contains(&address) {
+                contact.addresses.push(address);
+            }
+        }
+        let added = contact.addresses.len() > before;
+        self.save();
+        added
+    }
+
+    pub fn remove(&mut self, peer: &PeerId) -> Option<Contact> {
+        let contact = self.contacts.remove(peer)?;
+        self.save();
+        Some(contact)
+    }
+
+    /// Remember the nickname a contact is using, ignoring everyone else
+    pub fn saw_nickname(&mut self, peer: &PeerId, nickname: &str) {
+        let Some(contact) = self.contacts.get_mut(peer) else {
+            return;
+        };
+        if contact.last_nickname.as_deref() != Some(nickname) {
+            contact.last_nickname = Some(nickname.to_string());
+            self.save();
+        }
+    }
+}
+
+/// Peers we never want to hear from, saved to the data directory whenever it changes
+pub struct BlockList {
+    peers: HashSet<PeerId>,
+    path: PathBuf,
+}
+
+impl BlockList {
+    pub fn load(data_dir: &Path) -> Self {
+        let path = data_dir.join("blocked.cbor");
+        let peers = read_cbor::<Vec<String>>(&path)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|peer| peer.parse().ok())
+            .collect();
+        BlockList { peers, path }
+    }
+
+    fn save(&self) {
+        let saved: Vec<String> = self.peers.iter().map(PeerId::to_string).collect();
+        write_cbor(&self.path, &saved);
+    }
+
+    pub fn contains(&self, peer: &PeerId) -> bool {
+        self.peers.contains(peer)
+    }
+
+    pub fn all(&self) -> Vec<PeerId> {
+        let mut peers: Vec<PeerId> = self.peers.iter().cloned().collect();
+        peers.sort();
+        peers
+    }
+
+    /// False if they were already blocked
+    pub fn block(&mut self, peer: PeerId) -> bool {
+        let added = self.peers.insert(peer);
+        self.save();
+        added
+    }
+
+    /// False if they weren't blocked
+    pub fn unblock(&mut self, peer: &PeerId) -> bool {
+        let removed = self.peers.remove(peer);
+        self.save();
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn contacts_are_saved_between_sessions() {
+        let dir = TempDir::new().unwrap();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let address: Multiaddr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        let mut contacts = ContactBook::load(dir.path());
+        contacts.set_petname(alice, Some("al".to_string())).unwrap();
+        contacts.set_friend(alice, true);
+        assert!(contacts.pin(bob, vec![address.clone()]));
+        assert!(!contacts.pin(bob, vec![address.clone()]));
+        contacts.saw_nickname(&bob, "bob.12345");
+
+        let contacts = ContactBook::load(dir.path());
+        let alice_contact = contacts.get(&alice).unwrap();
+        assert_eq!(alice_contact.petname.as_deref(), Some("al"));
+        assert!(alice_contact.friend);
+        let bob_contact = contacts.get(&bob).unwrap();
+        assert_eq!(bob_contact.addresses, [address]);
+        assert_eq!(bob_contact.last_nickname.as_deref(), Some("bob.12345"));
+    }
+
+    #[test]
+    fn petnames_are_unique() {
+        let dir = TempDir::new().unwrap();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let mut contacts = ContactBook::load(dir.path());
+        contacts
+            .set_petname(alice, Some("pal".to_string()))
+            .unwrap();
+        assert!(contacts.set_petname(bob, Some("pal".to_string())).is_err());
+        assert!(contacts.set_petname(alice, Some("pal".to_string())).is_ok());
+        assert_eq!(contacts.find_petname("pal"), Some(alice));
+    }
+
+    #[test]
+    fn nicknames_are_only_remembered_for_contacts() {
+        let dir = TempDir::new().unwrap();
+        let stranger = PeerId::random();
+        let mut contacts = ContactBook::load(dir.path());
+        contacts.saw_nickname(&stranger, "eve.12345");
+        assert!(!contacts.contains(&stranger));
+    }
+
+    #[test]
+    fn contacts_are_sorted_by_the_name_shown() {
+        let dir = TempDir::new().unwrap();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        let mut contacts = ContactBook::load(dir.path());
+        contacts.set_petname(bob, Some("Zed".to_string())).unwrap();
+        contacts.set_friend(alice, true);
+        contacts.saw_nickname(&alice, "alice.12345");
+
+        let order: Vec<PeerId> = contacts.all().iter().map(|(peer, _)| **peer).collect();
+        assert_eq!(order, [alice, bob]);
+    }
+
+    #[test]
+    fn clearing_does_not_add_contacts() {
+        let dir = TempDir::new().unwrap();
+        let stranger = PeerId::random();
+        let mut contacts = ContactBook::load(dir.path());
+        contacts.set_friend(stranger, false);
+        contacts.set_petname(stranger, None).unwrap();
+        assert!(!contacts.contains(&stranger));
+    }
+
+    #[test]
+    fn contacts_with_nothing_left_are_dropped() {
+        let dir = TempDir::new().unwrap();
+        let peer = PeerId::random();
+        let mut contacts = ContactBook::load(dir.path());
+        contacts.set_friend(peer, true);
+        contacts.set_petname(peer, Some("pal".to_string())).unwrap();
+        contacts.set_friend(peer, false);
+        assert!(contacts.contains(&peer));
+        contacts.set_petname(peer, None).unwrap();
+        assert!(!contacts.contains(&peer));
+        assert!(ContactBook::load(dir.path()).all().is_empty());
+    }
+
+    #[test]
+    fn block_list_is_saved_between_sessions() {
+        let dir = TempDir::new().unwrap();
+        let (eve, mallory) = (PeerId::random(), PeerId::random());
+        let mut blocked = BlockList::load(dir.path());
+        assert!(blocked.block(eve));
+        assert!(!blocked.block(eve));
+        assert!(blocked.block(mallory));
+        assert!(blocked.unblock(&mallory));
+        assert!(!blocked.unblock(&mallory));
+
+        let blocked = BlockList::load(dir.path());
+        assert!(blocked.contains(&eve));
+        assert_eq!(blocked.all(), [eve]);
+    }
+
+    #[test]
+    fn open_dms_setting_is_saved() {
+        let dir = TempDir::new().unwrap();
+        let mut contacts = ContactBook::load(dir.path());
+        assert!(!contacts.open_dms());
+        contacts.set_open_dms(true);
+        assert!(ContactBook::load(dir.path()).open_dms());
+    }
+}
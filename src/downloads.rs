@@ -0,0 +1,699 @@
+use libp2p::{
+    kad::{self, QueryId, QueryResult},
+    request_response::{OutboundFailure, OutboundRequestId},
+    PeerId, Swarm,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use crate::events::SwapBytesBehaviour;
+use crate::files::{
+    compute_chunk_hash, compute_hash, merkle_root, ChunkRequest, ChunkResponse, FileMetadata,
+};
+use crate::records;
+
+/// How many chunk requests a peer starts with, and the most it can ever have open
+const INITIAL_WINDOW: usize = 2;
+const MAX_WINDOW: usize = 8;
+
+/// How a single provider is performing in a download
+struct Source {
+    /// Number of chunk requests this peer is allowed to have open at once.
+    /// Grows while the peer answers quickly, shrinks when it times out.
+    window: usize,
+    in_flight: usize,
+}
+
+/// What an open request to a source is asking for
+#[derive(Debug, Clone, Copy)]
+enum Pending {
+    Tree,
+    Chunk(usize),
+}
+
+/// A file being pulled in pieces from everyone who holds it
+struct Download {
+    metadata: Option<FileMetadata>,
+    sources: HashMap<PeerId, Source>,
+    /// Chunk hashes, only set once they've been checked against the Merkle root
+    leaves: Option<Vec<String>>,
+    chunks: Vec<Option<Vec<u8>>>,
+    queue: VecDeque<usize>,
+    in_flight: HashMap<OutboundRequestId, (PeerId, Pending)>,
+    /// Peers that have already failed to give us a valid copy of a chunk
+    failed: HashMap<usize, HashSet<PeerId>>,
+    /// Peers that have already failed to give us a valid chunk tree
+    tree_failed: HashSet<PeerId>,
+    record_lookup_done: bool,
+    provider_lookup_done: bool,
+}
+
+impl Download {
+    fn new() -> Self {
+        Download {
+            metadata: None,
+            sources: HashMap::new(),
+            leaves: None,
+            chunks: Vec::new(),
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
+            failed: HashMap::new(),
+            tree_failed: HashSet::new(),
+            record_lookup_done: false,
+            provider_lookup_done: false,
+        }
+    }
+
+    fn add_source(&mut self, peer: PeerId) {
+        self.sources.entry(peer).or_insert(Source {
+            window: INITIAL_WINDOW,
+            in_flight: 0,
+        });
+    }
+
+    fn is_complete(&self) -> bool {
+        self.chunks.iter().all(|chunk| chunk.is_some())
+    }
+
+    /// Put an unanswered request's work back up for grabs
+    fn requeue(&mut self, pending: Pending) {
+        if let Pending::Chunk(index) = pending {
+            self.queue.push_front(index);
+        }
+    }
+
+    /// Peer with the most spare room that hasn't already let us down
+    fn best_source(&self, excluded: Option<&HashSet<PeerId>>) -> Option<PeerId> {
+        self.sources
+            .iter()
+            .filter(|(peer, _)| !excluded.is_some_and(|excluded| excluded.contains(*peer)))
+            .filter(|(_, source)| source.in_flight < source.window)
+            .max_by_key(|(_, source)| source.window - source.in_flight)
+            .map(|(peer, _)| *peer)
+    }
+}
+
+/// What happened to a download after an event
+pub enum DownloadStatus {
+    InProgress,
+    Complete(FileMetadata, Vec<u8>),
+    Failed(String),
+}
+
+/// Tracks every multi-source download, and which DHT queries and chunk requests belong to them
+pub struct Downloads {
+    active: HashMap<String, Download>,
+    queries: HashMap<QueryId, String>,
+    requests: HashMap<OutboundRequestId, String>,
+}
+
+impl Downloads {
+    pub fn new() -> Self {
+        Downloads {
+            active: HashMap::new(),
+            queries: HashMap::new(),
+            requests: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.active.contains_key(hash)
+    }
+
+    /// Look up the file's metadata and everyone who holds it, chunks are requested once both arrive
+    pub fn start(&mut self, swarm: &mut Swarm<SwapBytesBehaviour>, hash: &str) {
+        let key = kad::RecordKey::new(&format!("file::{}", hash));
+        let record_query = swarm.behaviour_mut().kademlia.get_record(key.clone());
+        let provider_query = swarm.behaviour_mut().kademlia.get_providers(key);
+
+        self.queries.insert(record_query, hash.to_string());
+        self.queries.insert(provider_query, hash.to_string());
+        self.active.insert(hash.to_string(), Download::new());
+    }
+
+    pub fn owns_query(&self, id: &QueryId) -> bool {
+        self.queries.contains_key(id)
+    }
+
+    /// Handle the metadata and provider lookups started in `start`
+    pub fn handle_query_result(
+        &mut self,
+        swarm: &mut Swarm<SwapBytesBehaviour>,
+        id: QueryId,
+        result: QueryResult,
+    ) -> DownloadStatus {
+        let Some(hash) = self.queries.get(&id).cloned() else {
+            return DownloadStatus::InProgress;
+        };
+        let Some(download) = self.active.get_mut(&hash) else {
+            self.queries.remove(&id);
+            return DownloadStatus::InProgress;
+        };
+
+        match result {
+            QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(peer_record))) => {
+                if download.metadata.is_none() {
+                    let key = format!("file::{}", hash);
+                    if let Ok((_, metadata)) =
+                        records::open::<FileMetadata>(&key, &peer_record.record.value)
+                    {
+                        download.chunks = vec![None; metadata.chunk_count];
+                        download.queue = (0..metadata.chunk_count).collect();
+                        download.metadata = Some(metadata);
+                    }
+                }
+                return DownloadStatus::InProgress;
+            }
+            QueryResult::GetRecord(_) => {
+                self.queries.remove(&id);
+                download.record_lookup_done = true;
+            }
+            QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders {
+                providers,
+                ..
+            })) => {
+                let local_peer_id = *swarm.local_peer_id();
+                for provider in providers {
+                    if provider != local_peer_id {
+                        download.add_source(provider);
+                    }
+                }
+                return DownloadStatus::InProgress;
+            }
+            QueryResult::GetProviders(_) => {
+                self.queries.remove(&id);
+                download.provider_lookup_done = true;
+            }
+            _ => return DownloadStatus::InProgress,
+        }
+
+        if !download.record_lookup_done || !download.provider_lookup_done {
+            return DownloadStatus::InProgress;
+        }
+
+        let Some(metadata) = download.metadata.clone() else {
+            return self.fail(&hash, "couldn't find the file's metadata".to_string());
+        };
+        if metadata.merkle_root.is_empty() {
+            return self.fail(&hash, "the file has no chunk tree".to_string());
+        }
+
+        // The uploader holds the file even if their provider record hasn't reached us
+        if let Ok(owner) = metadata.owner.parse::<PeerId>() {
+            if owner != *swarm.local_peer_id() {
+                download.add_source(owner);
+            }
+        }
+
+        println!(
+            "Downloading '{}' ({} chunks) from {} peer{}",
+            metadata.filename,
+            metadata.chunk_count,
+            download.sources.len(),
+            if download.sources.len() == 1 { "" } else { "s" }
+        );
+        self.schedule(swarm, &hash)
+    }
+
+    /// A chunk tree or chunk came back, check it and ask for more
+    pub fn handle_response(
+        &mut self,
+        swarm: &mut Swarm<SwapBytesBehaviour>,
+        peer: PeerId,
+        request_id: OutboundRequestId,
+        response: ChunkResponse,
+    ) -> DownloadStatus {
+        let Some(hash) = self.requests.remove(&request_id) else {
+            return DownloadStatus::InProgress;
+        };
+        let Some(download) = self.active.get_mut(&hash) else {
+            return DownloadStatus::InProgress;
+        };
+        let Some((_, pending)) = download.in_flight.remove(&request_id) else {
+            return DownloadStatus::InProgress;
+        };
+        if let Some(source) = download.sources.get_mut(&peer) {
+            source.in_flight = source.in_flight.saturating_sub(1);
+        }
+        let Some(metadata) = &download.metadata else {
+            return DownloadStatus::InProgress;
+        };
+
+        match (pending, response) {
+            // Only trust the chunk hashes if they add up to the signed root
+            (Pending::Tree, ChunkResponse::Tree(Some(leaves)))
+                if leaves.len() == metadata.chunk_count
+                    && merkle_root(&leaves) == metadata.merkle_root =>
+            {
+                download.leaves = Some(leaves);
+            }
+
+            (Pending::Chunk(index), ChunkResponse::Chunk(Some(data)))
+                if download
+                    .leaves
+                    .as_ref()
+                    .and_then(|leaves| leaves.get(index))
+                    == Some(&compute_chunk_hash(&data)) =>
+            {
+                download.chunks[index] = Some(data);
+                if let Some(source) = download.sources.get_mut(&peer) {
+                    source.window = (source.window + 1).min(MAX_WINDOW);
+                }
+            }
+
+            // The peer doesn't have the file after all
+            (pending, ChunkResponse::Tree(None) | ChunkResponse::Chunk(None)) => {
+                download.sources.remove(&peer);
+                download.requeue(pending);
+            }
+
+            // Bad chunk tree, ask someone else for it
+            (Pending::Tree, _) => {
+                eprintln!("Chunk tree of {hash} from {peer} doesn't match its Merkle root");
+                download.tree_failed.insert(peer);
+            }
+
+            // Corrupt chunk, get it from someone else
+            (Pending::Chunk(index), _) => {
+                eprintln!("Chunk {index} of {hash} from {peer} failed verification");
+                download.failed.entry(index).or_default().insert(peer);
+                download.queue.push_front(index);
+            }
+        }
+
+        self.schedule(swarm, &hash)
+    }
+
+    /// A chunk request failed or timed out, hand the chunk to someone else
+    pub fn handle_failure(
+        &mut self,
+        swarm: &mut Swarm<SwapBytesBehaviour>,
+        peer: PeerId,
+        request_id: OutboundRequestId,
+        error: &OutboundFailure,
+    ) -> DownloadStatus {
+        let Some(hash) = self.requests.remove(&request_id) else {
+            return DownloadStatus::InProgress;
+        };
+        let Some(download) = self.active.get_mut(&hash) else {
+            return DownloadStatus::InProgress;
+        };
+        let Some((_, pending)) = download.in_flight.remove(&request_id) else {
+            return DownloadStatus::InProgress;
+        };
+        download.requeue(pending);
+
+        match error {
+            // Slow peer, give it fewer chunks at a time
+            OutboundFailure::Timeout => {
+                if let Some(source) = download.sources.get_mut(&peer) {
+                    source.in_flight = source.in_flight.saturating_sub(1);
+                    source.window = 1;
+                }
+                if let Pending::Tree = pending {
+                    download.tree_failed.insert(peer);
+                }
+            }
+            // Peer is gone or can't serve chunks, stop using it
+            _ => {
+                download.sources.remove(&peer);
+            }
+        }
+
+        self.schedule(swarm, &hash)
+    }
+
+    /// A peer disconnected, drop it from every download and requeue its chunks
+    pub fn remove_peer(&mut self, swarm: &mut Swarm<SwapBytesBehaviour>, peer: &PeerId) {
+        let hashes: Vec<String> = self
+            .active
+            .iter()
+            .filter(|(_, download)| download.sources.contains_key(peer))
+            .map(|(hash, _)| hash.clone())
+            .collect();
+
+        for hash in hashes {
+            let Some(download) = self.active.get_mut(&hash) else {
+                continue;
+            };
+            download.sources.remove(peer);
+
+            let lost: Vec<OutboundRequestId> = download
+                .in_flight
+                .iter()
+                .filter(|(_, (source, _))| source == peer)
+                .map(|(id, _)| *id)
+                .collect();
+            for id in lost {
+                if let Some((_, pending)) = download.in_flight.remove(&id) {
+                    download.requeue(pending);
+                }
+                self.requests.remove(&id);
+            }
+
+            if let DownloadStatus::Failed(reason) = self.schedule(swarm, &hash) {
+                eprintln!("Download of {hash} failed: {reason}");
+            }
+        }
+    }
+
+    /// Fetch the chunk tree if we don't have it yet, then hand out queued chunks to the sources
+    /// with the most spare room
+    fn schedule(&mut self, swarm: &mut Swarm<SwapBytesBehaviour>, hash: &str) -> DownloadStatus {
+        let Some(download) = self.active.get_mut(hash) else {
+            return DownloadStatus::InProgress;
+        };
+        if !download.record_lookup_done || !download.provider_lookup_done {
+            return DownloadStatus::InProgress;
+        }
+        if download.leaves.is_some() && download.is_complete() {
+            return self.finish(hash);
+        }
+
+        if download.leaves.is_none() {
+            let tree_requested = download
+                .in_flight
+                .values()
+                .any(|(_, pending)| matches!(pending, Pending::Tree));
+            if tree_requested {
+                return DownloadStatus::InProgress;
+            }
+
+            let Some(peer) = download.best_source(Some(&download.tree_failed)) else {
+                let reason = if download.sources.is_empty() {
+                    "no peers are sharing this file"
+                } else {
+                    "nobody sent a chunk tree matching the Merkle root"
+                };
+                return self.fail(hash, reason.to_string());
+            };
+            let request_id = swarm.behaviour_mut().chunk_transfer.send_request(
+                &peer,
+                ChunkRequest::Tree {
+                    hash: hash.to_string(),
+                },
+            );
+            download.in_flight.insert(request_id, (peer, Pending::Tree));
+            if let Some(source) = download.sources.get_mut(&peer) {
+                source.in_flight += 1;
+            }
+            self.requests.insert(request_id, hash.to_string());
+            return DownloadStatus::InProgress;
+        }
+
+        let mut skipped = VecDeque::new();
+        while let Some(index) = download.queue.pop_front() {
+            let Some(peer) = download.best_source(download.failed.get(&index)) else {
+                skipped.push_back(index);
+                continue;
+            };
+
+            let request_id = swarm.behaviour_mut().chunk_transfer.send_request(
+                &peer,
+                ChunkRequest::Chunk {
+                    hash: hash.to_string(),
+                    index,
+                },
+            );
+            download
+                .in_flight
+                .insert(request_id, (peer, Pending::Chunk(index)));
+            if let Some(source) = download.sources.get_mut(&peer) {
+                source.in_flight += 1;
+            }
+            self.requests.insert(request_id, hash.to_string());
+        }
+        download.queue = skipped;
+
+        // Nothing left in flight and nobody left to ask
+        if download.in_flight.is_empty() && !download.queue.is_empty() {
+            let reason = if download.sources.is_empty() {
+                "no peers are sharing this file"
+            } else {
+                "every peer sent a bad copy of a chunk"
+            };
+            return self.fail(hash, reason.to_string());
+        }
+        DownloadStatus::InProgress
+    }
+
+    /// Put the chunks back together and check the whole file against its hash
+    fn finish(&mut self, hash: &str) -> DownloadStatus {
+        let Some(download) = self.active.remove(hash) else {
+            return DownloadStatus::InProgress;
+        };
+        let Some(metadata) = download.metadata else {
+            return DownloadStatus::Failed("couldn't find the file's metadata".to_string());
+        };
+
+        let file: Vec<u8> = download.chunks.into_iter().flatten().flatten().collect();
+        if compute_hash(&file) != metadata.hash {
+            return DownloadStatus::Failed("the assembled file doesn't match its hash".to_string());
+        }
+        DownloadStatus::Complete(metadata, file)
+    }
+
+    fn fail(&mut self, hash: &str, reason: String) -> DownloadStatus {
+        if let Some(download) = self.active.remove(hash) {
+            for id in download.in_flight.keys() {
+                self.requests.remove(id);
+            }
+        }
+        self.queries.retain(|_, query_hash| query_hash != hash);
+        DownloadStatus::Failed(reason)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::get_swapbytes_behaviour;
+    use crate::files::{chunk_hashes, LocalFileStore, CHUNK_SIZE};
+    use libp2p::{noise, tcp, yamux};
+    use tempfile::TempDir;
+
+    fn test_swarm(dir: &TempDir) -> Swarm<SwapBytesBehaviour> {
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )
+            .unwrap()
+            .with_behaviour(|key| get_swapbytes_behaviour(key, dir.path()).unwrap())
+            .unwrap()
+            .build()
+    }
+
+    /// Set up a download whose metadata and providers have already been found
+    fn add_download(downloads: &mut Downloads, data: &[u8], sources: &[PeerId]) -> String {
+        let mut store = LocalFileStore::new();
+        let hash = store.add_file(data.to_vec(), "file.bin", &PeerId::random(), None);
+        let metadata = store.get_metadata(&hash).unwrap().clone();
+
+        let mut download = Download::new();
+        download.chunks = vec![None; metadata.chunk_count];
+        download.queue = (0..metadata.chunk_count).collect();
+        download.metadata = Some(metadata);
+        download.record_lookup_done = true;
+        download.provider_lookup_done = true;
+        for peer in sources {
+            download.add_source(*peer);
+        }
+        downloads.active.insert(hash.clone(), download);
+        hash
+    }
+
+    fn in_flight(downloads: &Downloads, hash: &str) -> Vec<(OutboundRequestId, PeerId, Pending)> {
+        let mut requests: Vec<_> = downloads.active[hash]
+            .in_flight
+            .iter()
+            .map(|(id, (peer, pending))| (*id, *peer, *pending))
+            .collect();
+        requests.sort_by_key(|(_, _, pending)| match pending {
+            Pending::Tree => 0,
+            Pending::Chunk(index) => index + 1,
+        });
+        requests
+    }
+
+    fn chunk(data: &[u8], index: usize) -> ChunkResponse {
+        ChunkResponse::Chunk(Some(data.chunks(CHUNK_SIZE).nth(index).unwrap().to_vec()))
+    }
+
+    /// Ask for the chunk tree and answer it, which sends out the first chunk requests
+    fn fetch_tree(swarm: &mut Swarm<SwapBytesBehaviour>, downloads: &mut Downloads, data: &[u8]) {
+        let hash = compute_hash(data);
+        downloads.schedule(swarm, &hash);
+        let (id, peer, pending) = in_flight(downloads, &hash)[0];
+        assert!(matches!(pending, Pending::Tree));
+        let leaves = ChunkResponse::Tree(Some(chunk_hashes(data)));
+        downloads.handle_response(swarm, peer, id, leaves);
+    }
+
+    fn index(pending: Pending) -> usize {
+        match pending {
+            Pending::Chunk(index) => index,
+            Pending::Tree => panic!("expected a chunk request"),
+        }
+    }
+
+    #[tokio::test]
+    async fn chunks_are_spread_within_each_window() {
+        let dir = TempDir::new().unwrap();
+        let mut swarm = test_swarm(&dir);
+        let mut downloads = Downloads::new();
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let data = vec![1; 10 * CHUNK_SIZE];
+        let hash = add_download(&mut downloads, &data, &[a, b]);
+        fetch_tree(&mut swarm, &mut downloads, &data);
+
+        let requests = in_flight(&downloads, &hash);
+        assert_eq!(requests.len(), 2 * INITIAL_WINDOW);
+        for peer in [a, b] {
+            let sent = requests.iter().filter(|(_, p, _)| *p == peer).count();
+            assert_eq!(sent, INITIAL_WINDOW);
+        }
+        assert_eq!(downloads.active[&hash].queue.len(), 10 - 2 * INITIAL_WINDOW);
+    }
+
+    #[tokio::test]
+    async fn good_chunks_grow_the_window_until_the_file_is_done() {
+        let dir = TempDir::new().unwrap();
+        let mut swarm = test_swarm(&dir);
+        let mut downloads = Downloads::new();
+        let peer = PeerId::random();
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE).map(|i| i as u8).collect();
+        let hash = add_download(&mut downloads, &data, &[peer]);
+        fetch_tree(&mut swarm, &mut downloads, &data);
+
+        let (id, _, pending) = in_flight(&downloads, &hash)[0];
+        downloads.handle_response(&mut swarm, peer, id, chunk(&data, index(pending)));
+        assert_eq!(
+            downloads.active[&hash].sources[&peer].window,
+            INITIAL_WINDOW + 1
+        );
+
+        let mut status = DownloadStatus::InProgress;
+        while downloads.contains(&hash) {
+            let (id, _, pending) = in_flight(&downloads, &hash)[0];
+            status = downloads.handle_response(&mut swarm, peer, id, chunk(&data, index(pending)));
+        }
+        let DownloadStatus::Complete(metadata, file) = status else {
+            panic!("download didn't complete");
+        };
+        assert_eq!(metadata.hash, hash);
+        assert_eq!(file, data);
+    }
+
+    #[tokio::test]
+    async fn trees_that_miss_the_root_are_asked_for_elsewhere() {
+        let dir = TempDir::new().unwrap();
+        let mut swarm = test_swarm(&dir);
+        let mut downloads = Downloads::new();
+        let data = b"one chunk";
+        let hash = add_download(&mut downloads, data, &[PeerId::random(), PeerId::random()]);
+        downloads.schedule(&mut swarm, &hash);
+
+        let forged = || ChunkResponse::Tree(Some(chunk_hashes(b"another chunk")));
+        let (id, first, _) = in_flight(&downloads, &hash)[0];
+        let status = downloads.handle_response(&mut swarm, first, id, forged());
+        assert!(matches!(status, DownloadStatus::InProgress));
+        let (id, second, pending) = in_flight(&downloads, &hash)[0];
+        assert!(matches!(pending, Pending::Tree));
+        assert_ne!(first, second);
+
+        let status = downloads.handle_response(&mut swarm, second, id, forged());
+        assert!(matches!(status, DownloadStatus::Failed(_)));
+        assert!(!downloads.contains(&hash));
+    }
+
+    #[tokio::test]
+    async fn bad_chunks_are_asked_for_elsewhere() {
+        let dir = TempDir::new().unwrap();
+        let mut swarm = test_swarm(&dir);
+        let mut downloads = Downloads::new();
+        let data = b"one chunk";
+        let hash = add_download(&mut downloads, data, &[PeerId::random(), PeerId::random()]);
+        fetch_tree(&mut swarm, &mut downloads, data);
+
+        let garbage = || ChunkResponse::Chunk(Some(b"garbage".to_vec()));
+        let (id, first, _) = in_flight(&downloads, &hash)[0];
+        let status = downloads.handle_response(&mut swarm, first, id, garbage());
+        assert!(matches!(status, DownloadStatus::InProgress));
+        let (id, second, _) = in_flight(&downloads, &hash)[0];
+        assert_ne!(first, second);
+
+        let status = downloads.handle_response(&mut swarm, second, id, garbage());
+        assert!(matches!(status, DownloadStatus::Failed(_)));
+        assert!(!downloads.contains(&hash));
+    }
+
+    #[tokio::test]
+    async fn peers_without_the_file_are_dropped() {
+        let dir = TempDir::new().unwrap();
+        let mut swarm = test_swarm(&dir);
+        let mut downloads = Downloads::new();
+        let data = vec![1; 4 * CHUNK_SIZE];
+        let hash = add_download(&mut downloads, &data, &[PeerId::random(), PeerId::random()]);
+        fetch_tree(&mut swarm, &mut downloads, &data);
+
+        let (id, peer, pending) = in_flight(&downloads, &hash)[0];
+        downloads.handle_response(&mut swarm, peer, id, ChunkResponse::Chunk(None));
+        assert!(!downloads.active[&hash].sources.contains_key(&peer));
+        assert!(downloads.active[&hash].queue.contains(&index(pending)));
+    }
+
+    #[tokio::test]
+    async fn timeouts_shrink_the_window() {
+        let dir = TempDir::new().unwrap();
+        let mut swarm = test_swarm(&dir);
+        let mut downloads = Downloads::new();
+        let peer = PeerId::random();
+        let data = vec![1; 3 * CHUNK_SIZE];
+        let hash = add_download(&mut downloads, &data, &[peer]);
+        fetch_tree(&mut swarm, &mut downloads, &data);
+
+        let (id, _, pending) = in_flight(&downloads, &hash)[0];
+        downloads.handle_failure(&mut swarm, peer, id, &OutboundFailure::Timeout);
+        let download = &downloads.active[&hash];
+        assert_eq!(download.sources[&peer].window, 1);
+        assert_eq!(download.in_flight.len(), 1);
+        assert!(download.queue.contains(&index(pending)));
+    }
+
+    #[tokio::test]
+    async fn unreachable_sources_are_dropped() {
+        let dir = TempDir::new().unwrap();
+        let mut swarm = test_swarm(&dir);
+        let mut downloads = Downloads::new();
+        let peer = PeerId::random();
+        let data = b"one chunk";
+        let hash = add_download(&mut downloads, data, &[peer]);
+        fetch_tree(&mut swarm, &mut downloads, data);
+
+        let (id, _, _) = in_flight(&downloads, &hash)[0];
+        let status =
+            downloads.handle_failure(&mut swarm, peer, id, &OutboundFailure::ConnectionClosed);
+        assert!(matches!(status, DownloadStatus::Failed(_)));
+        assert!(downloads.requests.is_empty());
+    }
+
+    #[tokio::test]
+    async fn disconnected_peers_hand_their_chunks_on() {
+        let dir = TempDir::new().unwrap();
+        let mut swarm = test_swarm(&dir);
+        let mut downloads = Downloads::new();
+        let (a, b) = (PeerId::random(), PeerId::random());
+        let data = vec![1; 4 * CHUNK_SIZE];
+        let hash = add_download(&mut downloads, &data, &[a, b]);
+        fetch_tree(&mut swarm, &mut downloads, &data);
+
+        downloads.remove_peer(&mut swarm, &a);
+        let requests = in_flight(&downloads, &hash);
+        assert!(requests.iter().all(|(_, peer, _)| *peer == b));
+        assert_eq!(requests.len(), INITIAL_WINDOW);
+        assert_eq!(downloads.active[&hash].queue.len(), 4 - INITIAL_WINDOW);
+        assert_eq!(downloads.requests.len(), INITIAL_WINDOW);
+    }
+}
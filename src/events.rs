@@ -1,19 +1,33 @@
 use libp2p::multiaddr::Protocol;
-use libp2p::{request_response, Multiaddr};
 use libp2p::{
-    gossipsub,
+    allow_block_list,
+    gossipsub::{self, MessageAcceptance},
+    identify,
     identity::Keypair,
-    kad::{self, store::MemoryStore, QueryResult},
+    kad::{self, QueryResult},
     mdns, ping, rendezvous,
-    request_response::{Message, ProtocolSupport},
+    request_response::{
+        InboundFailure, Message, OutboundFailure, OutboundRequestId, ProtocolSupport,
+    },
     swarm::{NetworkBehaviour, Swarm, SwarmEvent},
     PeerId, StreamProtocol,
 };
-use std::error::Error;
+use libp2p::{kad::store::RecordStore, request_response, Multiaddr};
+use std::{error::Error, fmt, path::Path};
 use tokio::time::Duration;
 
-use crate::files::{FileMetadata, FileResponse};
-use crate::utils::ChatState;
+use crate::chat;
+use crate::downloads::DownloadStatus;
+use crate::files::{
+    compute_hash, ChunkRequest, ChunkResponse, FileAnnouncement, FileMetadata, FileResponse,
+    FreeFileRequest, GiftMessage,
+};
+use crate::groups::{GroupControl, GroupEnvelope, GroupKey};
+use crate::records::{self, PeerList};
+use crate::storage::DiskStore;
+use crate::utils::{self, ChatState};
+use crate::validation::{self, Rejection, MAX_TRANSMIT_SIZE};
+use crate::wanted::{suggest_offers, Want};
 use crate::{
     events::kad::QueryId,
     files::{save_file_to_filesystem, AcknowledgeResponse, DirectMessage, LocalFileStore},
@@ -21,6 +35,12 @@ use crate::{
     utils::{NicknameUpdate, TradeRequest},
 };
 
+/// Our own Kademlia protocol, so our DHT doesn't merge with other libp2p networks on the LAN
+pub const KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/swapbytes/kad/1.0.0");
+
+/// How many peers have to see us on the same address before we advertise it
+const OBSERVED_ADDRESS_REPORTS: usize = 2;
+
 #[derive(NetworkBehaviour)]
 pub struct ChatBehaviour {
     pub mdns: mdns::tokio::Behaviour,
@@ -36,26 +56,38 @@ pub struct RendezvousBehaviour {
 #[derive(NetworkBehaviour)]
 pub struct SwapBytesBehaviour {
     pub chat: ChatBehaviour,
-    pub kademlia: kad::Behaviour<MemoryStore>,
+    pub kademlia: kad::Behaviour<DiskStore>,
     pub file_transfer:
         request_response::cbor::Behaviour<Option<FileResponse>, Option<FileResponse>>,
     pub direct_message: request_response::cbor::Behaviour<DirectMessage, AcknowledgeResponse>,
     pub nickname_update: request_response::cbor::Behaviour<NicknameUpdate, NicknameUpdate>,
     pub trade_request: request_response::cbor::Behaviour<TradeRequest, AcknowledgeResponse>,
+    pub chunk_transfer: request_response::cbor::Behaviour<ChunkRequest, ChunkResponse>,
+    pub free_download: request_response::cbor::Behaviour<FreeFileRequest, Option<FileResponse>>,
+    pub gift: request_response::cbor::Behaviour<GiftMessage, AcknowledgeResponse>,
     pub rendezvous: RendezvousBehaviour,
+    pub identify: identify::Behaviour,
+    /// Refuses connections to and from anyone on the user's block list
+    pub blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
 }
 
 /// Setup different sets of behaviour for the app.
 /// Splitting them means its easier to filter them in the event handler
-pub fn get_swapbytes_behaviour(key: &Keypair) -> Result<SwapBytesBehaviour, Box<dyn Error>> {
+pub fn get_swapbytes_behaviour(
+    key: &Keypair,
+    data_dir: &Path,
+) -> Result<SwapBytesBehaviour, Box<dyn Error>> {
     let chat_behaviour = ChatBehaviour {
         mdns: mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id())?,
-        gossipsub: gossipsub::Behaviour::new(
-            gossipsub::MessageAuthenticity::Signed(key.clone()),
-            gossipsub::Config::default(),
-        )?,
+        gossipsub: get_gossipsub_behaviour(key)?,
     };
 
+    // Inbound records are checked in handle_event before they're stored
+    let mut kad_config = kad::Config::new(KAD_PROTOCOL);
+    kad_config.set_record_filtering(kad::StoreInserts::FilterBoth);
+    // Bootstrapping is scheduled from the main loop instead
+    kad_config.set_periodic_bootstrap_interval(None);
+
     let rendezvous_behaviour = RendezvousBehaviour {
         rendezvous: rendezvous::client::Behaviour::new(key.clone()),
         ping: ping::Behaviour::new(ping::Config::new().with_interval(Duration::from_secs(1))),
@@ -63,9 +95,10 @@ pub fn get_swapbytes_behaviour(key: &Keypair) -> Result<SwapBytesBehaviour, Box<
 
     Ok(SwapBytesBehaviour {
         chat: chat_behaviour,
-        kademlia: kad::Behaviour::new(
+        kademlia: kad::Behaviour::with_config(
             key.public().to_peer_id(),
-            MemoryStore::new(key.public().to_peer_id()),
+            DiskStore::new(key.public().to_peer_id(), &data_dir.join("dht"))?,
+            kad_config,
         ),
         file_transfer: request_response::cbor::Behaviour::new(
             [(
@@ -83,7 +116,7 @@ pub fn get_swapbytes_behaviour(key: &Keypair) -> Result<SwapBytesBehaviour, Box<
         ),
         nickname_update: request_response::cbor::Behaviour::new(
             [(
-                StreamProtocol::new("/nickname-update/1"),
+                StreamProtocol::new("/nickname-update/2"),
                 ProtocolSupport::Full,
             )],
             request_response::Config::default(),
@@ -95,10 +128,48 @@ pub fn get_swapbytes_behaviour(key: &Keypair) -> Result<SwapBytesBehaviour, Box<
             )],
             request_response::Config::default(),
         ),
+        // Slow peers time out quickly so their chunks can be handed to someone else
+        chunk_transfer: request_response::cbor::Behaviour::new(
+            [(
+                StreamProtocol::new("/chunk-transfer/1"),
+                ProtocolSupport::Full,
+            )],
+            request_response::Config::default().with_request_timeout(Duration::from_secs(10)),
+        ),
+        free_download: request_response::cbor::Behaviour::new(
+            [(
+                StreamProtocol::new("/free-download/1"),
+                ProtocolSupport::Full,
+            )],
+            request_response::Config::default(),
+        ),
+        gift: request_response::cbor::Behaviour::new(
+            [(StreamProtocol::new("/gift/1"), ProtocolSupport::Full)],
+            request_response::Config::default(),
+        ),
         rendezvous: rendezvous_behaviour,
+        identify: identify::Behaviour::new(
+            identify::Config::new("/swapbytes/1.0.0".to_string(), key.public())
+                .with_agent_version(format!("swapbytes/{}", env!("CARGO_PKG_VERSION"))),
+        ),
+        blocked: allow_block_list::Behaviour::default(),
     })
 }
 
+/// Gossipsub that holds every message back until handle_chat_event has checked it, and scores
+/// peers so those sending bad messages get pruned from the mesh and then ignored
+fn get_gossipsub_behaviour(key: &Keypair) -> Result<gossipsub::Behaviour, Box<dyn Error>> {
+    let config = gossipsub::ConfigBuilder::default()
+        .validate_messages()
+        .max_transmit_size(MAX_TRANSMIT_SIZE)
+        .build()?;
+    let mut gossipsub =
+        gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(key.clone()), config)?;
+    let (params, thresholds) = validation::peer_score_params();
+    gossipsub.with_peer_score(params, thresholds)?;
+    Ok(gossipsub)
+}
+
 /// High level event handler, filters by behaviour type and delegates to lower-level handlers
 pub async fn handle_event(
     swarm: &mut Swarm<SwapBytesBehaviour>,
@@ -106,16 +177,80 @@ pub async fn handle_event(
     chat_state: &mut ChatState,
     file_store: &mut LocalFileStore,
 ) {
+    // Failures from every request-response protocol are handled together, this says which one
+    let request_kind = match &event {
+        SwarmEvent::Behaviour(behaviour) => RequestKind::of(behaviour),
+        _ => None,
+    };
+
     match event {
         // Gossipsub and MDNS (peer discovery and chat)
         SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Chat(event)) => {
-            handle_chat_event(swarm, event, chat_state)
+            handle_chat_event(swarm, event, chat_state, file_store)
         }
 
         // Kad events (any data thats supposed to be public, file metadata at the moment)
         SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Kademlia(
             kad::Event::OutboundQueryProgressed { id, result, .. },
-        )) => handle_kad_event(id, swarm, result, chat_state),
+        )) => {
+            // Drop forged or malformed records before they get used
+            if let QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(peer_record))) = &result
+            {
+                let stored = swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .store_mut()
+                    .get(&peer_record.record.key);
+                if let Err(e) = records::validate_record(&peer_record.record, stored.as_deref()) {
+                    eprintln!("Ignoring invalid record: {e}");
+                    return;
+                }
+            }
+            handle_kad_event(id, swarm, result, chat_state, file_store)
+        }
+
+        // Someone wants us to store a record, only keep it if it's signed by the right peer
+        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Kademlia(kad::Event::InboundRequest {
+            request:
+                kad::InboundRequest::PutRecord {
+                    source,
+                    record: Some(record),
+                    ..
+                },
+        })) => {
+            let store = swarm.behaviour_mut().kademlia.store_mut();
+            let validated = records::validate_record(&record, store.get(&record.key).as_deref());
+            match validated {
+                Ok(()) => {
+                    if let Err(e) = store.put(record) {
+                        eprintln!("Failed to store record: {e}");
+                    }
+                }
+                Err(e) => eprintln!("Rejected record from {source}: {e}"),
+            }
+        }
+
+        // Provider records only say who holds a key, Kademlia has already checked the sender
+        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Kademlia(kad::Event::InboundRequest {
+            request:
+                kad::InboundRequest::AddProvider {
+                    record: Some(record),
+                },
+        })) => {
+            if let Err(e) = swarm
+                .behaviour_mut()
+                .kademlia
+                .store_mut()
+                .add_provider(record)
+            {
+                eprintln!("Failed to store provider record: {e}");
+            }
+        }
+
+        // Keep the saved address book in line with the routing table, it's written on the next save
+        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Kademlia(kad::Event::RoutingUpdated {
+            ..
+        })) => chat_state.routing_changed = true,
 
         // File sharing with request/response pattern
         SwarmEvent::Behaviour(SwapBytesBehaviourEvent::FileTransfer(
@@ -124,8 +259,8 @@ pub async fn handle_event(
 
         // Direct messages with request/response pattern
         SwarmEvent::Behaviour(SwapBytesBehaviourEvent::DirectMessage(
-            request_response::Event::Message { message, .. },
-        )) => handle_direct_message_event(message, swarm).await,
+            request_response::Event::Message { peer, message, .. },
+        )) => handle_direct_message_event(peer, message, swarm, chat_state).await,
 
         // Nickname updates with request/response pattern
         SwarmEvent::Behaviour(SwapBytesBehaviourEvent::NicknameUpdate(
@@ -137,6 +272,177 @@ pub async fn handle_event(
             request_response::Event::Message { peer, message, .. },
         )) => handle_trade_request_event(peer, message, swarm, chat_state, file_store).await,
 
+        // Multi-source downloads, pieces of a file from everyone who holds it
+        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::ChunkTransfer(
+            request_response::Event::Message { peer, message, .. },
+        )) => handle_chunk_transfer_event(peer, message, swarm, chat_state, file_store).await,
+
+        // Someone fetching a free file from us, or the file we asked for
+        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::FreeDownload(
+            request_response::Event::Message { peer, message, .. },
+        )) => handle_free_download_event(peer, message, swarm, chat_state, file_store).await,
+
+        // Gift offers and refusals
+        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Gift(
+            request_response::Event::Message { peer, message, .. },
+        )) => handle_gift_event(peer, message, swarm, chat_state).await,
+
+        // A chunk request failed or timed out, give the chunk to another holder
+        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::ChunkTransfer(
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            },
+        )) => {
+            let status = chat_state
+                .downloads
+                .handle_failure(swarm, peer, request_id, &error);
+            handle_download_status(swarm, status, chat_state, file_store).await
+        }
+
+        // Requests we sent that never got an answer, undo whatever was waiting on them
+        SwarmEvent::Behaviour(
+            SwapBytesBehaviourEvent::FileTransfer(request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            })
+            | SwapBytesBehaviourEvent::DirectMessage(request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            })
+            | SwapBytesBehaviourEvent::NicknameUpdate(request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            })
+            | SwapBytesBehaviourEvent::TradeRequest(request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            })
+            | SwapBytesBehaviourEvent::FreeDownload(request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            })
+            | SwapBytesBehaviourEvent::Gift(request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            }),
+        ) => {
+            if let Some(kind) = request_kind {
+                handle_outbound_failure(swarm, chat_state, kind, peer, request_id, error);
+            }
+        }
+
+        // Requests sent to us that we couldn't answer
+        SwarmEvent::Behaviour(
+            SwapBytesBehaviourEvent::FileTransfer(request_response::Event::InboundFailure {
+                peer,
+                error,
+                ..
+            })
+            | SwapBytesBehaviourEvent::DirectMessage(request_response::Event::InboundFailure {
+                peer,
+                error,
+                ..
+            })
+            | SwapBytesBehaviourEvent::NicknameUpdate(request_response::Event::InboundFailure {
+                peer,
+                error,
+                ..
+            })
+            | SwapBytesBehaviourEvent::TradeRequest(request_response::Event::InboundFailure {
+                peer,
+                error,
+                ..
+            })
+            | SwapBytesBehaviourEvent::FreeDownload(request_response::Event::InboundFailure {
+                peer,
+                error,
+                ..
+            })
+            | SwapBytesBehaviourEvent::Gift(request_response::Event::InboundFailure {
+                peer,
+                error,
+                ..
+            })
+            | SwapBytesBehaviourEvent::ChunkTransfer(request_response::Event::InboundFailure {
+                peer,
+                error,
+                ..
+            }),
+        ) => {
+            if let Some(kind) = request_kind {
+                report_inbound_failure(chat_state, kind, peer, error);
+            }
+        }
+
+        // A peer told us about itself, learn where it listens so Kademlia can reach it
+        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Identify(identify::Event::Received {
+            peer_id,
+            info,
+            ..
+        })) => {
+            if info.protocols.contains(&KAD_PROTOCOL) {
+                for address in &info.listen_addrs {
+                    swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer_id, address.clone());
+                }
+            }
+            // How the peer sees us, so the addresses we give out in records are ones that work.
+            // Any peer can claim anything here, so an address is only used once enough different
+            // peers have seen us on it.
+            let observed = info.observed_addr.clone();
+            chat_state.peer_details.entry(peer_id).or_default().info = Some(info);
+            let reports = chat_state
+                .peer_details
+                .values()
+                .filter(|details| {
+                    details
+                        .info
+                        .as_ref()
+                        .is_some_and(|info| info.observed_addr == observed)
+                })
+                .count();
+            if reports >= OBSERVED_ADDRESS_REPORTS {
+                swarm.add_external_address(observed);
+            }
+        }
+
+        // Keep the latest round trip time to each peer for /whois
+        SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(
+            RendezvousBehaviourEvent::Ping(ping::Event {
+                peer,
+                result: Ok(rtt),
+                ..
+            }),
+        )) => chat_state.peer_details.entry(peer).or_default().rtt = Some(rtt),
+
+        // Stop asking disconnected peers for chunks, and cancel anything open with them
+        SwarmEvent::ConnectionClosed {
+            peer_id,
+            num_established: 0,
+            ..
+        } => {
+            chat_state.downloads.remove_peer(swarm, &peer_id);
+            chat_state.peer_details.remove(&peer_id);
+            handle_disconnect(peer_id, chat_state);
+        }
+
         SwarmEvent::ConnectionEstablished { peer_id, .. } if peer_id == chat_state.rendezvous => {
             if let Err(error) = swarm.behaviour_mut().rendezvous.rendezvous.register(
                 rendezvous::Namespace::from_static("rendezvous"),
@@ -156,15 +462,40 @@ pub async fn handle_event(
             )
         }
 
+        // Someone's back, send them anything that failed to reach them last time
+        SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+            if let Some(messages) = chat_state.take_undelivered_dms(&peer_id) {
+                println!(
+                    "{} is back, sending {} undelivered DM{}",
+                    chat_state.nicknames.get(&peer_id.to_string()),
+                    messages.len(),
+                    if messages.len() == 1 { "" } else { "s" }
+                );
+                for message in messages {
+                    utils::send_dm(swarm, chat_state, peer_id, message);
+                }
+            }
+            if chat_state.nickname_retries.remove(&peer_id) {
+                if let Some(update) = chat_state.nickname_update() {
+                    swarm
+                        .behaviour_mut()
+                        .nickname_update
+                        .send_request(&peer_id, update);
+                }
+            }
+        }
+
         SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Rendezvous(
             RendezvousBehaviourEvent::Rendezvous(rendezvous::client::Event::Discovered {
                 registrations,
                 ..
-        }))) => {
+            }),
+        )) => {
             for registration in registrations {
                 for address in registration.record.addresses() {
                     let peer = registration.record.peer_id();
                     println!("Discovered peer: {:?}", peer);
+                    chat_state.catalog.add_known_peer(peer);
 
                     let p2p_suffix = Protocol::P2p(peer);
                     let address_with_p2p =
@@ -185,11 +516,156 @@ pub async fn handle_event(
     }
 }
 
+/// Which request-response protocol a failure came from
+#[derive(Debug, Clone, Copy)]
+enum RequestKind {
+    FileTransfer,
+    DirectMessage,
+    NicknameUpdate,
+    TradeRequest,
+    ChunkTransfer,
+    FreeDownload,
+    Gift,
+}
+
+impl RequestKind {
+    /// The protocol a behaviour event came from, None if it isn't a request-response one
+    fn of(event: &SwapBytesBehaviourEvent) -> Option<Self> {
+        match event {
+            SwapBytesBehaviourEvent::FileTransfer(_) => Some(RequestKind::FileTransfer),
+            SwapBytesBehaviourEvent::DirectMessage(_) => Some(RequestKind::DirectMessage),
+            SwapBytesBehaviourEvent::NicknameUpdate(_) => Some(RequestKind::NicknameUpdate),
+            SwapBytesBehaviourEvent::TradeRequest(_) => Some(RequestKind::TradeRequest),
+            SwapBytesBehaviourEvent::ChunkTransfer(_) => Some(RequestKind::ChunkTransfer),
+            SwapBytesBehaviourEvent::FreeDownload(_) => Some(RequestKind::FreeDownload),
+            SwapBytesBehaviourEvent::Gift(_) => Some(RequestKind::Gift),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for RequestKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            RequestKind::FileTransfer => "file transfer",
+            RequestKind::DirectMessage => "DM",
+            RequestKind::NicknameUpdate => "nickname",
+            RequestKind::TradeRequest => "trade",
+            RequestKind::ChunkTransfer => "chunk",
+            RequestKind::FreeDownload => "download",
+            RequestKind::Gift => "gift",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// A request we sent failed, tell the user and roll back whatever was waiting on it
+fn handle_outbound_failure(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &mut ChatState,
+    kind: RequestKind,
+    peer: PeerId,
+    request_id: OutboundRequestId,
+    error: OutboundFailure,
+) {
+    let peer_id_str = peer.to_string();
+    let nickname = chat_state.nicknames.get(&peer_id_str).to_string();
+    match kind {
+        RequestKind::TradeRequest => {
+            if let Some(trade) = chat_state.outgoing_trades.remove(&peer_id_str) {
+                eprintln!(
+                    "Your trade request to {nickname} for {} didn't go through ({error}), it's been cancelled",
+                    trade.requested_file
+                );
+            }
+        }
+
+        RequestKind::FileTransfer => {
+            eprintln!("Couldn't send your file to {nickname} ({error})");
+            if chat_state.incoming_trades.contains_key(&peer_id_str) {
+                eprintln!("The trade is still open, try /trade_accept {nickname} again");
+            }
+        }
+
+        RequestKind::DirectMessage => {
+            if let Some((peer, message)) = chat_state.pending_dms.remove(&request_id) {
+                eprintln!(
+                    "Your DM to {nickname} couldn't be delivered ({error}), it'll be sent again when they reconnect"
+                );
+                chat_state.queue_dm(peer, message);
+            }
+        }
+
+        // Nicknames are swapped in the background, so just try again later
+        RequestKind::NicknameUpdate => {
+            chat_state.nickname_retries.insert(peer);
+        }
+
+        RequestKind::Gift => {
+            if let Some(hash) = chat_state.outgoing_gifts.remove(&peer_id_str) {
+                eprintln!("Your gift of {hash} to {nickname} couldn't be delivered ({error})");
+            } else {
+                eprintln!("Couldn't reach {nickname} about a gift ({error})");
+            }
+        }
+
+        RequestKind::FreeDownload => {
+            chat_state.free_requests.remove(&request_id);
+            eprintln!("Couldn't fetch the file from {nickname} ({error})");
+        }
+
+        // Chunk failures are handled by the download itself
+        RequestKind::ChunkTransfer => {}
+    }
+
+    // The peer is gone, anything open with them won't complete
+    if matches!(error, OutboundFailure::DialFailure) && !swarm.is_connected(&peer) {
+        handle_disconnect(peer, chat_state);
+    }
+}
+
+/// We couldn't answer a request sent to us
+fn report_inbound_failure(
+    chat_state: &ChatState,
+    kind: RequestKind,
+    peer: PeerId,
+    error: InboundFailure,
+) {
+    eprintln!(
+        "Couldn't answer {}'s {kind} request ({error})",
+        chat_state.nicknames.get(&peer.to_string())
+    );
+}
+
+/// A peer has gone, cancel the trades and gifts open with them
+fn handle_disconnect(peer: PeerId, chat_state: &mut ChatState) {
+    let peer_id_str = peer.to_string();
+    let nickname = chat_state.nicknames.get(&peer_id_str).to_string();
+
+    if let Some(trade) = chat_state.outgoing_trades.remove(&peer_id_str) {
+        println!(
+            "{nickname} disconnected, your offer of '{}' has been cancelled",
+            trade.offered_file.filename
+        );
+    }
+    if chat_state.incoming_trades.remove(&peer_id_str).is_some() {
+        println!("{nickname} disconnected, their trade request has been cancelled");
+    }
+    if let Some(gift) = chat_state.incoming_gifts.remove(&peer_id_str) {
+        println!(
+            "{nickname} disconnected, their gift of '{}' has been withdrawn",
+            gift.filename
+        );
+    }
+    chat_state.outgoing_gifts.remove(&peer_id_str);
+}
+
 /// Low-level chat handler. Sorts MDNS events (mostly connection and peers), and catching broadcasts
 fn handle_chat_event(
     swarm: &mut Swarm<SwapBytesBehaviour>,
     event: ChatBehaviourEvent,
     chat_state: &mut ChatState,
+    file_store: &LocalFileStore,
 ) {
     match event {
         // When a new peer is discovered
@@ -208,10 +684,7 @@ fn handle_chat_event(
                     .kademlia
                     .add_address(&peer_id, multiaddr);
 
-                swarm
-                    .behaviour_mut()
-                    .kademlia
-                    .get_closest_peers(peer_id.clone());
+                swarm.behaviour_mut().kademlia.get_closest_peers(peer_id);
             }
         }
 
@@ -232,16 +705,76 @@ fn handle_chat_event(
 
         // Message received
         ChatBehaviourEvent::Gossipsub(gossipsub::Event::Message {
-            propagation_source: peer_id,
-            message_id: _id,
+            propagation_source,
+            message_id,
             message,
         }) => {
+            // Nothing is forwarded until it's been checked. Blocked peers can still reach us
+            // through others, so their messages are dropped here too.
+            let acceptance = match chat_state
+                .gossip_validator
+                .check(&message, &chat_state.blocked)
+            {
+                Ok(()) => MessageAcceptance::Accept,
+                Err(rejection) => {
+                    let author = message.source.unwrap_or(propagation_source);
+                    match &rejection {
+                        Rejection::Invalid(reason) => eprintln!(
+                            "Dropped a bad message from {}: {reason}",
+                            chat_state.nicknames.get(&author.to_string())
+                        ),
+                        Rejection::RateLimited { first: true } => eprintln!(
+                            "{} is sending messages too quickly, ignoring some of them",
+                            chat_state.nicknames.get(&author.to_string())
+                        ),
+                        Rejection::RateLimited { first: false }
+                        | Rejection::Blocked
+                        | Rejection::UnknownTopic => {}
+                    }
+                    rejection.acceptance()
+                }
+            };
+            let accepted = matches!(acceptance, MessageAcceptance::Accept);
+            swarm
+                .behaviour_mut()
+                .chat
+                .gossipsub
+                .report_message_validation_result(&message_id, &propagation_source, acceptance);
+            if !accepted {
+                return;
+            }
+
+            // Messages are signed by their author, which is who the nickname belongs to.
+            // Whoever forwarded the message to us doesn't matter.
+            let Some(source) = message.source else {
+                return;
+            };
+
+            if message.topic == chat_state.announcement_topic.hash() {
+                handle_announcement(swarm, &message.data, chat_state, file_store);
+                return;
+            }
+
+            // Someone's renamed themselves
+            if message.topic == chat_state.nickname_topic.hash() {
+                if let Ok(claim) = serde_cbor::from_slice::<NicknameUpdate>(&message.data) {
+                    record_nickname_claim(source, claim, chat_state);
+                }
+                return;
+            }
+
+            // A private group we're in
+            if let Some(group) = chat_state.groups.by_topic(&message.topic).cloned() {
+                handle_group_message(swarm, source, &group, &message.data, chat_state);
+                return;
+            }
+
             // Try to interpret the message as a ChatMessage
             if let Ok(chat) = serde_cbor::from_slice::<ChatMessage>(&message.data) {
-                chat_state
-                    .nicknames
-                    .insert(peer_id.to_string(), chat.nickname.clone());
-                println!("{}: {}", chat.nickname, chat.message);
+                request_nickname_claim(swarm, source, chat_state);
+                chat_state.catalog.add_known_peer(source);
+                chat::print_message(chat_state, &source, &chat, None);
+                chat_state.chat_log.push(source, chat, None);
             }
         }
 
@@ -250,92 +783,227 @@ fn handle_chat_event(
     }
 }
 
+/// Decrypt and show a message from a private group
+fn handle_group_message(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    source: PeerId,
+    group: &GroupKey,
+    data: &[u8],
+    chat_state: &mut ChatState,
+) {
+    let chat = serde_cbor::from_slice::<GroupEnvelope>(data)
+        .map_err(|e| e.to_string())
+        .and_then(|envelope| group.decrypt(&envelope))
+        .and_then(|plaintext| validation::check_chat(&plaintext));
+    let chat = match chat {
+        Ok(chat) => chat,
+        Err(e) => {
+            eprintln!(
+                "Couldn't read a message in '{}' from {}: {e}",
+                group.name,
+                chat_state.nicknames.get(&source.to_string())
+            );
+            return;
+        }
+    };
+
+    request_nickname_claim(swarm, source, chat_state);
+    chat::print_message(chat_state, &source, &chat, Some(&group.name));
+    chat_state
+        .chat_log
+        .push(source, chat, Some(group.name.clone()));
+}
+
+/// Invites, new keys and removals for private groups. Only a group's creator can manage it.
+fn handle_group_control(
+    peer_id: PeerId,
+    control: GroupControl,
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &mut ChatState,
+) {
+    let nickname = chat_state.nicknames.get(&peer_id.to_string()).to_string();
+    match control {
+        GroupControl::Invite(group) => {
+            if !group.is_creator(&peer_id) {
+                eprintln!("Ignoring an invite from {nickname} to a group they didn't create");
+                return;
+            }
+            if chat_state.groups.by_id(&group.id).is_some() {
+                return;
+            }
+            println!(
+                "{nickname} invited you to the private group '{}' ({} member{}). Type '/group_accept {}' to join or '/group_decline {}' to refuse.",
+                group.name,
+                group.members.len(),
+                if group.members.len() == 1 { "" } else { "s" },
+                group.name,
+                group.name
+            );
+            chat_state.groups.add_invite(group);
+        }
+
+        GroupControl::Rekey(group) => {
+            // The key can change before we've answered an invite, which would leave us joining
+            // with a key nobody uses any more
+            if let Some(invite) = chat_state.groups.invite_by_id(&group.id) {
+                if invite.is_creator(&peer_id) && group.epoch > invite.epoch {
+                    let group = GroupKey {
+                        name: invite.name.clone(),
+                        ..group
+                    };
+                    chat_state.groups.add_invite(group);
+                }
+                return;
+            }
+
+            let Some(current) = chat_state.groups.by_id(&group.id) else {
+                return;
+            };
+            if !current.is_creator(&peer_id) || group.epoch <= current.epoch {
+                return;
+            }
+            // Keep our own name for the group, in case it clashed with another when we joined
+            let group = GroupKey {
+                name: current.name.clone(),
+                ..group
+            };
+            let name = group.name.clone();
+            let joined: Vec<String> = group
+                .members
+                .iter()
+                .filter(|member| !current.members.contains(member))
+                .map(|member| chat_state.nicknames.get(member).to_string())
+                .collect();
+            let removed: Vec<String> = current
+                .members
+                .iter()
+                .filter(|member| !group.members.contains(member))
+                .map(|member| chat_state.nicknames.get(member).to_string())
+                .collect();
+            if let Err(e) = utils::join_group(swarm, chat_state, group) {
+                eprintln!("{e}");
+                return;
+            }
+            for member in joined {
+                println!("{member} joined '{name}'");
+            }
+            for member in removed {
+                println!("{member} was removed from '{name}'");
+            }
+        }
+
+        GroupControl::Removed { id } => {
+            let Some(group) = chat_state.groups.by_id(&id) else {
+                return;
+            };
+            if !group.is_creator(&peer_id) {
+                return;
+            }
+            let name = group.name.clone();
+            utils::leave_group(swarm, chat_state, &name);
+            println!("{nickname} removed you from '{name}'");
+        }
+    }
+}
+
 /// Kademlia handler, handles responses for DHT queries requested elsewhere.
 fn handle_kad_event(
     id: QueryId,
     swarm: &mut Swarm<SwapBytesBehaviour>,
     result: QueryResult,
     chat_state: &mut ChatState,
+    file_store: &LocalFileStore,
 ) {
+    // Someone's wishlist, used to suggest what to offer them
+    if chat_state.want_queries.contains_key(&id) {
+        handle_wishlist_lookup(id, swarm, result, chat_state, file_store);
+        return;
+    }
+
+    // Metadata for a file offered in a trade, for the trade rules
+    if chat_state.rule_queries.contains_key(&id) {
+        handle_rule_lookup(id, swarm, result, chat_state, file_store);
+        return;
+    }
+
+    // Metadata lookups for /get, the owner is asked for the file once we know who they are
+    if chat_state.get_queries.contains_key(&id) {
+        handle_get_lookup(id, swarm, result, chat_state);
+        return;
+    }
+
+    // File lists and metadata for /list_files go into the catalog
+    if chat_state.catalog.owns_query(&id) {
+        let found = chat_state.catalog.handle_query_result(swarm, id, result);
+        for metadata in found {
+            notify_if_wanted(swarm, &metadata, chat_state, file_store);
+        }
+        chat_state
+            .catalog
+            .poll_listing(swarm, &chat_state.nicknames);
+        return;
+    }
+
+    // Lookups for multi-source downloads are handled separately
+    if chat_state.downloads.owns_query(&id) {
+        let status = chat_state.downloads.handle_query_result(swarm, id, result);
+        if let DownloadStatus::Failed(reason) = status {
+            eprintln!("Download failed: {reason}");
+        }
+        return;
+    }
+
     match result {
-        // Response from DHT request
-        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(peer_record))) => {
-            // Match on the custom response type (file, file_index, etc)
-            let record_key = String::from_utf8_lossy(peer_record.record.key.as_ref());
-            match record_key.as_ref() {
-                // File metadata responses
-                key if key.starts_with("file::") => {
-                    // Deduplicate
-                    if !chat_state.pending_keys.remove(&id) {
-                        return;
-                    }
-                    match serde_cbor::from_slice::<FileMetadata>(&peer_record.record.value) {
-                        Ok(metadata) => {
-                            println!(
-                                "\t{} - {} ({} bytes) - {}",
-                                metadata.hash,
-                                metadata.filename,
-                                metadata.size,
-                                metadata
-                                    .description
-                                    .unwrap_or_else(|| "No description".to_string())
-                            )
-                        }
-                        Err(e) => {
-                            println!("Error deserializing file metadata: {e}");
-                        }
-                    }
-                }
+        // More copies of a record we've already had an answer for, or one nobody asked for
+        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(_))) => {}
 
-                // Response from a peer saying what files they have.
-                key if key.starts_with("file_index::") => {
-                    // Deduplicate
-                    if peer_record.peer.is_none() || !chat_state.pending_keys.remove(&id) {
-                        return;
-                    }
+        // Some holders of a file found, collect them until the query is done
+        kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders {
+            providers,
+            ..
+        })) => {
+            if let Some((_, holders)) = chat_state.provider_queries.get_mut(&id) {
+                holders.extend(providers);
+            }
+        }
 
-                    match serde_cbor::from_slice::<Vec<String>>(&peer_record.record.value) {
-                        Ok(hashes) => {
-                            let file_count = hashes.len();
-                            let peerid_str = peer_record
-                                .peer
-                                .map_or("Someone".to_string(), |peer_id| peer_id.to_string());
-                            println!(
-                                "{} has uploaded {} file{}:",
-                                chat_state.nicknames.get(&peerid_str),
-                                file_count,
-                                if file_count == 1 { "" } else { "s" }
-                            );
-
-                            // For each file listed, request the metadata of it
-                            hashes.iter().for_each(|hash| {
-                                let key = kad::RecordKey::new(&format!("file::{}", hash));
-                                let queryid = swarm.behaviour_mut().kademlia.get_record(key);
-                                chat_state.pending_keys.insert(queryid);
-                            });
-                        }
-                        Err(e) => {
-                            println!("Failed to parse file index for {key}: {e}");
-                        }
-                    }
-                }
+        // Provider query finished, list everyone who holds the file
+        kad::QueryResult::GetProviders(Ok(
+            kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. },
+        ))
+        | kad::QueryResult::GetProviders(Err(_)) => {
+            let Some((hash, holders)) = chat_state.provider_queries.remove(&id) else {
+                return;
+            };
+            if holders.is_empty() {
+                println!("Nobody is sharing {hash}");
+                return;
+            }
 
-                // If the record type isn't defined
-                _ => println!("Unexpected record type: {}", record_key),
+            println!(
+                "{hash} is held by {} peer{}:",
+                holders.len(),
+                if holders.len() == 1 { "" } else { "s" }
+            );
+            for holder in holders {
+                println!(" - {}", chat_state.nicknames.get(&holder.to_string()));
             }
         }
 
         // Once bootstrapping is complete, fetch nicknames from peers
-        kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk { num_remaining, .. })) => {
-            if num_remaining == 0 {
-                let peers: Vec<PeerId> = swarm.connected_peers().cloned().collect();
-                // For each peer, request their nickname
-                for peer in peers {
-                    swarm
-                        .behaviour_mut()
-                        .nickname_update
-                        .send_request(&peer, NicknameUpdate(chat_state.nickname.clone()));
-                }
+        kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk {
+            num_remaining: 0, ..
+        })) => {
+            let peers: Vec<PeerId> = swarm.connected_peers().cloned().collect();
+            let Some(update) = chat_state.nickname_update() else {
+                return;
+            };
+            // For each peer, request their nickname
+            for peer in peers {
+                swarm
+                    .behaviour_mut()
+                    .nickname_update
+                    .send_request(&peer, update.clone());
             }
         }
 
@@ -345,28 +1013,112 @@ fn handle_kad_event(
 }
 
 async fn handle_direct_message_event(
+    peer_id: PeerId,
     message: Message<DirectMessage, AcknowledgeResponse>,
     swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &mut ChatState,
 ) {
     match message {
         Message::Request {
             request, channel, ..
         } => {
-            // Output DM to user
-            println!("*DM* {}: {}", request.sender_nickname, request.message);
+            // Group invites and keys can come from anyone who isn't blocked, the user decides
+            // whether to join
+            if let Some(control) = request.group {
+                let blocked = chat_state.blocked.contains(&peer_id);
+                if !blocked {
+                    handle_group_control(peer_id, control, swarm, chat_state);
+                }
+                if swarm
+                    .behaviour_mut()
+                    .direct_message
+                    .send_response(channel, AcknowledgeResponse(!blocked))
+                    .is_err()
+                {
+                    eprintln!("Failed to send response.")
+                };
+                return;
+            }
+
+            // Output DM to user, unless they've been blocked or aren't allowed to DM us
+            let accepted =
+                !chat_state.blocked.contains(&peer_id) && chat_state.accepts_dm_from(&peer_id);
+            if accepted {
+                println!("*DM* {}: {}", request.sender_nickname, request.message);
+            } else if !chat_state.blocked.contains(&peer_id) {
+                println!(
+                    "Turned away a DM from {}, you aren't trading with them{}",
+                    chat_state.nicknames.get(&peer_id.to_string()),
+                    if chat_state.nicknames.contacts().contains(&peer_id) {
+                        " (use /dm_contacts on to hear from contacts any time)"
+                    } else {
+                        ""
+                    }
+                );
+            }
 
             // Send response so request is fulfilled
-            if let Err(_) = swarm
+            if swarm
                 .behaviour_mut()
                 .direct_message
-                .send_response(channel, AcknowledgeResponse(true))
+                .send_response(channel, AcknowledgeResponse(accepted))
+                .is_err()
             {
                 eprintln!("Failed to send response.")
             };
         }
 
-        // Ignore response messages
-        Message::Response { .. } => {}
+        // Delivered, no need to keep it for a retry
+        Message::Response {
+            request_id,
+            response,
+        } => {
+            chat_state.pending_dms.remove(&request_id);
+            if response == AcknowledgeResponse(false) {
+                eprintln!(
+                    "{} isn't accepting DMs from you outside of a trade",
+                    chat_state.nicknames.get(&peer_id.to_string())
+                );
+            }
+        }
+    }
+}
+
+/// Check and save a peer's signed nickname
+fn record_nickname_claim(peer_id: PeerId, claim: NicknameUpdate, chat_state: &mut ChatState) {
+    match chat_state.nicknames.verify_claim(peer_id, claim) {
+        Ok(nickname) => update_nickname(peer_id, nickname, chat_state),
+        Err(e) => eprintln!("Ignoring nickname from {peer_id}: {e}"),
+    }
+}
+
+/// Ask someone we've seen in chat for their signed nickname if we don't have it yet. The name in
+/// a chat message isn't used, an old message could carry a name they've since given up.
+fn request_nickname_claim(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    peer_id: PeerId,
+    chat_state: &mut ChatState,
+) {
+    if chat_state.nicknames.is_signed(&peer_id) || !chat_state.claim_requests.insert(peer_id) {
+        return;
+    }
+    if let Some(update) = chat_state.nickname_update() {
+        swarm
+            .behaviour_mut()
+            .nickname_update
+            .send_request(&peer_id, update);
+    }
+}
+
+/// Save the nickname a peer is using, letting the user know if they've renamed themselves or if
+/// anything looks suspicious
+fn update_nickname(peer_id: PeerId, nickname: String, chat_state: &mut ChatState) {
+    let previous = chat_state.nicknames.current(&peer_id).map(str::to_string);
+    if let Some(warning) = chat_state.nicknames.insert(peer_id, nickname.clone()) {
+        eprintln!("Warning: {warning}");
+    }
+    if let Some(previous) = previous.filter(|previous| *previous != nickname) {
+        println!("{previous} is now known as {nickname}");
     }
 }
 
@@ -382,21 +1134,59 @@ async fn handle_nickname_event(
         Message::Request {
             request, channel, ..
         } => {
-            chat_state.nicknames.insert(peer_id.to_string(), request.0);
-            if let Err(_) = swarm
+            record_nickname_claim(peer_id, request, chat_state);
+            let Some(update) = chat_state.nickname_update() else {
+                return;
+            };
+            if swarm
                 .behaviour_mut()
                 .nickname_update
-                .send_response(channel, NicknameUpdate(chat_state.nickname.clone()))
+                .send_response(channel, update)
+                .is_err()
             {
                 eprintln!("Failed to send nickname acknowledgement")
             }
         }
 
         // A response to our nickname request, save it in the app state
-        Message::Response { response, .. } => {
-            chat_state.nicknames.insert(peer_id.to_string(), response.0);
+        Message::Response { response, .. } => record_nickname_claim(peer_id, response, chat_state),
+    }
+}
+
+/// Someone uploaded a file, add it to the catalog and show it in the feed
+fn handle_announcement(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    data: &[u8],
+    chat_state: &mut ChatState,
+    file_store: &LocalFileStore,
+) {
+    let Ok(announcement) = serde_cbor::from_slice::<FileAnnouncement>(data) else {
+        return;
+    };
+    let key = format!("file::{}", announcement.hash);
+    let metadata = match records::open_file_metadata(&key, &announcement.record) {
+        Ok((_, metadata)) => metadata,
+        Err(e) => {
+            eprintln!("Ignoring bad announcement for {}: {e}", announcement.hash);
+            return;
         }
+    };
+
+    if !chat_state.catalog.insert(metadata.clone()) {
+        return;
     }
+    println!(
+        "*NEW FILE* {} uploaded {} ({} bytes) - {} [hash {}]",
+        chat_state.nicknames.get(&metadata.owner),
+        metadata.filename,
+        metadata.size,
+        metadata
+            .description
+            .clone()
+            .unwrap_or_else(|| "No description".to_string()),
+        metadata.hash
+    );
+    notify_if_wanted(swarm, &metadata, chat_state, file_store);
 }
 
 /// Handles trade requests/responses. Handling is async so users aren't blocked during a request
@@ -412,6 +1202,19 @@ async fn handle_trade_request_event(
         Message::Request {
             request, channel, ..
         } => {
+            // Turn blocked peers away without bothering the user
+            if chat_state.blocked.contains(&peer_id) {
+                if swarm
+                    .behaviour_mut()
+                    .trade_request
+                    .send_response(channel, AcknowledgeResponse(false))
+                    .is_err()
+                {
+                    eprintln!("Failed to send trade acknowledgement")
+                }
+                return;
+            }
+
             let requested_file_exists = file_store.contains_file(&request.requested_file);
             if requested_file_exists {
                 chat_state
@@ -431,13 +1234,29 @@ async fn handle_trade_request_event(
                     request.nickname
                 );
             }
-            if let Err(_) = swarm
+            if swarm
                 .behaviour_mut()
                 .trade_request
                 .send_response(channel, AcknowledgeResponse(requested_file_exists))
+                .is_err()
             {
                 eprintln!("Failed to send nickname acknowledgement")
             }
+
+            // See if one of the user's rules takes the decision out of their hands. The rules are
+            // checked against the offered file's signed metadata, which is looked up if we
+            // haven't seen it yet.
+            if requested_file_exists {
+                let offered = verified_metadata(swarm, chat_state, &request.offered_file.hash);
+                if !apply_trade_rules(swarm, chat_state, file_store, peer_id, offered.as_ref())
+                    && offered.is_none()
+                    && !chat_state.trade_rules.rules().is_empty()
+                {
+                    let key = kad::RecordKey::new(&format!("file::{}", request.offered_file.hash));
+                    let query_id = swarm.behaviour_mut().kademlia.get_record(key);
+                    chat_state.rule_queries.insert(query_id, peer_id);
+                }
+            }
         }
 
         // A acknowledgement response to our trade request, represents whether the requested file exists
@@ -458,6 +1277,94 @@ async fn handle_trade_request_event(
     }
 }
 
+/// Signed metadata for a file, from our copy of its DHT record or the catalog
+fn verified_metadata(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &ChatState,
+    hash: &str,
+) -> Option<FileMetadata> {
+    let key = format!("file::{hash}");
+    let stored = swarm
+        .behaviour_mut()
+        .kademlia
+        .store_mut()
+        .get(&kad::RecordKey::new(&key))
+        .and_then(|record| records::open_file_metadata(&key, &record.value).ok());
+    stored
+        .map(|(_, metadata)| metadata)
+        .or_else(|| chat_state.catalog.get(hash).cloned())
+}
+
+/// Accept an incoming trade if one of the user's rules allows it, true if it was accepted.
+/// `offered` is the offered file's signed metadata, if we have it.
+fn apply_trade_rules(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &ChatState,
+    file_store: &LocalFileStore,
+    peer_id: PeerId,
+    offered: Option<&FileMetadata>,
+) -> bool {
+    let Some(request) = chat_state.incoming_trades.get(&peer_id.to_string()) else {
+        return false;
+    };
+    let Some(requested_file) = file_store.get_metadata(&request.requested_file) else {
+        return false;
+    };
+    let Some(rule) =
+        chat_state
+            .trade_rules
+            .evaluate(&peer_id, offered, requested_file, &chat_state.wanted)
+    else {
+        return false;
+    };
+
+    chat_state
+        .trade_rules
+        .log_decision(&peer_id, request, requested_file, rule);
+    println!("Automatically accepting the trade ({rule})");
+    if let Err(e) = utils::accept_trade(swarm, &peer_id, request, file_store) {
+        eprintln!("{e}");
+    }
+    true
+}
+
+/// The signed metadata of a file offered in a trade has arrived, check the trade rules again now
+/// it can be trusted. If it isn't found the trade is left for the user to decide.
+fn handle_rule_lookup(
+    id: QueryId,
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    result: QueryResult,
+    chat_state: &mut ChatState,
+    file_store: &LocalFileStore,
+) {
+    let kad::QueryResult::GetRecord(result) = result else {
+        return;
+    };
+    let Some(peer_id) = chat_state.rule_queries.remove(&id) else {
+        return;
+    };
+    let Ok(kad::GetRecordOk::FoundRecord(peer_record)) = result else {
+        return;
+    };
+    if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&id) {
+        query.finish();
+    }
+    let Some(request) = chat_state.incoming_trades.get(&peer_id.to_string()) else {
+        return;
+    };
+
+    let key = format!("file::{}", request.offered_file.hash);
+    match records::open_file_metadata(&key, &peer_record.record.value) {
+        Ok((_, metadata)) => {
+            apply_trade_rules(swarm, chat_state, file_store, peer_id, Some(&metadata));
+        }
+        Err(e) => eprintln!(
+            "Couldn't verify the file offered by {}: {e}",
+            request.nickname
+        ),
+    }
+}
+
 async fn handle_file_transfer_event(
     peer_id: PeerId,
     message: Message<Option<FileResponse>, Option<FileResponse>>,
@@ -476,10 +1383,11 @@ async fn handle_file_transfer_event(
                     let Some(trade_details) =
                         chat_state.outgoing_trades.remove(&peer_id.to_string())
                     else {
-                        if let Err(_) = swarm
+                        if swarm
                             .behaviour_mut()
                             .file_transfer
                             .send_response(channel, None)
+                            .is_err()
                         {
                             eprintln!("Failed to send file response");
                         }
@@ -488,13 +1396,14 @@ async fn handle_file_transfer_event(
 
                     // Save the file sent by the other party
                     if let Err(e) = save_file_to_filesystem(
-                        file_response.file,
+                        &file_response.file,
                         &file_response.metadata.filename,
                     )
                     .await
                     {
                         eprintln!("Failed to save file: {}", e);
                     }
+                    reshare_file(swarm, chat_state, file_store, file_response);
 
                     // Construct response and send it
                     let file_bytes = file_store
@@ -504,10 +1413,11 @@ async fn handle_file_transfer_event(
                         file: file_bytes,
                         metadata: trade_details.offered_file,
                     };
-                    if let Err(_) = swarm
+                    if swarm
                         .behaviour_mut()
                         .file_transfer
                         .send_response(channel, Some(response))
+                        .is_err()
                     {
                         eprintln!("Failed to send file response");
                     }
@@ -517,6 +1427,16 @@ async fn handle_file_transfer_event(
 
                 // Trade request declined, remove trade request from state
                 None => {
+                    // Answer so the decline doesn't show up as a failed request on either side
+                    if swarm
+                        .behaviour_mut()
+                        .file_transfer
+                        .send_response(channel, None)
+                        .is_err()
+                    {
+                        eprintln!("Failed to acknowledge the declined trade");
+                    }
+
                     let peer_id_str = peer_id.to_string();
                     chat_state.outgoing_trades.remove(&peer_id_str);
 
@@ -535,17 +1455,383 @@ async fn handle_file_transfer_event(
 
                     // Save the file sent by the other party
                     if let Err(e) =
-                        save_file_to_filesystem(file.file, &file.metadata.filename).await
+                        save_file_to_filesystem(&file.file, &file.metadata.filename).await
                     {
                         eprintln!("Failed to save file: {}", e);
                         return;
                     }
+                    reshare_file(swarm, chat_state, file_store, file);
 
                     println!("Trade successful!")
                 }
 
-                None => eprintln!("File transfer failed."),
+                // Declining is acknowledged with None too, it's only a failure if we accepted
+                None => {
+                    if chat_state
+                        .incoming_trades
+                        .contains_key(&peer_id.to_string())
+                    {
+                        eprintln!("File transfer failed.");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// If resharing is turned on, add a received file to our store and announce ourselves as a holder
+fn reshare_file(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &ChatState,
+    file_store: &mut LocalFileStore,
+    file: FileResponse,
+) {
+    if !chat_state.reshare {
+        return;
+    }
+
+    let hash = file.metadata.hash.clone();
+    if !file_store.add_received_file(file.file, file.metadata) {
+        eprintln!("Received file doesn't match its hash {hash}, not resharing it");
+        return;
+    }
+
+    utils::provide_file(swarm, &hash);
+    utils::publish_file_index(swarm, &chat_state.keypair, file_store);
+    println!("Now sharing {hash} with the network");
+}
+
+/// Serves pieces of our files to downloaders, and feeds pieces we receive to the download tracker
+async fn handle_chunk_transfer_event(
+    peer_id: PeerId,
+    message: Message<ChunkRequest, ChunkResponse>,
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &mut ChatState,
+    file_store: &mut LocalFileStore,
+) {
+    match message {
+        // Someone wants the chunk hashes or a piece of a file we hold
+        Message::Request {
+            request, channel, ..
+        } => {
+            let response = file_store.serve_chunk(request, &peer_id.to_string());
+            if swarm
+                .behaviour_mut()
+                .chunk_transfer
+                .send_response(channel, response)
+                .is_err()
+            {
+                eprintln!("Failed to send chunk response");
+            }
+        }
+
+        // Chunk hashes or a piece of a file we're downloading
+        Message::Response {
+            request_id,
+            response,
+        } => {
+            let status = chat_state
+                .downloads
+                .handle_response(swarm, peer_id, request_id, response);
+            handle_download_status(swarm, status, chat_state, file_store).await
+        }
+    }
+}
+
+/// Save finished downloads, and report failed ones
+async fn handle_download_status(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    status: DownloadStatus,
+    chat_state: &mut ChatState,
+    file_store: &mut LocalFileStore,
+) {
+    match status {
+        DownloadStatus::InProgress => {}
+        DownloadStatus::Failed(reason) => eprintln!("Download failed: {reason}"),
+        DownloadStatus::Complete(metadata, file) => {
+            if let Err(e) = save_file_to_filesystem(&file, &metadata.filename).await {
+                eprintln!("Failed to save file: {}", e);
+                return;
+            }
+            println!(
+                "Downloaded '{}' ({} bytes)",
+                metadata.filename, metadata.size
+            );
+            reshare_file(
+                swarm,
+                chat_state,
+                file_store,
+                FileResponse { file, metadata },
+            );
+        }
+    }
+}
+
+/// Found (or failed to find) the metadata for a /get, ask the owner for the file
+fn handle_get_lookup(
+    id: QueryId,
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    result: QueryResult,
+    chat_state: &mut ChatState,
+) {
+    match result {
+        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(peer_record))) => {
+            let Some(hash) = chat_state.get_queries.remove(&id) else {
+                return;
+            };
+            // One copy is enough, stop the query so other copies don't turn up later
+            if let Some(mut query) = swarm.behaviour_mut().kademlia.query_mut(&id) {
+                query.finish();
+            }
+            let key = format!("file::{}", hash);
+            let Ok((owner, metadata)) =
+                records::open::<FileMetadata>(&key, &peer_record.record.value)
+            else {
+                eprintln!("Failed to read the metadata for {hash}");
+                return;
+            };
+            if owner == *swarm.local_peer_id() {
+                println!("You uploaded '{}' yourself", metadata.filename);
+                return;
+            }
+
+            let request_id = swarm
+                .behaviour_mut()
+                .free_download
+                .send_request(&owner, FreeFileRequest { hash: hash.clone() });
+            chat_state.free_requests.insert(request_id, hash);
+            println!(
+                "Asking {} for '{}'",
+                chat_state.nicknames.get(&owner.to_string()),
+                metadata.filename
+            );
+        }
+
+        kad::QueryResult::GetRecord(Err(_)) => {
+            if let Some(hash) = chat_state.get_queries.remove(&id) {
+                eprintln!("Couldn't find a file with hash {hash}");
+            }
+        }
+
+        _ => {}
+    }
+}
+
+/// Handles files given away for free, either because of their sharing policy or as a gift
+async fn handle_free_download_event(
+    peer_id: PeerId,
+    message: Message<FreeFileRequest, Option<FileResponse>>,
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &mut ChatState,
+    file_store: &mut LocalFileStore,
+) {
+    match message {
+        // Someone wants a file without trading, only send it if they're allowed
+        Message::Request {
+            request, channel, ..
+        } => {
+            let peer_id_str = peer_id.to_string();
+            let is_gift = chat_state.outgoing_gifts.get(&peer_id_str) == Some(&request.hash);
+            let response = if is_gift || file_store.is_free_for(&request.hash, &peer_id_str) {
+                file_store
+                    .get_file(&request.hash)
+                    .zip(file_store.get_metadata(&request.hash).cloned())
+                    .map(|(file, metadata)| FileResponse { file, metadata })
+            } else {
+                None
+            };
+
+            let nickname = chat_state.nicknames.get(&peer_id_str).to_string();
+            if let Some(file) = &response {
+                if is_gift {
+                    chat_state.outgoing_gifts.remove(&peer_id_str);
+                    println!(
+                        "{} accepted your gift '{}'",
+                        nickname, file.metadata.filename
+                    );
+                } else {
+                    println!("{} downloaded '{}'", nickname, file.metadata.filename);
+                }
+            }
+
+            if swarm
+                .behaviour_mut()
+                .free_download
+                .send_response(channel, response)
+                .is_err()
+            {
+                eprintln!("Failed to send file response");
+            }
+        }
+
+        // The file we asked for, or None if we aren't allowed it
+        Message::Response {
+            request_id,
+            response,
+        } => {
+            let Some(requested) = chat_state.free_requests.remove(&request_id) else {
+                return;
+            };
+            let Some(file) = response else {
+                eprintln!(
+                    "{} isn't giving that file away, try trading for it instead",
+                    chat_state.nicknames.get(&peer_id.to_string())
+                );
+                return;
+            };
+            // Only the file we asked for will do, not whatever the peer chose to send instead
+            if file.metadata.hash != requested || compute_hash(&file.file) != requested {
+                eprintln!(
+                    "{} sent something other than {requested}, discarding it",
+                    chat_state.nicknames.get(&peer_id.to_string())
+                );
+                return;
+            }
+
+            if let Err(e) = save_file_to_filesystem(&file.file, &file.metadata.filename).await {
+                eprintln!("Failed to save file: {}", e);
+                return;
+            }
+            println!("Received '{}'", file.metadata.filename);
+            reshare_file(swarm, chat_state, file_store, file);
+        }
+    }
+}
+
+/// Handles gift offers, and the other side turning them down
+async fn handle_gift_event(
+    peer_id: PeerId,
+    message: Message<GiftMessage, AcknowledgeResponse>,
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &mut ChatState,
+) {
+    match message {
+        Message::Request {
+            request, channel, ..
+        } => {
+            let peer_id_str = peer_id.to_string();
+            match request {
+                // Someone wants to give us a file, let the user decide
+                GiftMessage::Offer { file, nickname } => {
+                    println!(
+                        "{} would like to give you '{}' ({} bytes){}. Type '/gift_accept {}' to accept it or '/gift_decline {}' to refuse.",
+                        nickname,
+                        file.filename,
+                        file.size,
+                        file.description
+                            .as_ref()
+                            .map(|desc| format!(" ({})", desc))
+                            .unwrap_or_default(),
+                        nickname,
+                        nickname
+                    );
+                    chat_state.incoming_gifts.insert(peer_id_str, file);
+                }
+
+                // Our gift was refused, stop offering it
+                GiftMessage::Decline { hash } => {
+                    if chat_state.outgoing_gifts.get(&peer_id_str) == Some(&hash) {
+                        chat_state.outgoing_gifts.remove(&peer_id_str);
+                        println!(
+                            "{} declined your gift",
+                            chat_state.nicknames.get(&peer_id_str)
+                        );
+                    }
+                }
+            }
+
+            if swarm
+                .behaviour_mut()
+                .gift
+                .send_response(channel, AcknowledgeResponse(true))
+                .is_err()
+            {
+                eprintln!("Failed to send gift acknowledgement");
             }
         }
+
+        // Acknowledgements don't carry anything
+        Message::Response { .. } => {}
+    }
+}
+
+/// Tell the user when a file on their wishlist shows up, and look up what the owner wants in return
+pub fn notify_if_wanted(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    metadata: &FileMetadata,
+    chat_state: &mut ChatState,
+    file_store: &LocalFileStore,
+) {
+    if file_store.contains_file(&metadata.hash) {
+        return;
+    }
+    let Some(want) = chat_state.wanted.check(metadata) else {
+        return;
+    };
+    let Ok(owner) = metadata.owner.parse::<PeerId>() else {
+        return;
+    };
+
+    println!(
+        "*WANTED* {} by {} matches {} on your wanted list (hash {})",
+        metadata.filename,
+        chat_state.nicknames.get(&metadata.owner),
+        want,
+        metadata.hash
+    );
+
+    let key = kad::RecordKey::new(&format!("wanted::{}", owner));
+    let queryid = swarm.behaviour_mut().kademlia.get_record(key);
+    chat_state
+        .want_queries
+        .insert(queryid, (owner, metadata.clone()));
+}
+
+/// Got (or didn't get) the wishlist of someone who has a file we want, suggest what to offer them
+fn handle_wishlist_lookup(
+    id: QueryId,
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    result: QueryResult,
+    chat_state: &mut ChatState,
+    file_store: &LocalFileStore,
+) {
+    let their_wants = match result {
+        kad::QueryResult::GetRecord(Ok(kad::GetRecordOk::FoundRecord(peer_record))) => {
+            let key = String::from_utf8_lossy(peer_record.record.key.as_ref()).to_string();
+            records::open::<PeerList<Want>>(&key, &peer_record.record.value)
+                .map(|(_, wants)| wants.entries)
+                .unwrap_or_default()
+        }
+        kad::QueryResult::GetRecord(Err(_)) => Vec::new(),
+        _ => return,
+    };
+    let Some((owner, wanted_file)) = chat_state.want_queries.remove(&id) else {
+        return;
+    };
+    if owner == *swarm.local_peer_id() {
+        return;
+    }
+
+    let nickname = chat_state.nicknames.get(&owner.to_string()).to_string();
+    let offers = suggest_offers(file_store, &their_wants, &wanted_file);
+    if offers.is_empty() {
+        println!("\tYou don't have any files to offer {nickname} yet, try /upload");
+        return;
+    }
+
+    let reason = if offers
+        .iter()
+        .any(|offer| their_wants.iter().any(|want| want.matches(offer)))
+    {
+        "on their wanted list"
+    } else {
+        "similar in size"
+    };
+    println!("\tFiles you could offer {nickname} ({reason}):");
+    for offer in offers {
+        println!(
+            "\t - {} ({}): /trade {} {} {}",
+            offer.filename, offer.size, nickname, offer.hash, wanted_file.hash
+        );
     }
 }
@@ -1,14 +1,21 @@
-use hex;
 use libp2p::PeerId;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::{collections::HashMap, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
 use tokio::{fs, fs::File, io::AsyncWriteExt};
 
+use crate::groups::GroupControl;
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct DirectMessage {
     pub sender_nickname: String,
     pub message: String,
+    /// Set when the DM is managing a private group rather than being read by the user
+    #[serde(default)]
+    pub group: Option<GroupControl>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -27,11 +34,79 @@ pub struct FileMetadata {
     pub description: Option<String>,
     pub hash: String,
     pub size: usize,
+    /// Number of CHUNK_SIZE pieces the file is split into
+    #[serde(default)]
+    pub chunk_count: usize,
+    /// Root of a Merkle tree over the chunk hashes. The chunk hashes themselves are fetched from
+    /// a holder and checked against this, then each chunk is checked as it arrives.
+    #[serde(default)]
+    pub merkle_root: String,
+    /// Set when the owner lets anyone have the file without a trade. It's signed with the rest of
+    /// the metadata, so peers resharing a copy know whether they can hand out pieces of it.
+    #[serde(default)]
+    pub free: bool,
+}
+
+/// Gossiped when someone uploads a file. The record is the same signed metadata stored on the DHT
+/// under `file::<hash>`, so it can be checked the same way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileAnnouncement {
+    pub hash: String,
+    pub record: Vec<u8>,
+}
+
+/// Asks a holder of a file for its chunk hashes, or for one piece of it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChunkRequest {
+    Tree { hash: String },
+    Chunk { hash: String, index: usize },
+}
+
+/// The requested chunk hashes or piece, None if the peer doesn't have the file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ChunkResponse {
+    Tree(Option<Vec<String>>),
+    Chunk(Option<Vec<u8>>),
 }
 
+/// Asks the owner of a file to send it without a trade
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FreeFileRequest {
+    pub hash: String,
+}
+
+/// Offering a file to someone as a gift, or turning one down
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum GiftMessage {
+    Offer {
+        file: FileMetadata,
+        nickname: String,
+    },
+    Decline {
+        hash: String,
+    },
+}
+
+/// Who can get a file without trading for it
+#[derive(Debug, Clone, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum SharingPolicy {
+    /// Only available through /trade
+    #[default]
+    TradeOnly,
+    /// Anyone can /get or /download it
+    Free,
+    /// Only these peers (by PeerId) can /get or /download it
+    FreeFor(HashSet<String>),
+}
+
+/// Files are split into pieces of this size for multi-source downloads
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
 pub struct LocalFileStore {
     metadata: HashMap<String, FileMetadata>,
     files: HashMap<String, Vec<u8>>,
+    chunk_trees: HashMap<String, Vec<String>>,
+    policies: HashMap<String, SharingPolicy>,
 }
 
 /// Each user keeps a store of the files they've uploaded.
@@ -41,6 +116,8 @@ impl LocalFileStore {
         LocalFileStore {
             metadata: HashMap::new(),
             files: HashMap::new(),
+            chunk_trees: HashMap::new(),
+            policies: HashMap::new(),
         }
     }
 
@@ -53,6 +130,7 @@ impl LocalFileStore {
         description: Option<String>,
     ) -> String {
         let hash = compute_hash(&file_bytes);
+        let leaves = chunk_hashes(&file_bytes);
 
         let metadata = FileMetadata {
             filename: filename.to_string(),
@@ -60,15 +138,33 @@ impl LocalFileStore {
             description,
             hash: hash.clone(),
             size: file_bytes.len(),
+            chunk_count: leaves.len(),
+            merkle_root: merkle_root(&leaves),
+            free: false,
         };
 
         // Add file and metadata separately (different levels of access)
+        self.chunk_trees.insert(hash.clone(), leaves);
         self.files.insert(hash.clone(), file_bytes);
         self.metadata.insert(hash.clone(), metadata);
 
         hash
     }
 
+    /// Keep a file we received from someone else so we can share it too.
+    /// The original (signed) metadata is kept, and the file is rejected if it doesn't match its hash.
+    pub fn add_received_file(&mut self, file_bytes: Vec<u8>, metadata: FileMetadata) -> bool {
+        if compute_hash(&file_bytes) != metadata.hash {
+            return false;
+        }
+
+        self.chunk_trees
+            .insert(metadata.hash.clone(), chunk_hashes(&file_bytes));
+        self.files.insert(metadata.hash.clone(), file_bytes);
+        self.metadata.insert(metadata.hash.clone(), metadata);
+        true
+    }
+
     pub fn get_metadata(&self, hash: &str) -> Option<&FileMetadata> {
         self.metadata.get(hash)
     }
@@ -84,6 +180,60 @@ impl LocalFileStore {
         self.files.get(hash).cloned()
     }
 
+    /// Get one CHUNK_SIZE piece of a file
+    pub fn get_chunk(&self, hash: &str, index: usize) -> Option<Vec<u8>> {
+        self.files
+            .get(hash)?
+            .chunks(CHUNK_SIZE)
+            .nth(index)
+            .map(|chunk| chunk.to_vec())
+    }
+
+    /// Get the hash of every chunk of a file, the leaves of its Merkle tree
+    pub fn get_chunk_tree(&self, hash: &str) -> Option<Vec<String>> {
+        self.chunk_trees.get(hash).cloned()
+    }
+
+    /// Change who can get one of our files without trading, false if we don't have the file.
+    /// The metadata is marked free to match, it needs to be signed and published again.
+    pub fn set_policy(&mut self, hash: &str, policy: SharingPolicy) -> bool {
+        let Some(metadata) = self.metadata.get_mut(hash) else {
+            return false;
+        };
+        metadata.free = policy == SharingPolicy::Free;
+        self.policies.insert(hash.to_string(), policy);
+        true
+    }
+
+    /// Check whether a peer can get a file without trading for it.
+    /// Copies of other people's files follow the free flag in the owner's metadata.
+    pub fn is_free_for(&self, hash: &str, peer_id: &str) -> bool {
+        match self.policies.get(hash) {
+            Some(SharingPolicy::Free) => true,
+            Some(SharingPolicy::FreeFor(peers)) => peers.contains(peer_id),
+            Some(SharingPolicy::TradeOnly) => false,
+            None => self
+                .metadata
+                .get(hash)
+                .is_some_and(|metadata| metadata.free),
+        }
+    }
+
+    /// Answer a request for a file's chunk hashes or one of its pieces. Trade-only files can't be
+    /// pulled in pieces, so they're answered with None as if we didn't have them.
+    pub fn serve_chunk(&self, request: ChunkRequest, peer_id: &str) -> ChunkResponse {
+        match request {
+            ChunkRequest::Tree { hash } => ChunkResponse::Tree(
+                self.get_chunk_tree(&hash)
+                    .filter(|_| self.is_free_for(&hash, peer_id)),
+            ),
+            ChunkRequest::Chunk { hash, index } => ChunkResponse::Chunk(
+                self.get_chunk(&hash, index)
+                    .filter(|_| self.is_free_for(&hash, peer_id)),
+            ),
+        }
+    }
+
     /// Check if the file store includes a given file
     pub fn contains_file(&self, hash: &str) -> bool {
         self.files.contains_key(hash)
@@ -97,11 +247,66 @@ pub fn compute_hash(data: &[u8]) -> String {
     hex::encode(hasher.finalize())[..8].to_string()
 }
 
-/// Saves a Vec<u8> to `traded_files/filename`, creating the folder if needed
+/// Merkle leaves and interior nodes are hashed with different prefixes, so a chunk can never be
+/// passed off as a pair of hashes or the other way round
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+/// Generate the full SHA256 hash of a single chunk, not truncated since users never type it.
+/// This is a Merkle leaf, so it's hashed with the leaf prefix.
+pub fn compute_chunk_hash(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}
+
+/// Hash every CHUNK_SIZE piece of a file
+pub fn chunk_hashes(data: &[u8]) -> Vec<String> {
+    data.chunks(CHUNK_SIZE).map(compute_chunk_hash).collect()
+}
+
+/// Fold a list of chunk hashes into a single Merkle root.
+/// Each level hashes the raw digests of neighbouring pairs together, an odd one out is carried up
+/// as-is. A leaf that isn't a hex digest can never match a chunk, so it's hashed as empty.
+pub fn merkle_root(leaves: &[String]) -> String {
+    if leaves.is_empty() {
+        return compute_chunk_hash(&[]);
+    }
+
+    let mut level: Vec<Vec<u8>> = leaves
+        .iter()
+        .map(|leaf| hex::decode(leaf).unwrap_or_default())
+        .collect();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = Sha256::new();
+                    hasher.update([NODE_PREFIX]);
+                    hasher.update(left);
+                    hasher.update(right);
+                    hasher.finalize().to_vec()
+                }
+                [single] => single.clone(),
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    hex::encode(&level[0])
+}
+
+/// Saves a Vec<u8> to `traded_files/filename`, creating the folder if needed.
+/// Filenames come from other peers, so only the last part of the name is used and nothing can be
+/// written outside the folder.
 pub async fn save_file_to_filesystem(
-    data: Vec<u8>,
+    data: &[u8],
     filename: &str,
 ) -> Result<(), Box<dyn std::error::Error>> {
+    let Some(filename) = Path::new(filename).file_name() else {
+        return Err(format!("'{filename}' isn't a valid file name").into());
+    };
     let dir_path = Path::new("traded_files");
 
     // Create the directory if it doesn't exist
@@ -111,6 +316,163 @@ pub async fn save_file_to_filesystem(
 
     let file_path = dir_path.join(filename);
     let mut file = File::create(file_path).await?;
-    file.write_all(&data).await?;
+    file.write_all(data).await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(left: &str, right: &str) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update([NODE_PREFIX]);
+        hasher.update(hex::decode(left).unwrap());
+        hasher.update(hex::decode(right).unwrap());
+        hex::encode(hasher.finalize())
+    }
+
+    #[test]
+    fn chunk_hash_is_prefixed() {
+        let plain = hex::encode(Sha256::digest(b"chunk"));
+        assert_ne!(compute_chunk_hash(b"chunk"), plain);
+    }
+
+    #[test]
+    fn single_leaf_is_the_root() {
+        let leaf = compute_chunk_hash(b"only");
+        assert_eq!(merkle_root(std::slice::from_ref(&leaf)), leaf);
+    }
+
+    #[test]
+    fn pairs_hash_raw_digests() {
+        let a = compute_chunk_hash(b"a");
+        let b = compute_chunk_hash(b"b");
+        assert_eq!(merkle_root(&[a.clone(), b.clone()]), node(&a, &b));
+        assert_ne!(merkle_root(&[a.clone(), b.clone()]), merkle_root(&[b, a]));
+    }
+
+    #[test]
+    fn odd_leaf_is_carried_up() {
+        let leaves: Vec<String> = ["a", "b", "c"]
+            .iter()
+            .map(|chunk| compute_chunk_hash(chunk.as_bytes()))
+            .collect();
+        assert_eq!(
+            merkle_root(&leaves),
+            node(&node(&leaves[0], &leaves[1]), &leaves[2])
+        );
+    }
+
+    #[test]
+    fn node_cant_pass_as_a_leaf() {
+        // The two digests of a pair, given as one chunk, hash to something else entirely
+        let a = compute_chunk_hash(b"a");
+        let b = compute_chunk_hash(b"b");
+        let mut joined = hex::decode(&a).unwrap();
+        joined.extend(hex::decode(&b).unwrap());
+        assert_ne!(compute_chunk_hash(&joined), merkle_root(&[a, b]));
+    }
+
+    #[test]
+    fn chunk_hashes_split_at_chunk_size() {
+        let data = vec![7u8; CHUNK_SIZE + 1];
+        let hashes = chunk_hashes(&data);
+        assert_eq!(hashes.len(), 2);
+        assert_eq!(hashes[1], compute_chunk_hash(&[7]));
+    }
+
+    #[test]
+    fn chunks_cover_the_file() {
+        let mut store = LocalFileStore::new();
+        let data: Vec<u8> = (0..CHUNK_SIZE + 10).map(|i| i as u8).collect();
+        let hash = store.add_file(data.clone(), "file.bin", &PeerId::random(), None);
+        let metadata = store.get_metadata(&hash).unwrap();
+        let tree = store.get_chunk_tree(&hash).unwrap();
+        assert_eq!(metadata.chunk_count, 2);
+        assert_eq!(tree, chunk_hashes(&data));
+        assert_eq!(merkle_root(&tree), metadata.merkle_root);
+
+        assert_eq!(store.get_chunk(&hash, 0).unwrap(), data[..CHUNK_SIZE]);
+        assert_eq!(store.get_chunk(&hash, 1).unwrap(), data[CHUNK_SIZE..]);
+        assert_eq!(store.get_chunk(&hash, 2), None);
+        assert_eq!(store.get_chunk("missing", 0), None);
+    }
+
+    #[test]
+    fn received_files_must_match_their_hash() {
+        let mut store = LocalFileStore::new();
+        let hash = store.add_file(b"original".to_vec(), "a.txt", &PeerId::random(), None);
+        let metadata = store.get_metadata(&hash).unwrap().clone();
+
+        let mut holder = LocalFileStore::new();
+        assert!(!holder.add_received_file(b"tampered".to_vec(), metadata.clone()));
+        assert!(!holder.contains_file(&hash));
+        assert!(holder.add_received_file(b"original".to_vec(), metadata));
+        assert_eq!(holder.get_file(&hash).unwrap(), b"original");
+    }
+
+    #[test]
+    fn files_are_trade_only_until_shared() {
+        let mut store = LocalFileStore::new();
+        let hash = store.add_file(b"data".to_vec(), "a.txt", &PeerId::random(), None);
+        let (friend, stranger) = (PeerId::random().to_string(), PeerId::random().to_string());
+        assert!(!store.is_free_for(&hash, &friend));
+
+        let friends = HashSet::from([friend.clone()]);
+        assert!(store.set_policy(&hash, SharingPolicy::FreeFor(friends)));
+        assert!(store.is_free_for(&hash, &friend));
+        assert!(!store.is_free_for(&hash, &stranger));
+
+        assert!(store.set_policy(&hash, SharingPolicy::Free));
+        assert!(store.is_free_for(&hash, &stranger));
+        assert!(!store.set_policy("missing", SharingPolicy::Free));
+    }
+
+    #[test]
+    fn copies_are_served_if_the_owner_made_them_free() {
+        let mut owner = LocalFileStore::new();
+        let data = vec![7; CHUNK_SIZE + 1];
+        let hash = owner.add_file(data.clone(), "a.txt", &PeerId::random(), None);
+        let trade_only = owner.get_metadata(&hash).unwrap().clone();
+        assert!(owner.set_policy(&hash, SharingPolicy::Free));
+        let free = owner.get_metadata(&hash).unwrap().clone();
+        assert!(free.free);
+
+        let downloader = PeerId::random().to_string();
+        let mut holder = LocalFileStore::new();
+        assert!(holder.add_received_file(data.clone(), free));
+        let tree = ChunkRequest::Tree { hash: hash.clone() };
+        let ChunkResponse::Tree(Some(leaves)) = holder.serve_chunk(tree.clone(), &downloader)
+        else {
+            panic!("a free copy should be served");
+        };
+        assert_eq!(leaves, chunk_hashes(&data));
+        let piece = ChunkRequest::Chunk {
+            hash: hash.clone(),
+            index: 1,
+        };
+        let ChunkResponse::Chunk(Some(chunk)) = holder.serve_chunk(piece.clone(), &downloader)
+        else {
+            panic!("a free copy should be served");
+        };
+        assert_eq!(chunk, vec![7]);
+
+        let mut holder = LocalFileStore::new();
+        assert!(holder.add_received_file(data, trade_only));
+        assert!(matches!(
+            holder.serve_chunk(tree, &downloader),
+            ChunkResponse::Tree(None)
+        ));
+        assert!(matches!(
+            holder.serve_chunk(piece, &downloader),
+            ChunkResponse::Chunk(None)
+        ));
+    }
+
+    #[tokio::test]
+    async fn saved_files_stay_in_the_folder() {
+        assert!(save_file_to_filesystem(b"", "..").await.is_err());
+        assert!(save_file_to_filesystem(b"", "/").await.is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use libp2p::{gossipsub::IdentTopic, PeerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use crate::storage::{read_cbor, write_private_cbor};
+
+/// Everything a member needs to take part in a group. Sent to members over the direct message
+/// channel, which is already encrypted end to end by noise.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GroupKey {
+    pub id: String,
+    pub name: String,
+    pub creator: String,
+    /// Bumped every time the key is replaced
+    pub epoch: u32,
+    pub key: [u8; 32],
+    pub members: Vec<String>,
+}
+
+/// Group management, piggybacking on direct messages
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GroupControl {
+    Invite(GroupKey),
+    /// Someone joined or was removed, here's the replacement key and member list
+    Rekey(GroupKey),
+    Removed {
+        id: String,
+    },
+}
+
+/// What's actually published on a group's topic. The bytes are encoded as CBOR byte strings, as a
+/// plain array would spend two bytes on most of them.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GroupEnvelope {
+    pub epoch: u32,
+    #[serde(with = "serde_bytes")]
+    pub nonce: [u8; 12],
+    #[serde(with = "serde_bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+impl GroupKey {
+    /// A brand new group with just its creator in it
+    pub fn generate(name: String, creator: PeerId) -> Self {
+        GroupKey {
+            id: format!("{:016x}", rand::random::<u64>()),
+            name,
+            creator: creator.to_string(),
+            epoch: 0,
+            key: rand::random(),
+            members: vec![creator.to_string()],
+        }
+    }
+
+    pub fn topic(&self) -> IdentTopic {
+        IdentTopic::new(format!("group/{}", self.id))
+    }
+
+    pub fn is_creator(&self, peer: &PeerId) -> bool {
+        self.creator == peer.to_string()
+    }
+
+    /// The group and key epoch are bound to every message, so ciphertext can't be replayed into
+    /// another group or under an older key
+    fn associated_data(&self) -> Vec<u8> {
+        format!("{}\0{}", self.id, self.epoch).into_bytes()
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<GroupEnvelope, String> {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key));
+        let nonce: [u8; 12] = rand::random();
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: &self.associated_data(),
+                },
+            )
+            .map_err(|_| "encryption failed".to_string())?;
+        Ok(GroupEnvelope {
+            epoch: self.epoch,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    pub fn decrypt(&self, envelope: &GroupEnvelope) -> Result<Vec<u8>, String> {
+        if envelope.epoch != self.epoch {
+            return Err(format!(
+                "it was sent with key {} but we have key {}",
+                envelope.epoch, self.epoch
+            ));
+        }
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.key));
+        cipher
+            .decrypt(
+                Nonce::from_slice(&envelope.nonce),
+                Payload {
+                    msg: &envelope.ciphertext,
+                    aad: &self.associated_data(),
+                },
+            )
+            .map_err(|_| "it couldn't be decrypted".to_string())
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SavedGroups {
+    groups: Vec<GroupKey>,
+}
+
+/// The private groups we're in, by name, saved to the data directory whenever they change. The
+/// file holds every group's key, so only we can read it.
+/// Invites wait here until they're accepted, they aren't saved.
+pub struct Groups {
+    groups: HashMap<String, GroupKey>,
+    invites: HashMap<String, GroupKey>,
+    path: PathBuf,
+}
+
+impl Groups {
+    pub fn load(data_dir: &Path) -> Self {
+        let path = data_dir.join("groups.cbor");
+        let saved = read_cbor::<SavedGroups>(&path).unwrap_or_default();
+        Groups {
+            groups: saved
+                .groups
+                .into_iter()
+                .map(|group| (group.name.clone(), group))
+                .collect(),
+            invites: HashMap::new(),
+            path,
+        }
+    }
+
+    fn save(&self) {
+        let saved = SavedGroups {
+            groups: self.groups.values().cloned().collect(),
+        };
+        write_private_cbor(&self.path, &saved);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&GroupKey> {
+        self.groups.get(name)
+    }
+
+    pub fn by_id(&self, id: &str) -> Option<&GroupKey> {
+        self.groups.values().find(|group| group.id == id)
+    }
+
+    pub fn by_topic(&self, topic: &libp2p::gossipsub::TopicHash) -> Option<&GroupKey> {
+        self.groups
+            .values()
+            .find(|group| group.topic().hash() == *topic)
+    }
+
+    /// Every group, sorted by name
+    pub fn all(&self) -> Vec<&GroupKey> {
+        let mut groups: Vec<&GroupKey> = self.groups.values().collect();
+        groups.sort_by_key(|group| group.name.to_lowercase());
+        groups
+    }
+
+    /// Add or replace a group, failing if a different group already has its name
+    pub fn insert(&mut self, group: GroupKey) -> Result<(), String> {
+        if let Some(existing) = self.groups.get(&group.name) {
+            if existing.id != group.id {
+                return Err(format!("You're already in a group called '{}'", group.name));
+            }
+        }
+        self.groups.insert(group.name.clone(), group);
+        self.save();
+        Ok(())
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<GroupKey> {
+        let group = self.groups.remove(name)?;
+        self.save();
+        Some(group)
+    }
+
+    pub fn add_invite(&mut self, invite: GroupKey) {
+        self.invites.insert(invite.name.clone(), invite);
+    }
+
+    pub fn invite(&self, name: &str) -> Option<&GroupKey> {
+        self.invites.get(name)
+    }
+
+    pub fn take_invite(&mut self, name: &str) -> Option<GroupKey> {
+        self.invites.remove(name)
+    }
+
+    pub fn invite_by_id(&self, id: &str) -> Option<&GroupKey> {
+        self.invites.values().find(|invite| invite.id == id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn group() -> GroupKey {
+        GroupKey::generate("friends".to_string(), PeerId::random())
+    }
+
+    #[test]
+    fn decrypts_what_it_encrypted() {
+        let group = group();
+        let envelope = group.encrypt(b"hello").unwrap();
+        assert_eq!(envelope.epoch, group.epoch);
+        assert_eq!(group.decrypt(&envelope).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn old_keys_are_refused() {
+        let group = group();
+        let envelope = group.encrypt(b"hello").unwrap();
+        let rotated = GroupKey {
+            epoch: group.epoch + 1,
+            key: rand::random(),
+            ..group.clone()
+        };
+        assert!(rotated.decrypt(&envelope).is_err());
+
+        // Same key but claiming another epoch doesn't get past the associated data either
+        let mut replayed = rotated.encrypt(b"hello").unwrap();
+        replayed.epoch = group.epoch;
+        assert!(group.decrypt(&replayed).is_err());
+    }
+
+    #[test]
+    fn other_groups_cant_read_it() {
+        let group = group();
+        let other = GroupKey {
+            id: "another".to_string(),
+            ..group.clone()
+        };
+        let envelope = group.encrypt(b"hello").unwrap();
+        assert!(other.decrypt(&envelope).is_err());
+    }
+
+    #[test]
+    fn tampering_is_noticed() {
+        let group = group();
+        let mut envelope = group.encrypt(b"hello").unwrap();
+        envelope.ciphertext[0] ^= 1;
+        assert!(group.decrypt(&envelope).is_err());
+    }
+
+    #[test]
+    fn groups_survive_a_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut groups = Groups::load(dir.path());
+        let group = group();
+        groups.insert(group.clone()).unwrap();
+        groups.add_invite(GroupKey::generate("invited".to_string(), PeerId::random()));
+
+        let mut groups = Groups::load(dir.path());
+        assert_eq!(groups.get("friends"), Some(&group));
+        assert_eq!(groups.by_id(&group.id), Some(&group));
+        assert_eq!(groups.take_invite("invited"), None);
+    }
+
+    #[test]
+    fn invites_wait_until_taken() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut groups = Groups::load(dir.path());
+        let invite = group();
+        groups.add_invite(invite.clone());
+        assert_eq!(groups.invite("friends"), Some(&invite));
+        assert_eq!(groups.invite_by_id(&invite.id), Some(&invite));
+        assert_eq!(groups.take_invite("friends"), Some(invite));
+        assert_eq!(groups.invite("friends"), None);
+    }
+
+    #[test]
+    fn names_must_be_unique() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut groups = Groups::load(dir.path());
+        let group = group();
+        groups.insert(group.clone()).unwrap();
+        let clash = GroupKey::generate("friends".to_string(), PeerId::random());
+        assert!(groups.insert(clash).is_err());
+
+        // Replacing the same group, say after a rekey, is fine
+        let rekeyed = GroupKey {
+            epoch: 1,
+            ..group.clone()
+        };
+        groups.insert(rekeyed.clone()).unwrap();
+        assert_eq!(groups.get("friends"), Some(&rekeyed));
+    }
+}
@@ -1,17 +1,42 @@
-use libp2p::{gossipsub::IdentTopic, kad, swarm::Swarm, PeerId};
+use libp2p::{gossipsub::IdentTopic, kad, swarm::Swarm, Multiaddr, PeerId};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{fs, error::Error, path::Path, str::FromStr};
+use std::{collections::HashSet, error::Error, fs, path::Path, str::FromStr};
 use tokio::io::{BufReader, Lines, Stdin};
 
+use crate::catalog::{ListOptions, SortBy};
+use crate::chat;
 use crate::events::SwapBytesBehaviour;
-use crate::files::{DirectMessage, FileResponse, LocalFileStore};
+use crate::files::{DirectMessage, FreeFileRequest, GiftMessage, LocalFileStore, SharingPolicy};
+use crate::groups::{GroupControl, GroupKey};
 use crate::utils::{self, prompt_for_nickname, ChatState, TradeRequest};
+use crate::validation;
+use crate::wanted::Want;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatMessage {
     pub message: String,
     pub nickname: String,
+    /// Older peers don't send these, so they all have defaults
+    #[serde(default)]
+    pub id: String,
+    /// When it was sent, in seconds since the unix epoch
+    #[serde(default)]
+    pub timestamp: u64,
+    /// The message this is replying to
+    #[serde(default)]
+    pub reply_to: Option<MessageRef>,
+    /// Peer IDs of everyone @mentioned
+    #[serde(default)]
+    pub mentions: Vec<String>,
+}
+
+/// Points at a chat message. IDs are picked by whoever sends the message, so they're only unique
+/// together with the author.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MessageRef {
+    pub author: String,
+    pub id: String,
 }
 
 pub async fn handle_input_line(
@@ -27,29 +52,13 @@ pub async fn handle_input_line(
     }
 
     // All inputs without the command prefix should just get sent as messages
-    if line.chars().nth(0).unwrap() != '/' {
-        let message = ChatMessage {
-            message: line,
-            nickname: chat_state.nickname.clone(),
-        };
-
-        let message_bytes = serde_cbor::to_vec(&message)?;
-
-        if let Err(e) = swarm
-            .behaviour_mut()
-            .chat
-            .gossipsub
-            .publish(current_topic, message_bytes)
-        {
-            eprintln!("Failed to send message: {}", e);
-        }
-
-        return Ok(());
+    if !line.starts_with('/') {
+        return send_chat(swarm, current_topic, chat_state, line, None, None);
     }
 
     // Strip the forward slash, handle the case where only a forward slash is given
     let args = split_string(&line[1..]);
-    let Some(cmd) = args.get(0) else {
+    let Some(cmd) = args.first() else {
         println!("No command given");
         return Ok(());
     };
@@ -59,31 +68,130 @@ pub async fn handle_input_line(
         "help" => {
             println!("/help");
             println!("\tShow this help message.");
-        
+
             println!("/nick <nickname>");
             println!("\tChange your nickname.");
-        
+
+            println!("/reply <message_id> <message>");
+            println!("\tReply to a chat message, using the ID shown next to it. Mention someone with @nickname to highlight it for them.");
+
             println!("/list_peers");
             println!("\tList all the peers currently on the network.");
-        
+
+            println!("/listen");
+            println!("\tShow the addresses you're listening on, for other peers to dial.");
+
+            println!("/quit");
+            println!("\tLeave the network. Trades and gifts waiting on you are declined, and anyone you've offered a trade to is told you've gone. Ctrl-C does the same.");
+
+            println!("/whois <nickname>");
+            println!("\tShow what's known about a peer: their ID, addresses, software, latency, files and any open trades.");
+
+            println!("/contacts");
+            println!("\tShow your contact book.");
+
+            println!("/petname <nickname> [petname]");
+            println!("\tGive a peer your own name for them, shown instead of their nickname and usable in any command. Leave the petname out to clear it.");
+
+            println!("/friend <nickname>");
+            println!("\tMark a peer as a friend. /unfriend <nickname> undoes it.");
+
+            println!("/pin <nickname> [address]");
+            println!("\tSave a peer's ID and addresses (the ones they told us about, or the one given) so they're dialled every time you start up.");
+
+            println!("/forget <nickname>");
+            println!("\tRemove a peer from your contacts.");
+
+            println!("/dm_contacts <on|off>");
+            println!("\tLet your contacts DM you when you aren't trading with them.");
+
+            println!("/group_create <name>");
+            println!("\tStart a private group. Messages in it are encrypted, so only the people you invite can read them.");
+
+            println!("/group_invite <group> <nickname>");
+            println!("\tInvite someone to a group you created. The group's key is sent to them over a DM.");
+
+            println!("/group_accept <group> [local_name]");
+            println!(
+                "\tJoin a group you've been invited to, under local_name if you already have a group with its name. /group_decline <group> refuses the invite."
+            );
+
+            println!("/group <group> <message>");
+            println!("\tSend a message to a private group.");
+
+            println!("/groups");
+            println!("\tShow the private groups you're in and their members.");
+
+            println!("/group_remove <group> <nickname>");
+            println!("\tRemove someone from a group you created. Everyone else is sent a new key, so they can't read anything from then on.");
+
+            println!("/group_leave <group>");
+            println!("\tLeave a private group.");
+
+            println!("/block [nickname]");
+            println!("\tHide a peer's messages, turn away their DMs and trades, and refuse their connections. On its own, shows who you've blocked.");
+
+            println!("/unblock <nickname>");
+            println!("\tLet a blocked peer back in.");
+
             println!("/upload <filename> <description (optional)>");
             println!("\tUpload a file to the application. This will share the name of the file, its size, and other metadata, but not the actual file. You are given a hash when you upload a file that you use to identify it.");
-        
-            println!("/list_files");
-            println!("\tShow a list of all the files that have been uploaded, grouped by the uploader.");
-        
+
+            println!("/list_files [--owner <nickname>] [--sort <name|size>] [--page <number>]");
+            println!("\tShow a table of the files on the network, grouped by the uploader. Files you've seen before are remembered, so slow peers don't hold up the list.");
+
+            println!("/share <file_hash> <trade|free|peers> <nicknames...>");
+            println!("\tChoose who can get one of your files without trading: nobody (trade), anyone (free), or only the listed peers.");
+
+            println!("/get <file_hash>");
+            println!("\tFetch a free file straight from its owner.");
+
+            println!("/send <nickname> <file_hash>");
+            println!(
+                "\tOffer one of your files to someone as a gift. They can accept or refuse it."
+            );
+
+            println!("/gift_accept <nickname>");
+            println!("\tAccept a gift. The file will transfer immediately.");
+
+            println!("/gift_decline <nickname>");
+            println!("\tRefuse a gift.");
+
+            println!("/want <search terms or file_hash>");
+            println!("\tAdd something to your wanted list. You'll be told when a matching file shows up, along with what you could offer for it.");
+
+            println!("/wants");
+            println!("\tShow your wanted list.");
+
+            println!("/unwant <number>");
+            println!("\tRemove something from your wanted list, using its number from /wants.");
+
+            println!("/want_publish <on|off>");
+            println!(
+                "\tShare your wanted list on the network so others can see what you're after."
+            );
+
+            println!("/providers <file_hash>");
+            println!("\tShow everyone who holds a copy of a file, not just the original uploader.");
+
+            println!("/download <file_hash>");
+            println!("\tDownload a free file in pieces from everyone who holds it at once. Much faster for large, popular files.");
+
             println!("/dm <nickname> <message>");
-            println!("\tIn the middle of a trade, you can DM the other trader to discuss private details about the trade.");
-        
+            println!("\tIn the middle of a trade, you can DM the other trader to discuss private details about the trade. Contacts can be DMed at any time if they've allowed it with /dm_contacts. DMs to someone offline are sent when they're back.");
+
             println!("/trade <nickname> <your_file_hash> <their_file_hash>");
             println!("\tSend a trade offer that the other trader can accept or decline. Use /list_files to find other people's file hashes.");
-        
+
             println!("/trade_accept");
             println!("\tAccept a trade offer. The files will transfer immediately.");
-        
+
             println!("/trade_decline");
             println!("\tDecline a trade offer.");
-        
+
+            println!("/rules");
+            println!("\tReload your trade rules file and show the rules in it.");
+
             println!();
 
             Ok(())
@@ -96,24 +204,45 @@ pub async fn handle_input_line(
                 return Ok(());
             }
 
-            // If only the command provided, enter prompt loop
-            let Some(nickname) = args.get(1) else {
-                let nickname = prompt_for_nickname(stdin, swarm).await;
-                chat_state.nickname = nickname;
-                return Ok(());
+            // A nickname given with the command is checked first, so a bad one doesn't count
+            // towards the rate limit. If it's missing or empty, enter prompt loop.
+            let given = match args.get(1) {
+                Some(nickname) if !nickname.trim().is_empty() => {
+                    match utils::process_nickname(swarm, nickname) {
+                        Ok(nickname) => Some(nickname),
+                        Err(e) => {
+                            println!("{e}");
+                            return Ok(());
+                        }
+                    }
+                }
+                Some(_) => {
+                    println!("Nickname cannot be empty. Please enter a valid nickname.");
+                    None
+                }
+                None => None,
             };
 
-            // If given nickname is empty, enter prompt loop
-            if nickname.trim().is_empty() {
-                println!("Nickname cannot be empty. Please enter a valid nickname.");
-                let nickname = prompt_for_nickname(stdin, swarm).await;
-                chat_state.nickname = nickname;
+            // Peers only take a few nickname changes a minute, any more wouldn't reach them
+            let local_peer_id = *swarm.local_peer_id();
+            let nickname_topic = chat_state.nickname_topic.hash();
+            if let Err(e) = chat_state
+                .gossip_validator
+                .check_sending_rate(local_peer_id, &nickname_topic)
+            {
+                println!("You're changing your nickname too often, {e}");
                 return Ok(());
             }
 
-            let nickname = utils::process_nickname(swarm, nickname);
-            println!("Nickname set to '{}'", nickname);
-            chat_state.nickname = nickname;
+            let nickname = match given {
+                Some(nickname) => {
+                    println!("Nickname set to '{}'", nickname);
+                    nickname
+                }
+                None => prompt_for_nickname(stdin, swarm).await,
+            };
+            chat_state.set_nickname(nickname);
+            utils::broadcast_nickname(swarm, chat_state);
 
             Ok(())
         }
@@ -152,66 +281,98 @@ pub async fn handle_input_line(
             // Get description if it exists
             let description = args.get(2).cloned();
 
+            // Peers won't pass on announcements with a longer name or description
+            if let Err(e) = validation::check_upload(filename, description.as_deref()) {
+                println!("Can't upload {filename}, its {e}");
+                return Ok(());
+            }
+
             // Share file metadata to peers
-            let peer_id = swarm.local_peer_id().clone();
+            let peer_id = *swarm.local_peer_id();
             let hash = file_store.add_file(file_bytes, filename, &peer_id, description);
             if let Some(metadata) = file_store.get_metadata(&hash) {
-                if let Ok(serialized) = serde_cbor::to_vec(metadata) {
-                    let record = kad::Record {
-                        key: kad::RecordKey::new(&format!("file::{}", hash)),
-                        value: serialized,
-                        publisher: Some(peer_id),
-                        expires: None,
-                    };
-
-                    if let Err(e) = swarm
-                        .behaviour_mut()
-                        .kademlia
-                        .put_record(record, kad::Quorum::One)
-                    {
-                        println!("Error publishing metadata: {e}");
-                    } else {
+                match utils::publish_metadata(swarm, &chat_state.keypair, metadata) {
+                    Ok(signed) => {
                         println!(
                             "Uploaded and shared metadata for file {} with hash {}",
                             filename, hash
                         );
+                        utils::announce_file(swarm, chat_state, &hash, signed);
                     }
-                } else {
-                    println!("Error serializing metadata");
+                    Err(e) => println!("{e}"),
                 }
             }
 
-            // Update a set of what files we have on the DHT, makes it easier to query everyone's files.
-            let file_hashes = file_store.all_hashes();
-            let index_key = format!("file_index::{}", swarm.local_peer_id());
-            let record = kad::Record {
-                key: kad::RecordKey::new(&index_key),
-                value: serde_cbor::to_vec(&file_hashes)?,
-                publisher: Some(peer_id),
-                expires: None,
-            };
-
-            if let Err(_) = swarm
-                .behaviour_mut()
-                .kademlia
-                .put_record(record, kad::Quorum::One)
-            {
-                eprintln!("Failed to update file list");
-            }
+            // Let everyone know we hold it, and add it to our file index
+            utils::provide_file(swarm, &hash);
+            utils::publish_file_index(swarm, &chat_state.keypair, file_store);
 
             Ok(())
         }
 
         "list_files" => {
-            let peers: Vec<PeerId> = swarm.connected_peers().cloned().collect();
-            for peer_id in peers {
-                let key = kad::RecordKey::new(&format!("file_index::{}", peer_id));
-                let queryid = swarm.behaviour_mut().kademlia.get_record(key);
-                chat_state.pending_keys.insert(queryid);
+            let mut options = ListOptions {
+                owner: None,
+                sort: SortBy::Name,
+                page: 1,
+            };
+            let mut flags = args[1..].iter();
+            while let Some(flag) = flags.next() {
+                let value = flags.next();
+                match (flag.as_str(), value.map(String::as_str)) {
+                    ("--owner", Some(nickname)) => {
+                        let Some(peer_id) = resolve_peer(nickname, chat_state, stdin)
+                            .await
+                            .and_then(|peer_id| PeerId::from_str(&peer_id).ok())
+                        else {
+                            return Ok(());
+                        };
+                        options.owner = Some(peer_id);
+                    }
+                    ("--sort", Some("name")) => options.sort = SortBy::Name,
+                    ("--sort", Some("size")) => options.sort = SortBy::Size,
+                    ("--page", Some(page)) if page.parse::<usize>().is_ok_and(|p| p > 0) => {
+                        options.page = page.parse().unwrap();
+                    }
+                    _ => {
+                        println!("Usage: /list_files [--owner <nickname>] [--sort <name|size>] [--page <number>]");
+                        return Ok(());
+                    }
+                }
             }
+
+            // Refresh the file lists of whoever we're asking about, the cache fills in the rest
+            chat_state.catalog.start_listing(swarm, options);
+            chat_state
+                .catalog
+                .poll_listing(swarm, &chat_state.nicknames);
             Ok(())
         }
 
+        "reply" => {
+            if args.len() < 3 {
+                println!("Usage: /reply <message_id> <message>");
+                return Ok(());
+            }
+            let id = &args[1];
+            let Some((author, group)) = resolve_message(id, chat_state, stdin).await else {
+                return Ok(());
+            };
+            let reply_to = MessageRef {
+                author: author.to_string(),
+                id: id.clone(),
+            };
+            // Replies to a group message stay in the group
+            send_chat(
+                swarm,
+                current_topic,
+                chat_state,
+                args[2..].join(" "),
+                Some(reply_to),
+                group,
+            )
+        }
+
         "dm" => {
             // Parse nickname
             if args.len() != 3 {
@@ -222,8 +383,7 @@ pub async fn handle_input_line(
                 eprintln!("Failed to parse nickname");
                 return Ok(());
             };
-            let Some(peer_id_str) = chat_state.nicknames.get_key_from_value(nickname) else {
-                eprintln!("Nickname not found");
+            let Some(peer_id_str) = resolve_peer(nickname, chat_state, stdin).await else {
                 return Ok(());
             };
 
@@ -232,12 +392,13 @@ pub async fn handle_input_line(
                 return Ok(());
             };
 
-            // If users aren't trading together, they shouldn't be able to DM each other
+            // Outside of a trade, only contacts can be DMed, and only if they've allowed it
             if !chat_state.incoming_trades.contains_key(&peer_id_str)
                 && !chat_state.outgoing_trades.contains_key(&peer_id_str)
+                && !chat_state.nicknames.contacts().contains(&peerid)
             {
                 eprintln!(
-                    "You can only DM someone while a trade request is open. Open one with /trade"
+                    "You can only DM someone while a trade request is open, or a contact. Open a trade with /trade"
                 );
                 return Ok(());
             }
@@ -248,13 +409,17 @@ pub async fn handle_input_line(
                 return Ok(());
             };
 
-            swarm.behaviour_mut().direct_message.send_request(
-                &peerid,
-                DirectMessage {
-                    message: message.clone(),
-                    sender_nickname: chat_state.nickname.clone(),
-                },
-            );
+            let message = DirectMessage {
+                message: message.clone(),
+                sender_nickname: chat_state.nickname.clone(),
+                group: None,
+            };
+            if utils::deliver_dm(swarm, chat_state, peerid, message) {
+                println!(
+                    "{} is offline, your DM will be delivered when they're back",
+                    chat_state.nicknames.get(&peer_id_str)
+                );
+            }
 
             Ok(())
         }
@@ -269,8 +434,7 @@ pub async fn handle_input_line(
                 eprintln!("Failed to parse nickname");
                 return Ok(());
             };
-            let Some(peer_id_str) = chat_state.nicknames.get_key_from_value(nickname) else {
-                eprintln!("Nickname not found");
+            let Some(peer_id_str) = resolve_peer(nickname, chat_state, stdin).await else {
                 return Ok(());
             };
             let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
@@ -343,8 +507,7 @@ pub async fn handle_input_line(
                 eprintln!("Failed to parse nickname");
                 return Ok(());
             };
-            let Some(peer_id_str) = chat_state.nicknames.get_key_from_value(nickname) else {
-                eprintln!("Nickname not found");
+            let Some(peer_id_str) = resolve_peer(nickname, chat_state, stdin).await else {
                 return Ok(());
             };
             let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
@@ -357,28 +520,31 @@ pub async fn handle_input_line(
                 return Ok(());
             };
 
-            // Check the requested file exists. This should have already been checked, but just incase
-            let Some(requested_file) = file_store.get_file(&trade_request.requested_file) else {
-                eprintln!("The requested file doesn't exist. Something has gone wrong.");
-                return Ok(());
-            };
-
-            // Fetch the metadata
-            let Some(metadata) = file_store.get_metadata(&trade_request.requested_file) else {
-                eprintln!("Failed to get the metadata of the requested file.");
-                return Ok(());
-            };
+            if let Err(e) = utils::accept_trade(swarm, &peerid, trade_request, file_store) {
+                eprintln!("{e}");
+            }
 
-            let response = FileResponse {
-                file: requested_file,
-                metadata: metadata.clone(),
-            };
+            Ok(())
+        }
 
-            swarm
-                .behaviour_mut()
-                .file_transfer
-                .send_request(&peerid, Some(response));
+        "rules" => {
+            chat_state.trade_rules.reload();
+            let rules = chat_state.trade_rules.rules();
+            if rules.is_empty() {
+                println!(
+                    "No trade rules in {}, every trade waits for /trade_accept",
+                    chat_state.trade_rules.path().display()
+                );
+                return Ok(());
+            }
 
+            println!(
+                "Trade rules from {}:",
+                chat_state.trade_rules.path().display()
+            );
+            for rule in rules {
+                println!(" {rule}");
+            }
             Ok(())
         }
 
@@ -393,8 +559,7 @@ pub async fn handle_input_line(
                 eprintln!("Failed to parse nickname");
                 return Ok(());
             };
-            let Some(peer_id_str) = chat_state.nicknames.get_key_from_value(nickname) else {
-                eprintln!("Nickname not found");
+            let Some(peer_id_str) = resolve_peer(nickname, chat_state, stdin).await else {
                 return Ok(());
             };
             let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
@@ -402,11 +567,7 @@ pub async fn handle_input_line(
                 return Ok(());
             };
 
-            if chat_state
-                .incoming_trades
-                .get(&peerid.to_string())
-                .is_none()
-            {
+            if !chat_state.incoming_trades.contains_key(&peer_id_str) {
                 eprintln!("You don't have a trade request from this user");
                 return Ok(());
             };
@@ -424,41 +585,1135 @@ pub async fn handle_input_line(
             Ok(())
         }
 
-        "list_peers" => {
-            let peers: Vec<PeerId> = swarm.connected_peers().cloned().collect();
-            match peers.len() {
-                0 => println!("There are no connected peers."),
-                1 => println!("There is 1 connected peer:"),
-                n => println!("There are {} connected peers:", n),
+        "share" => {
+            if args.len() < 3 {
+                println!("Usage: /share <file_hash> <trade|free|peers> <nicknames...>");
+                return Ok(());
             }
 
-            for peer_id in peers {
-                println!(" - {}", chat_state.nicknames.get(&peer_id.to_string()))
+            let hash = &args[1];
+            let policy = match args[2].to_lowercase().as_str() {
+                "trade" => SharingPolicy::TradeOnly,
+                "free" => SharingPolicy::Free,
+                "peers" if args.len() > 3 => {
+                    let mut peers = HashSet::new();
+                    for nickname in &args[3..] {
+                        let Some(peer_id_str) = resolve_peer(nickname, chat_state, stdin).await
+                        else {
+                            return Ok(());
+                        };
+                        peers.insert(peer_id_str);
+                    }
+                    SharingPolicy::FreeFor(peers)
+                }
+                _ => {
+                    println!("Usage: /share <file_hash> <trade|free|peers> <nicknames...>");
+                    return Ok(());
+                }
+            };
+
+            // Copies of other people's files go by the policy their owner signed
+            let local_peer_id = swarm.local_peer_id().to_string();
+            match file_store.get_metadata(hash) {
+                Some(metadata) if metadata.owner == local_peer_id => {}
+                Some(_) => {
+                    eprintln!("You can only change the sharing policy of your own uploads");
+                    return Ok(());
+                }
+                None => {
+                    eprintln!("You don't have a file with that hash");
+                    return Ok(());
+                }
             }
+            file_store.set_policy(hash, policy);
+
+            // Holders of a copy learn whether it's free from the metadata
+            if let Some(metadata) = file_store.get_metadata(hash) {
+                if let Err(e) = utils::publish_metadata(swarm, &chat_state.keypair, metadata) {
+                    println!("{e}");
+                }
+            }
+            println!("Sharing policy for {hash} updated");
             Ok(())
         }
 
-        default => {
-            println!("Command not recognized: {}", default);
+        "get" => {
+            if args.len() != 2 {
+                println!("Usage: /get <file_hash>");
+                return Ok(());
+            }
+
+            let hash = &args[1];
+            if file_store.contains_file(hash) {
+                println!("You already have this file");
+                return Ok(());
+            }
+
+            // Find out who owns the file first, they're asked for it once the metadata arrives
+            let key = kad::RecordKey::new(&format!("file::{}", hash));
+            let queryid = swarm.behaviour_mut().kademlia.get_record(key);
+            chat_state.get_queries.insert(queryid, hash.clone());
             Ok(())
         }
-    }
-}
 
-/// Splits a command string into its different parts
-/// Double quoted strings gets captured as a whole but without the quotes
-/// i.e. 'NICK "super man"' will return ['NICK', 'super man']
-fn split_string(input: &str) -> Vec<String> {
-    let re = Regex::new(r#""([^"]*)"|\S+"#).unwrap();
-    re.captures_iter(input)
-        // I don't understand this, but the conditions can't be flipped around
-        .filter_map(|cap| {
-            if let Some(matched) = cap.get(1) {
-                Some(matched.as_str().to_string())
-            } else if let Some(matched) = cap.get(0) {
+        "send" => {
+            if args.len() != 3 {
+                println!("Usage: /send <nickname> <file_hash>");
+                return Ok(());
+            }
+
+            // Process nickname
+            let Some(nickname) = args.get(1) else {
+                eprintln!("Failed to parse nickname");
+                return Ok(());
+            };
+            let Some(peer_id_str) = resolve_peer(nickname, chat_state, stdin).await else {
+                return Ok(());
+            };
+            let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
+                eprintln!("Failed to parse retrieved nickname");
+                return Ok(());
+            };
+
+            // Only one gift at a time per person, same as trades
+            if chat_state.outgoing_gifts.contains_key(&peer_id_str) {
+                eprintln!(
+                    "You've already offered {} a gift. Wait for them to accept or decline it.",
+                    nickname
+                );
+                return Ok(());
+            }
+
+            let Some(file) = file_store.get_metadata(&args[2]) else {
+                eprintln!("You don't have a file with that hash");
+                return Ok(());
+            };
+
+            chat_state
+                .outgoing_gifts
+                .insert(peer_id_str, file.hash.clone());
+            swarm.behaviour_mut().gift.send_request(
+                &peerid,
+                GiftMessage::Offer {
+                    file: file.clone(),
+                    nickname: chat_state.nickname.clone(),
+                },
+            );
+            println!(
+                "Offered '{}' to {}, it will be sent once they accept",
+                file.filename, nickname
+            );
+            Ok(())
+        }
+
+        "gift_accept" | "gift_decline" => {
+            if args.len() != 2 {
+                println!("Usage: /{} <nickname>", cmd.to_lowercase());
+                return Ok(());
+            }
+
+            // Process nickname
+            let Some(nickname) = args.get(1) else {
+                eprintln!("Failed to parse nickname");
+                return Ok(());
+            };
+            let Some(peer_id_str) = resolve_peer(nickname, chat_state, stdin).await else {
+                return Ok(());
+            };
+            let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
+                eprintln!("Failed to parse retrieved nickname");
+                return Ok(());
+            };
+
+            let Some(gift) = chat_state.incoming_gifts.remove(&peer_id_str) else {
+                eprintln!("You don't have a gift from this user");
+                return Ok(());
+            };
+
+            if cmd.eq_ignore_ascii_case("gift_accept") {
+                let request_id = swarm.behaviour_mut().free_download.send_request(
+                    &peerid,
+                    FreeFileRequest {
+                        hash: gift.hash.clone(),
+                    },
+                );
+                chat_state.free_requests.insert(request_id, gift.hash);
+                println!("Gift accepted, '{}' is on its way", gift.filename);
+            } else {
+                swarm
+                    .behaviour_mut()
+                    .gift
+                    .send_request(&peerid, GiftMessage::Decline { hash: gift.hash });
+                println!("Gift declined");
+            }
+            Ok(())
+        }
+
+        "want" => {
+            if args.len() < 2 {
+                println!("Usage: /want <search terms or file_hash>");
+                return Ok(());
+            }
+
+            let Some(want) = Want::parse(&args[1..].join(" ")) else {
+                println!("Usage: /want <search terms or file_hash>");
+                return Ok(());
+            };
+            if !chat_state.wanted.add(want.clone()) {
+                println!("{want} is already on your wanted list");
+                return Ok(());
+            }
+
+            println!("Added {want} to your wanted list, you'll be told when it shows up");
+            if chat_state.wanted.publish() {
+                utils::publish_wanted(swarm, chat_state);
+            }
+            Ok(())
+        }
+
+        "wants" => {
+            let wants = chat_state.wanted.wants();
+            if wants.is_empty() {
+                println!("Your wanted list is empty. Add to it with /want");
+                return Ok(());
+            }
+
+            println!(
+                "Your wanted list ({}):",
+                if chat_state.wanted.publish() {
+                    "published"
+                } else {
+                    "private"
+                }
+            );
+            for (position, want) in wants.iter().enumerate() {
+                println!(" {}. {}", position + 1, want);
+            }
+            Ok(())
+        }
+
+        "unwant" => {
+            let Some(position) = args.get(1).and_then(|arg| arg.parse::<usize>().ok()) else {
+                println!("Usage: /unwant <number from /wants>");
+                return Ok(());
+            };
+
+            let Some(want) = chat_state.wanted.remove(position) else {
+                eprintln!("There's no number {position} on your wanted list");
+                return Ok(());
+            };
+            println!("Removed {want} from your wanted list");
+            if chat_state.wanted.publish() {
+                utils::publish_wanted(swarm, chat_state);
+            }
+            Ok(())
+        }
+
+        "want_publish" => {
+            let publish = match args.get(1).map(|arg| arg.to_lowercase()).as_deref() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => {
+                    println!("Usage: /want_publish <on|off>");
+                    return Ok(());
+                }
+            };
+
+            chat_state.wanted.set_publish(publish);
+            utils::publish_wanted(swarm, chat_state);
+            if publish {
+                println!("Your wanted list is now public, others can see what you're after");
+            } else {
+                println!("Your wanted list is now private");
+            }
+            Ok(())
+        }
+
+        "providers" => {
+            if args.len() != 2 {
+                println!("Usage: /providers <file_hash>");
+                return Ok(());
+            }
+
+            let key = kad::RecordKey::new(&format!("file::{}", args[1]));
+            let queryid = swarm.behaviour_mut().kademlia.get_providers(key);
+            chat_state
+                .provider_queries
+                .insert(queryid, (args[1].clone(), HashSet::new()));
+            Ok(())
+        }
+
+        "download" => {
+            if args.len() != 2 {
+                println!("Usage: /download <file_hash>");
+                return Ok(());
+            }
+
+            let hash = &args[1];
+            if file_store.contains_file(hash) {
+                println!("You already have this file");
+                return Ok(());
+            }
+            if chat_state.downloads.contains(hash) {
+                println!("This file is already downloading");
+                return Ok(());
+            }
+
+            chat_state.downloads.start(swarm, hash);
+            println!("Looking for peers sharing {hash}...");
+            Ok(())
+        }
+
+        "list_peers" => {
+            let peers: Vec<PeerId> = swarm.connected_peers().cloned().collect();
+            match peers.len() {
+                0 => println!("There are no connected peers."),
+                1 => println!("There is 1 connected peer:"),
+                n => println!("There are {} connected peers:", n),
+            }
+
+            for peer_id in peers {
+                println!(" - {}", chat_state.nicknames.get(&peer_id.to_string()))
+            }
+            Ok(())
+        }
+
+        "listen" => {
+            let peer_id = *swarm.local_peer_id();
+            let listeners: Vec<Multiaddr> = swarm.listeners().cloned().collect();
+            if listeners.is_empty() {
+                println!("Not listening on any addresses yet");
+            } else {
+                println!("Listening on:");
+                for address in listeners {
+                    println!(" - {}", address.with_p2p(peer_id).unwrap_or_else(|a| a));
+                }
+            }
+
+            let external: Vec<Multiaddr> = swarm.external_addresses().cloned().collect();
+            if !external.is_empty() {
+                println!("Other peers see you at:");
+                for address in external {
+                    println!(" - {}", address.with_p2p(peer_id).unwrap_or_else(|a| a));
+                }
+            }
+            Ok(())
+        }
+
+        "quit" => {
+            chat_state.quit = true;
+            Ok(())
+        }
+
+        "whois" => {
+            if args.len() != 2 {
+                println!("Usage: /whois <nickname>");
+                return Ok(());
+            }
+
+            let Some(peer_id_str) = resolve_peer(&args[1], chat_state, stdin).await else {
+                return Ok(());
+            };
+            let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
+                eprintln!("Failed to parse retrieved nickname");
+                return Ok(());
+            };
+
+            println!("{}", chat_state.nicknames.get(&peer_id_str));
+            println!(" Peer ID: {peerid}");
+            let previous = chat_state.nicknames.history(&peerid);
+            if !previous.is_empty() {
+                println!(" Previously known as: {}", previous.join(", "));
+            }
+            if let Some(contact) = chat_state.nicknames.contacts().get(&peerid) {
+                println!(
+                    " Contact: {}{}",
+                    if contact.friend { "friend" } else { "yes" },
+                    contact
+                        .petname
+                        .as_deref()
+                        .map(|petname| format!(", petname {petname}"))
+                        .unwrap_or_default()
+                );
+                if let Some(nickname) = chat_state.nicknames.current(&peerid) {
+                    println!(" Calls themselves: {nickname}");
+                }
+            }
+            println!(
+                " Nickname: {}",
+                if chat_state.nicknames.is_signed(&peerid) {
+                    "signed by this peer"
+                } else {
+                    "not received yet"
+                }
+            );
+            println!(
+                " Status: {}",
+                if swarm.is_connected(&peerid) {
+                    "connected"
+                } else {
+                    "not connected"
+                }
+            );
+
+            let details = chat_state.peer_details.get(&peerid);
+            match details.and_then(|details| details.info.as_ref()) {
+                Some(info) => {
+                    println!(" Software: {}", info.agent_version);
+                    println!(" Protocol version: {}", info.protocol_version);
+                    println!(" Addresses:");
+                    for address in &info.listen_addrs {
+                        println!("  - {address}");
+                    }
+                    println!(" Protocols:");
+                    for protocol in &info.protocols {
+                        println!("  - {protocol}");
+                    }
+                }
+                None => println!(" They haven't identified themselves yet"),
+            }
+            match details.and_then(|details| details.rtt) {
+                Some(rtt) => println!(" Latency: {} ms", rtt.as_millis()),
+                None => println!(" Latency: unknown"),
+            }
+
+            let files = chat_state.catalog.files_by(&peerid);
+            if files.is_empty() {
+                println!(" Files: none seen yet, try /list_files --owner {}", args[1]);
+            } else {
+                println!(" Files:");
+                for file in files {
+                    println!("  - {} {} ({} bytes)", file.hash, file.filename, file.size);
+                }
+            }
+
+            if let Some(trade) = chat_state.incoming_trades.get(&peer_id_str) {
+                println!(
+                    " Open trade: they're offering '{}' for your {}",
+                    trade.offered_file.filename, trade.requested_file
+                );
+            }
+            if let Some(trade) = chat_state.outgoing_trades.get(&peer_id_str) {
+                println!(
+                    " Open trade: you're offering '{}' for their {}",
+                    trade.offered_file.filename, trade.requested_file
+                );
+            }
+            Ok(())
+        }
+
+        "contacts" => {
+            let contacts = chat_state.nicknames.contacts().all();
+            if contacts.is_empty() {
+                println!("Your contact book is empty, add someone with /petname, /friend or /pin");
+                return Ok(());
+            }
+            println!(
+                "Contacts ({}):",
+                if chat_state.nicknames.contacts().open_dms() {
+                    "they can DM you at any time"
+                } else {
+                    "they can only DM you during a trade"
+                }
+            );
+            for (peer_id, contact) in contacts {
+                let name = contact
+                    .petname
+                    .as_deref()
+                    .or(contact.last_nickname.as_deref())
+                    .unwrap_or("(no name yet)");
+                println!(
+                    " - {name}{}{}",
+                    if contact.friend { " [friend]" } else { "" },
+                    if swarm.is_connected(peer_id) {
+                        " (online)"
+                    } else {
+                        ""
+                    }
+                );
+                println!("\tPeer ID: {peer_id}");
+                if let Some(nickname) = contact.last_nickname.as_deref() {
+                    println!("\tLast seen as: {nickname}");
+                }
+                for address in &contact.addresses {
+                    println!("\tPinned: {address}");
+                }
+            }
+            Ok(())
+        }
+
+        "petname" => {
+            if args.len() != 2 && args.len() != 3 {
+                println!("Usage: /petname <nickname> [petname]");
+                return Ok(());
+            }
+            let Some(peer_id_str) = resolve_peer(&args[1], chat_state, stdin).await else {
+                return Ok(());
+            };
+            let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
+                eprintln!("Failed to parse retrieved nickname");
+                return Ok(());
+            };
+
+            let petname = args.get(2).cloned();
+            match chat_state
+                .nicknames
+                .contacts_mut()
+                .set_petname(peerid, petname.clone())
+            {
+                Ok(()) => match petname {
+                    Some(petname) => println!("{peer_id_str} will be shown as {petname}"),
+                    None => println!("Cleared the petname for {peer_id_str}"),
+                },
+                Err(e) => eprintln!("{e}"),
+            }
+            chat_state.nicknames.refresh_contact(&peerid);
+            Ok(())
+        }
+
+        "friend" | "unfriend" => {
+            if args.len() != 2 {
+                println!("Usage: /{} <nickname>", cmd.to_lowercase());
+                return Ok(());
+            }
+            let Some(peer_id_str) = resolve_peer(&args[1], chat_state, stdin).await else {
+                return Ok(());
+            };
+            let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
+                eprintln!("Failed to parse retrieved nickname");
+                return Ok(());
+            };
+
+            let friend = cmd.eq_ignore_ascii_case("friend");
+            chat_state
+                .nicknames
+                .contacts_mut()
+                .set_friend(peerid, friend);
+            chat_state.nicknames.refresh_contact(&peerid);
+            let name = chat_state.nicknames.get(&peer_id_str);
+            if friend {
+                println!("{name} is now a friend");
+            } else {
+                println!("{name} is no longer a friend");
+            }
+            Ok(())
+        }
+
+        "pin" => {
+            if args.len() != 2 && args.len() != 3 {
+                println!("Usage: /pin <nickname> [address]");
+                return Ok(());
+            }
+            let Some(peer_id_str) = resolve_peer(&args[1], chat_state, stdin).await else {
+                return Ok(());
+            };
+            let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
+                eprintln!("Failed to parse retrieved nickname");
+                return Ok(());
+            };
+
+            // Pin the given address, or the ones the peer told us it listens on
+            let addresses = match args.get(2) {
+                Some(address) => match address.parse::<Multiaddr>() {
+                    Ok(address) => vec![address],
+                    Err(e) => {
+                        eprintln!("Invalid address {address}: {e}");
+                        return Ok(());
+                    }
+                },
+                None => chat_state
+                    .peer_details
+                    .get(&peerid)
+                    .and_then(|details| details.info.as_ref())
+                    .map(|info| info.listen_addrs.clone())
+                    .unwrap_or_default(),
+            };
+            if addresses.is_empty() {
+                eprintln!("No addresses known for {peer_id_str} yet, give one to pin");
+                return Ok(());
+            }
+
+            for address in &addresses {
+                swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(&peerid, address.clone());
+            }
+            let name = chat_state.nicknames.get(&peer_id_str).to_string();
+            let pinned = chat_state.nicknames.contacts_mut().pin(peerid, addresses);
+            chat_state.nicknames.refresh_contact(&peerid);
+            if pinned {
+                println!("Pinned {name}, they'll be dialled whenever you start up");
+            } else {
+                println!("Those addresses were already pinned for {name}");
+            }
+            Ok(())
+        }
+
+        "forget" => {
+            if args.len() != 2 {
+                println!("Usage: /forget <nickname>");
+                return Ok(());
+            }
+            let Some(peer_id_str) = resolve_peer(&args[1], chat_state, stdin).await else {
+                return Ok(());
+            };
+            let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
+                eprintln!("Failed to parse retrieved nickname");
+                return Ok(());
+            };
+
+            match chat_state.nicknames.contacts_mut().remove(&peerid) {
+                Some(_) => println!("Removed {peer_id_str} from your contacts"),
+                None => println!("{peer_id_str} isn't one of your contacts"),
+            }
+            Ok(())
+        }
+
+        "dm_contacts" => {
+            let open_dms = match args.get(1).map(|arg| arg.to_lowercase()).as_deref() {
+                Some("on") => true,
+                Some("off") => false,
+                _ => {
+                    println!("Usage: /dm_contacts <on|off>");
+                    return Ok(());
+                }
+            };
+            chat_state.nicknames.contacts_mut().set_open_dms(open_dms);
+            if open_dms {
+                println!("Your contacts can DM you at any time");
+            } else {
+                println!("Only people you're trading with can DM you");
+            }
+            Ok(())
+        }
+
+        "group" => {
+            if args.len() < 3 {
+                println!("Usage: /group <name> <message>");
+                return Ok(());
+            }
+            send_chat(
+                swarm,
+                current_topic,
+                chat_state,
+                args[2..].join(" "),
+                None,
+                Some(args[1].clone()),
+            )
+        }
+
+        "groups" => {
+            let groups = chat_state.groups.all();
+            if groups.is_empty() {
+                println!("You aren't in any private groups, start one with /group_create");
+            }
+            for group in groups {
+                let creator = if group.is_creator(swarm.local_peer_id()) {
+                    "you".to_string()
+                } else {
+                    chat_state.nicknames.get(&group.creator).to_string()
+                };
+                println!("{} (created by {creator}):", group.name);
+                for member in &group.members {
+                    if *member == swarm.local_peer_id().to_string() {
+                        println!(" - you");
+                    } else {
+                        println!(" - {}", chat_state.nicknames.get(member));
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        "group_create" => {
+            if args.len() != 2 {
+                println!("Usage: /group_create <name>");
+                return Ok(());
+            }
+            let group = GroupKey::generate(args[1].clone(), *swarm.local_peer_id());
+            match utils::join_group(swarm, chat_state, group) {
+                Ok(()) => println!(
+                    "Created the private group '{}'. Invite people with /group_invite {} <nickname>",
+                    args[1], args[1]
+                ),
+                Err(e) => eprintln!("{e}"),
+            }
+            Ok(())
+        }
+
+        "group_invite" => {
+            if args.len() != 3 {
+                println!("Usage: /group_invite <group> <nickname>");
+                return Ok(());
+            }
+            let Some(mut group) = chat_state.groups.get(&args[1]).cloned() else {
+                eprintln!("You aren't in a group called '{}'", args[1]);
+                return Ok(());
+            };
+            if !group.is_creator(swarm.local_peer_id()) {
+                eprintln!(
+                    "Only the person who created '{}' can invite people",
+                    group.name
+                );
+                return Ok(());
+            }
+            let Some(peer_id_str) = resolve_peer(&args[2], chat_state, stdin).await else {
+                return Ok(());
+            };
+            let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
+                eprintln!("Failed to parse retrieved nickname");
+                return Ok(());
+            };
+
+            // Someone new gets a new key, which tells everyone else they've joined. Member lists
+            // only ever change along with the key, so an old list can't overwrite a newer one.
+            let joining = !group.members.contains(&peer_id_str);
+            if joining {
+                group.members.push(peer_id_str.clone());
+                group.epoch += 1;
+                group.key = rand::random();
+            }
+            if let Err(e) = chat_state.groups.insert(group.clone()) {
+                eprintln!("{e}");
+                return Ok(());
+            }
+            if joining {
+                send_rekey(swarm, chat_state, &group, &peerid);
+            }
+            let name = group.name.clone();
+            let invite = DirectMessage {
+                sender_nickname: chat_state.nickname.clone(),
+                message: format!("Invite to '{name}'"),
+                group: Some(GroupControl::Invite(group)),
+            };
+            let nickname = chat_state.nicknames.get(&peer_id_str).to_string();
+            if utils::deliver_dm(swarm, chat_state, peerid, invite) {
+                println!(
+                    "{nickname} is offline, they'll get the invite to '{name}' when they're back"
+                );
+            } else {
+                println!("Invited {nickname} to '{name}'");
+            }
+            Ok(())
+        }
+
+        "group_accept" => {
+            if !(2..=3).contains(&args.len()) {
+                println!("Usage: /group_accept <group> [local_name]");
+                return Ok(());
+            }
+            let Some(invite) = chat_state.groups.invite(&args[1]).cloned() else {
+                eprintln!("No invite to a group called '{}'", args[1]);
+                return Ok(());
+            };
+            // The name is only ours, so a group can be given another if it clashes
+            let name = args.get(2).cloned().unwrap_or_else(|| invite.name.clone());
+            if chat_state
+                .groups
+                .get(&name)
+                .is_some_and(|group| group.id != invite.id)
+            {
+                eprintln!(
+                    "You're already in a group called '{name}', give this one another name with /group_accept {} <local_name>",
+                    args[1]
+                );
+                return Ok(());
+            }
+            let group = GroupKey {
+                name: name.clone(),
+                ..invite
+            };
+            match utils::join_group(swarm, chat_state, group) {
+                Ok(()) => {
+                    chat_state.groups.take_invite(&args[1]);
+                    println!("Joined '{name}'. Talk to the group with /group {name} <message>")
+                }
+                Err(e) => eprintln!("{e}"),
+            }
+            Ok(())
+        }
+
+        "group_decline" => {
+            if args.len() != 2 {
+                println!("Usage: /group_decline <group>");
+                return Ok(());
+            }
+            match chat_state.groups.take_invite(&args[1]) {
+                Some(_) => println!("Declined the invite to '{}'", args[1]),
+                None => eprintln!("No invite to a group called '{}'", args[1]),
+            }
+            Ok(())
+        }
+
+        "group_remove" => {
+            if args.len() != 3 {
+                println!("Usage: /group_remove <group> <nickname>");
+                return Ok(());
+            }
+            let Some(group) = chat_state.groups.get(&args[1]).cloned() else {
+                eprintln!("You aren't in a group called '{}'", args[1]);
+                return Ok(());
+            };
+            let local_peer_id = *swarm.local_peer_id();
+            if !group.is_creator(&local_peer_id) {
+                eprintln!(
+                    "Only the person who created '{}' can remove people",
+                    group.name
+                );
+                return Ok(());
+            }
+            let Some(peer_id_str) = resolve_peer(&args[2], chat_state, stdin).await else {
+                return Ok(());
+            };
+            let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
+                eprintln!("Failed to parse retrieved nickname");
+                return Ok(());
+            };
+            if peerid == local_peer_id || !group.members.contains(&peer_id_str) {
+                eprintln!("They aren't a member of '{}'", group.name);
+                return Ok(());
+            }
+
+            // A new key, so whoever was removed can't read anything from now on
+            let rotated = GroupKey {
+                epoch: group.epoch + 1,
+                key: rand::random(),
+                members: group
+                    .members
+                    .iter()
+                    .filter(|member| **member != peer_id_str)
+                    .cloned()
+                    .collect(),
+                ..group
+            };
+            if let Err(e) = utils::join_group(swarm, chat_state, rotated.clone()) {
+                eprintln!("{e}");
+                return Ok(());
+            }
+
+            let removed = DirectMessage {
+                sender_nickname: chat_state.nickname.clone(),
+                message: format!("Removed from '{}'", rotated.name),
+                group: Some(GroupControl::Removed {
+                    id: rotated.id.clone(),
+                }),
+            };
+            utils::deliver_dm(swarm, chat_state, peerid, removed);
+            send_rekey(swarm, chat_state, &rotated, &peerid);
+            println!(
+                "Removed {} from '{}' and sent everyone else a new key",
+                chat_state.nicknames.get(&peer_id_str),
+                rotated.name
+            );
+            Ok(())
+        }
+
+        "group_leave" => {
+            if args.len() != 2 {
+                println!("Usage: /group_leave <group>");
+                return Ok(());
+            }
+            match utils::leave_group(swarm, chat_state, &args[1]) {
+                Some(group) if group.is_creator(swarm.local_peer_id()) => println!(
+                    "Left '{}'. You created it, so nobody can be added or removed any more",
+                    group.name
+                ),
+                Some(group) => println!("Left '{}'", group.name),
+                None => eprintln!("You aren't in a group called '{}'", args[1]),
+            }
+            Ok(())
+        }
+
+        "block" => {
+            // On its own, show who's blocked
+            if args.len() == 1 {
+                let blocked = chat_state.blocked.all();
+                if blocked.is_empty() {
+                    println!("You haven't blocked anyone");
+                }
+                for peer_id in blocked {
+                    let peer_id_str = peer_id.to_string();
+                    match chat_state.nicknames.get(&peer_id_str) {
+                        name if name == peer_id_str => println!(" - {peer_id}"),
+                        name => println!(" - {name} ({peer_id})"),
+                    }
+                }
+                return Ok(());
+            }
+            if args.len() != 2 {
+                println!("Usage: /block <nickname>");
+                return Ok(());
+            }
+            let Some(peer_id_str) = resolve_peer(&args[1], chat_state, stdin).await else {
+                return Ok(());
+            };
+            let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
+                eprintln!("Failed to parse retrieved nickname");
+                return Ok(());
+            };
+
+            let name = chat_state.nicknames.get(&peer_id_str).to_string();
+            if !chat_state.blocked.block(peerid) {
+                println!("{name} is already blocked");
+                return Ok(());
+            }
+            // Closes any connections we have to them, and refuses new ones
+            swarm.behaviour_mut().blocked.block_peer(peerid);
+            chat_state.incoming_trades.remove(&peer_id_str);
+            chat_state.incoming_gifts.remove(&peer_id_str);
+            println!("Blocked {name}, you won't see their messages or requests any more");
+            if let Some(trade) = chat_state.outgoing_trades.remove(&peer_id_str) {
+                println!(
+                    "Your offer of '{}' to {name} has been cancelled",
+                    trade.offered_file.filename
+                );
+            }
+            if let Some(hash) = chat_state.outgoing_gifts.remove(&peer_id_str) {
+                println!("Your gift of {hash} to {name} has been withdrawn");
+            }
+            match chat_state.drop_undelivered_dms(&peerid) {
+                0 => {}
+                1 => println!("Dropped 1 undelivered DM to {name}"),
+                dropped => println!("Dropped {dropped} undelivered DMs to {name}"),
+            }
+            Ok(())
+        }
+
+        "unblock" => {
+            if args.len() != 2 {
+                println!("Usage: /unblock <nickname>");
+                return Ok(());
+            }
+            let Some(peer_id_str) = resolve_peer(&args[1], chat_state, stdin).await else {
+                return Ok(());
+            };
+            let Ok(peerid) = PeerId::from_str(&peer_id_str) else {
+                eprintln!("Failed to parse retrieved nickname");
+                return Ok(());
+            };
+
+            let name = chat_state.nicknames.get(&peer_id_str).to_string();
+            if chat_state.blocked.unblock(&peerid) {
+                swarm.behaviour_mut().blocked.unblock_peer(peerid);
+                println!("Unblocked {name}");
+            } else {
+                println!("{name} isn't blocked");
+            }
+            Ok(())
+        }
+
+        default => {
+            println!("Command not recognized: {}", default);
+            Ok(())
+        }
+    }
+}
+
+/// Send a message to the chat, or encrypted to one of our private groups, resolving any @mentions
+/// to the peers they refer to
+fn send_chat(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    topic: IdentTopic,
+    chat_state: &mut ChatState,
+    text: String,
+    reply_to: Option<MessageRef>,
+    group: Option<String>,
+) -> Result<(), Box<dyn Error>> {
+    // Names that could be more than one peer are left for readers to work out
+    let mentions = chat::parse_mentions(&text)
+        .iter()
+        .filter_map(|name| match chat_state.nicknames.lookup(name).as_slice() {
+            [peer_id] => Some(peer_id.clone()),
+            _ => None,
+        })
+        .collect();
+    let message = ChatMessage {
+        message: text,
+        nickname: chat_state.nickname.clone(),
+        id: chat::new_message_id(),
+        timestamp: chat::now(),
+        reply_to,
+        mentions,
+    };
+
+    let mut message_bytes = serde_cbor::to_vec(&message)?;
+
+    // Peers drop anything over the chat limits, so it isn't worth sending
+    if let Err(e) = validation::check_chat(&message_bytes) {
+        println!("Message not sent: {e}");
+        return Ok(());
+    }
+
+    // Group messages go to the group's own topic, and only members can read them
+    let topic = match &group {
+        Some(name) => {
+            let Some(key) = chat_state.groups.get(name) else {
+                eprintln!("You aren't in a group called '{name}'");
+                return Ok(());
+            };
+            message_bytes = serde_cbor::to_vec(&key.encrypt(&message_bytes)?)?;
+            key.topic()
+        }
+        None => topic,
+    };
+
+    let local_peer_id = *swarm.local_peer_id();
+    let topic_hash = topic.hash();
+    if let Err(e) =
+        chat_state
+            .gossip_validator
+            .check_sending(local_peer_id, &topic_hash, &message_bytes)
+    {
+        println!("Message not sent: {e}");
+        return Ok(());
+    }
+    if let Err(e) = chat_state
+        .gossip_validator
+        .check_sending_rate(local_peer_id, &topic_hash)
+    {
+        println!("Message not sent, you're sending too quickly: {e}");
+        return Ok(());
+    }
+
+    if let Err(e) = swarm
+        .behaviour_mut()
+        .chat
+        .gossipsub
+        .publish(topic, message_bytes)
+    {
+        eprintln!("Failed to send message: {}", e);
+        return Ok(());
+    }
+
+    // Keep our own messages too, so replies to them can be shown in context
+    chat_state
+        .chat_log
+        .push(*swarm.local_peer_id(), message, group);
+    Ok(())
+}
+
+/// Send a group's new key to its members, apart from us and whoever caused the change
+fn send_rekey(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &mut ChatState,
+    group: &GroupKey,
+    skip: &PeerId,
+) {
+    let local_peer_id = *swarm.local_peer_id();
+    for member in &group.members {
+        let Ok(member) = member.parse::<PeerId>() else {
+            continue;
+        };
+        if member == local_peer_id || member == *skip {
+            continue;
+        }
+        let rekey = DirectMessage {
+            sender_nickname: chat_state.nickname.clone(),
+            message: format!("New key for '{}'", group.name),
+            group: Some(GroupControl::Rekey(group.clone())),
+        };
+        utils::deliver_dm(swarm, chat_state, member, rekey);
+    }
+}
+
+/// Find the message an ID refers to, returning its author and group. Anyone can reuse an ID, so
+/// if more than one author has the user is asked which message they meant.
+async fn resolve_message(
+    id: &str,
+    chat_state: &ChatState,
+    stdin: &mut Lines<BufReader<Stdin>>,
+) -> Option<(PeerId, Option<String>)> {
+    let matches = chat_state.chat_log.with_id(id);
+    match matches.as_slice() {
+        [] => {
+            eprintln!("No message with the ID {id}");
+            return None;
+        }
+        [logged] => return Some((logged.author, logged.group.clone())),
+        _ => {}
+    }
+
+    println!("More than one message has the ID {id}:");
+    for (i, logged) in matches.iter().enumerate() {
+        println!(
+            " {}. {}: {}",
+            i + 1,
+            chat_state.nicknames.get(&logged.author.to_string()),
+            logged.message.message
+        );
+    }
+    println!("Which one did you mean? (number, or blank to cancel)");
+
+    let choice = stdin.next_line().await.ok().flatten().unwrap_or_default();
+    let chosen = choice
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .and_then(|choice| matches.get(choice.checked_sub(1)?))
+        .map(|logged| (logged.author, logged.group.clone()));
+    if chosen.is_none() {
+        println!("Cancelled");
+    }
+    chosen
+}
+
+/// Work out which peer a name refers to, asking the user to pick one if it could be several
+async fn resolve_peer(
+    name: &str,
+    chat_state: &ChatState,
+    stdin: &mut Lines<BufReader<Stdin>>,
+) -> Option<String> {
+    let mut matches = chat_state.nicknames.lookup(name);
+    match matches.len() {
+        0 => {
+            eprintln!("Nickname not found: {name}");
+            return None;
+        }
+        1 => return matches.pop(),
+        _ => {}
+    }
+
+    println!("'{name}' could be more than one peer:");
+    for (i, peer_id_str) in matches.iter().enumerate() {
+        let contact = peer_id_str
+            .parse::<PeerId>()
+            .ok()
+            .and_then(|peer| chat_state.nicknames.contacts().get(&peer));
+        let note = match contact {
+            Some(contact) if contact.friend => " (friend)",
+            Some(_) => " (contact)",
+            None => "",
+        };
+        println!(" {}. {peer_id_str}{note}", i + 1);
+    }
+    println!("Which one did you mean? (number, or blank to cancel)");
+
+    let choice = stdin.next_line().await.ok().flatten().unwrap_or_default();
+    let chosen = choice
+        .trim()
+        .parse::<usize>()
+        .ok()
+        .filter(|choice| (1..=matches.len()).contains(choice))
+        .map(|choice| matches.swap_remove(choice - 1));
+    if chosen.is_none() {
+        println!("Cancelled");
+    }
+    chosen
+}
+
+/// Splits a command string into its different parts
+/// Double quoted strings gets captured as a whole but without the quotes
+/// i.e. 'NICK "super man"' will return ['NICK', 'super man']
+fn split_string(input: &str) -> Vec<String> {
+    let re = Regex::new(r#""([^"]*)"|\S+"#).unwrap();
+    re.captures_iter(input)
+        // I don't understand this, but the conditions can't be flipped around
+        .filter_map(|cap| {
+            if let Some(matched) = cap.get(1) {
                 Some(matched.as_str().to_string())
             } else {
-                None
+                cap.get(0).map(|matched| matched.as_str().to_string())
             }
         })
         .collect()
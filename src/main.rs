@@ -1,17 +1,37 @@
+mod catalog;
+mod chat;
+mod contacts;
+mod downloads;
 mod events;
 mod files;
+mod groups;
 mod input;
+mod records;
+mod rules;
+mod storage;
 mod utils;
+mod validation;
+mod wanted;
 
+use clap::Parser;
 use files::LocalFileStore;
 use futures::StreamExt;
-use libp2p::{kad::Mode, noise, rendezvous, tcp, yamux, Multiaddr};
-use std::{error::Error, time::Duration};
+use libp2p::{
+    core::{transport::Transport, upgrade},
+    identity::Keypair,
+    kad::{self, store::RecordStore, Mode},
+    multiaddr::Protocol,
+    noise,
+    pnet::{PnetConfig, PreSharedKey},
+    rendezvous,
+    swarm::{dial_opts::DialOpts, DialError},
+    tcp, yamux, Multiaddr, PeerId, Swarm,
+};
+use std::{error::Error, net::IpAddr, path::Path, path::PathBuf, time::Duration};
 use tokio::io::{self, AsyncBufReadExt};
 use tokio::time::MissedTickBehavior;
-use clap::Parser;
 
-use crate::events::get_swapbytes_behaviour;
+use crate::events::{get_swapbytes_behaviour, SwapBytesBehaviour};
 use crate::utils::ChatState;
 
 #[derive(Parser, Debug)]
@@ -20,64 +40,283 @@ struct Cli {
     #[arg(long)]
     port: Option<String>,
 
+    /// Address to listen on, e.g. /ip6/::/udp/9999/quic-v1. Can be given more than once.
+    /// Defaults to TCP and QUIC on every IPv4 and IPv6 interface, using --port.
+    #[arg(long = "listen")]
+    listen: Vec<Multiaddr>,
+
+    /// A peer to join the DHT through, as a multiaddr ending in /p2p/<peer_id>.
+    /// Can be given more than once.
+    #[arg(long = "bootstrap")]
+    bootstrap: Vec<Multiaddr>,
+
+    /// Rendezvous server, as an IPv4/IPv6 address on the default port or a full multiaddr
     #[arg(long)]
     rendezvous: Option<String>,
+
+    /// Where the identity, DHT records and address book are kept between sessions.
+    /// Defaults to a swapbytes directory in the user's data directory.
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
+    /// Keep files received in trades and share them with the network too
+    #[arg(long)]
+    reshare: bool,
+
+    /// File of rules for accepting trades automatically (defaults to rules.txt in the data directory)
+    #[arg(long)]
+    rules: Option<PathBuf>,
+
+    /// Swarm key file for a private network, only peers with the same key can connect.
+    /// QUIC is turned off, as the key can only protect TCP connections.
+    #[arg(long)]
+    psk: Option<PathBuf>,
+
+    /// Write a new random swarm key to this file for use with --psk, then exit
+    #[arg(long)]
+    gen_psk: Option<PathBuf>,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
+
+    if let Some(path) = &cli.gen_psk {
+        let psk = storage::generate_psk(path)?;
+        println!(
+            "Wrote a new swarm key to {} (fingerprint {})",
+            path.display(),
+            psk.fingerprint()
+        );
+        println!("Give the file to everyone who should be able to join, and start with --psk");
+        return Ok(());
+    }
+
+    let data_dir = cli
+        .data_dir
+        .clone()
+        .unwrap_or_else(storage::default_data_dir);
+    // Held until we exit, so nobody else can start with our identity
+    let _data_dir_lock = storage::lock_data_dir(&data_dir)?;
+    let keypair = storage::load_or_create_identity(&data_dir)?;
+    let psk = cli.psk.as_deref().map(storage::load_psk).transpose()?;
+
     // Initialize swarm
-    let mut swarm = libp2p::SwarmBuilder::with_new_identity()
-        .with_tokio()
-        .with_tcp(
-            tcp::Config::default(),
-            noise::Config::new,
-            yamux::Config::default,
-        )?
-        .with_quic()
-        .with_behaviour(|key| {
-            get_swapbytes_behaviour(key).expect("Failed to build SwapBytesBehaviour")
-        })?
-        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
-        .build();
+    let mut swarm = match psk {
+        Some(psk) => {
+            println!(
+                "Private network, swarm key fingerprint {}",
+                psk.fingerprint()
+            );
+            build_private_swarm(keypair.clone(), psk, &data_dir)?
+        }
+        None => libp2p::SwarmBuilder::with_existing_identity(keypair.clone())
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default(),
+                noise::Config::new,
+                yamux::Config::default,
+            )?
+            .with_quic()
+            .with_behaviour(|key| {
+                get_swapbytes_behaviour(key, &data_dir).expect("Failed to build SwapBytesBehaviour")
+            })?
+            .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build(),
+    };
 
     // Create an input for the user and ask them for their nickname
     let mut stdin = io::BufReader::new(io::stdin()).lines();
     let nickname = utils::prompt_for_nickname(&mut stdin, &mut swarm).await;
 
     // Initialize local state trackers
-    let mut chat_state = ChatState::new(nickname);
+    let rules_path = cli
+        .rules
+        .clone()
+        .unwrap_or_else(|| data_dir.join("rules.txt"));
+    let mut chat_state =
+        ChatState::new(nickname, data_dir.clone(), keypair, cli.reshare, rules_path);
     let mut file_store = LocalFileStore::new();
 
+    // Keep out everyone blocked in a previous session
+    for peer_id in chat_state.blocked.all() {
+        swarm.behaviour_mut().blocked.block_peer(peer_id);
+    }
+
     // Setup GossipSub
     swarm
         .behaviour_mut()
         .chat
         .gossipsub
         .subscribe(&chat_state.current_topic.clone())?;
+    swarm
+        .behaviour_mut()
+        .chat
+        .gossipsub
+        .subscribe(&chat_state.announcement_topic.clone())?;
+    swarm
+        .behaviour_mut()
+        .chat
+        .gossipsub
+        .subscribe(&chat_state.nickname_topic.clone())?;
+    for group in chat_state.groups.all() {
+        let gossipsub = &mut swarm.behaviour_mut().chat.gossipsub;
+        gossipsub.subscribe(&group.topic())?;
+        let _ = gossipsub.set_topic_params(group.topic(), validation::topic_score_params());
+    }
     swarm.behaviour_mut().kademlia.set_mode(Some(Mode::Server));
 
     // Rendezvous server schenanigans
-    let rendezvous_addr = cli.rendezvous.unwrap_or("127.0.0.1".to_string());
-    let rendezvous_point_address = format!("/ip4/{}/tcp/62649", rendezvous_addr)
-        .parse::<Multiaddr>()
-        .unwrap();
+    let rendezvous_point_address =
+        rendezvous_address(cli.rendezvous.as_deref().unwrap_or("127.0.0.1"))?;
+
+    // Registering needs an external address, use the rendezvous server's network until
+    // identify tells us how peers actually see us
+    if let Some(host) = rendezvous_point_address.iter().next() {
+        swarm.add_external_address(Multiaddr::empty().with(host).with(Protocol::Tcp(0)));
+    }
+    // An address none of our transports can dial (QUIC with --psk for example) only means
+    // there's no discovery through the rendezvous server, mDNS and the DHT still work
+    let rendezvous_dialable = match swarm.dial(rendezvous_point_address.clone()) {
+        Ok(()) => true,
+        Err(e) => {
+            eprintln!(
+                "Can't dial the rendezvous server at {rendezvous_point_address} ({e}), carrying on without it"
+            );
+            false
+        }
+    };
+
+    let listen_addresses = if cli.listen.is_empty() {
+        let listen_port = cli.port.unwrap_or("0".to_string());
+        let mut defaults = vec![
+            format!("/ip4/0.0.0.0/tcp/{listen_port}"),
+            format!("/ip6/::/tcp/{listen_port}"),
+        ];
+        if psk.is_none() {
+            defaults.push(format!("/ip4/0.0.0.0/udp/{listen_port}/quic-v1"));
+            defaults.push(format!("/ip6/::/udp/{listen_port}/quic-v1"));
+        }
+        defaults
+            .iter()
+            .map(|address| address.parse())
+            .collect::<Result<Vec<Multiaddr>, _>>()?
+    } else {
+        cli.listen
+    };
+
+    // Some addresses may not work on this machine (no IPv6 for example), that's fine as long as
+    // at least one does
+    let mut listening = false;
+    for address in listen_addresses {
+        match swarm.listen_on(address.clone()) {
+            Ok(_) => listening = true,
+            Err(e) => eprintln!("Failed to listen on {address}: {e}"),
+        }
+    }
+    if !listening {
+        return Err("Couldn't listen on any address".into());
+    }
 
-    let external_address = format!("/ip4/{}/tcp/0", rendezvous_addr)
-        .parse::<Multiaddr>()
-        .unwrap();
-    swarm.add_external_address(external_address);
-    swarm.dial(rendezvous_point_address.clone()).unwrap();
+    // Files aren't kept between sessions, so stop claiming to hold the ones we had last time
+    let stale_keys: Vec<kad::RecordKey> = swarm
+        .behaviour_mut()
+        .kademlia
+        .store_mut()
+        .provided()
+        .map(|record| record.key.clone())
+        .collect();
+    for key in stale_keys {
+        swarm.behaviour_mut().kademlia.stop_providing(&key);
+    }
 
-    let listen_port = cli.port.unwrap_or("0".to_string());
-    let multiaddr = format!("/ip4/0.0.0.0/tcp/{listen_port}");
-    swarm.listen_on(multiaddr.parse()?)?;
+    // Rejoin the peers we knew about last session, so stored records can be served straight away
+    let known_peers = storage::load_address_book(&data_dir);
+    for (peer_id, addresses) in &known_peers {
+        for address in addresses {
+            swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(peer_id, address.clone());
+        }
+    }
+
+    // Peers given on the command line are how we join the DHT when there's no LAN discovery
+    for address in &cli.bootstrap {
+        let Some(Protocol::P2p(peer_id)) = address.iter().last() else {
+            eprintln!("Bootstrap address {address} needs to end with /p2p/<peer_id>");
+            continue;
+        };
+        swarm
+            .behaviour_mut()
+            .kademlia
+            .add_address(&peer_id, address.clone());
+        if let Err(e) = swarm.dial(address.clone()) {
+            eprintln!("Failed to dial bootstrap peer {address}: {e}");
+        }
+    }
+
+    // Reconnect to our contacts, on their pinned addresses as well as any the DHT knows about
+    let contacts: Vec<(PeerId, Vec<Multiaddr>)> = chat_state
+        .nicknames
+        .contacts()
+        .all()
+        .into_iter()
+        .filter(|(peer_id, _)| !chat_state.blocked.contains(peer_id))
+        .map(|(peer_id, contact)| (*peer_id, contact.addresses.clone()))
+        .collect();
+    for (peer_id, addresses) in contacts {
+        for address in &addresses {
+            swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(&peer_id, address.clone());
+        }
+        // Contacts we've nowhere to look for yet are found later through discovery
+        let dial = DialOpts::peer_id(peer_id).addresses(addresses).build();
+        match swarm.dial(dial) {
+            Ok(()) | Err(DialError::NoAddresses) => {}
+            Err(e) => eprintln!("Failed to dial contact {peer_id}: {e}"),
+        }
+    }
+
+    // Try to reach anyone with DMs left over from last session, they're sent once connected
+    let outbox: Vec<PeerId> = chat_state
+        .undelivered_peers()
+        .into_iter()
+        .filter(|peer_id| !chat_state.blocked.contains(peer_id))
+        .collect();
+    if !outbox.is_empty() {
+        println!(
+            "You have undelivered DMs for {} peer{}, they'll be sent when they're online",
+            outbox.len(),
+            if outbox.len() == 1 { "" } else { "s" }
+        );
+    }
+    for peer_id in outbox {
+        let _ = swarm.dial(peer_id);
+    }
+
+    // Refresh the routing table every 5 minutes, starting straight away
+    let mut bootstrap_tick = tokio::time::interval(Duration::from_secs(5 * 60));
+    bootstrap_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
     // Discovery ping goes off every 30 seconds
     let mut discover_tick = tokio::time::interval(Duration::from_secs(30));
     discover_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
+    // DHT changes are saved in batches rather than as they happen
+    let mut save_tick = tokio::time::interval(Duration::from_secs(30));
+    save_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    // Checks whether a /list_files has waited long enough for slow peers
+    let mut catalog_tick = tokio::time::interval(Duration::from_secs(1));
+    catalog_tick.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    // Created once, so a Ctrl-C that arrives while another branch is running isn't lost
+    let ctrl_c = tokio::signal::ctrl_c();
+    tokio::pin!(ctrl_c);
+
     // Main loop
     loop {
         tokio::select! {
@@ -95,16 +334,87 @@ async fn main() -> Result<(), Box<dyn Error>> {
             // Catch events and handle them
             event = swarm.select_next_some() => events::handle_event(&mut swarm, event, &mut chat_state, &mut file_store).await,
 
-            // If discovery tick, try to discover new peers
-            _ = discover_tick.tick() => {
-                swarm.dial(rendezvous_point_address.clone()).unwrap();
-                swarm.behaviour_mut().rendezvous.rendezvous.discover(
-                    Some(rendezvous::Namespace::new("rendezvous".to_string()).unwrap()),
-                    None,
-                    None,
-                    chat_state.rendezvous
-                )
+            // Nobody to bootstrap from yet is fine, mDNS or rendezvous may find someone later
+            _ = bootstrap_tick.tick() => {
+                let _ = swarm.behaviour_mut().kademlia.bootstrap();
             }
+
+            _ = save_tick.tick() => storage::save_dht(&mut swarm, &mut chat_state),
+
+            _ = catalog_tick.tick() => chat_state.catalog.poll_listing(&swarm, &chat_state.nicknames),
+
+            // Ctrl-C leaves the same way /quit does
+            _ = &mut ctrl_c => chat_state.quit = true,
+
+            // If discovery tick, try to discover new peers
+            _ = discover_tick.tick(), if rendezvous_dialable
+                => discover_peers(&mut swarm, &chat_state, &rendezvous_point_address),
+        }
+
+        if chat_state.quit {
+            break;
+        }
+    }
+
+    utils::shutdown(&mut swarm, &mut chat_state).await;
+    // Stdin is read on a blocking thread that can't be cancelled, so exit rather than have the
+    // runtime wait for the user to press enter
+    std::process::exit(0)
+}
+
+/// Ask the rendezvous server who else is registered. If we've lost the connection to it, dial it
+/// again instead, discovery starts as soon as the connection is made.
+fn discover_peers(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &ChatState,
+    address: &Multiaddr,
+) {
+    if !swarm.is_connected(&chat_state.rendezvous) {
+        if let Err(e) = swarm.dial(address.clone()) {
+            eprintln!("Couldn't dial the rendezvous server at {address}: {e}");
         }
+        return;
     }
+    swarm.behaviour_mut().rendezvous.rendezvous.discover(
+        Some(rendezvous::Namespace::new("rendezvous".to_string()).unwrap()),
+        None,
+        None,
+        chat_state.rendezvous,
+    );
+}
+
+/// The rendezvous server can be given as an IP address, which uses the default port, or as a
+/// full multiaddr
+fn rendezvous_address(rendezvous: &str) -> Result<Multiaddr, Box<dyn Error>> {
+    if let Ok(ip) = rendezvous.parse::<IpAddr>() {
+        return Ok(Multiaddr::from(ip).with(Protocol::Tcp(62649)));
+    }
+    Ok(rendezvous.parse()?)
+}
+
+/// A swarm that only talks TCP, with every connection wrapped in the swarm key before noise.
+/// Peers without the same key fail the handshake, so they can't join even if mDNS finds them.
+fn build_private_swarm(
+    keypair: Keypair,
+    psk: PreSharedKey,
+    data_dir: &Path,
+) -> Result<Swarm<SwapBytesBehaviour>, Box<dyn Error>> {
+    Ok(libp2p::SwarmBuilder::with_existing_identity(keypair)
+        .with_tokio()
+        .with_other_transport(|key| {
+            let noise = noise::Config::new(key)?;
+            Ok::<_, Box<dyn Error + Send + Sync>>(
+                tcp::tokio::Transport::new(tcp::Config::default())
+                    .and_then(move |socket, _| PnetConfig::new(psk).handshake(socket))
+                    .upgrade(upgrade::Version::V1Lazy)
+                    .authenticate(noise)
+                    .multiplex(yamux::Config::default())
+                    .timeout(Duration::from_secs(20)),
+            )
+        })?
+        .with_behaviour(|key| {
+            get_swapbytes_behaviour(key, data_dir).expect("Failed to build SwapBytesBehaviour")
+        })?
+        .with_swarm_config(|cfg| cfg.with_idle_connection_timeout(Duration::from_secs(60)))
+        .build())
 }
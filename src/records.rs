@@ -0,0 +1,276 @@
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    kad, PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+
+use crate::files::FileMetadata;
+use crate::wanted::Want;
+
+/// A serialized value together with the key that signed it.
+/// The signature covers the context (usually the DHT key) as well as the payload,
+/// so a signed value can't be replayed under a different key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SignedRecord {
+    pub payload: Vec<u8>,
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl SignedRecord {
+    /// Serialize and sign a value with our keypair
+    pub fn sign<T: Serialize>(
+        keypair: &Keypair,
+        context: &str,
+        value: &T,
+    ) -> Result<Self, Box<dyn Error>> {
+        let payload = serde_cbor::to_vec(value)?;
+        let signature = keypair.sign(&signing_bytes(context, &payload))?;
+        Ok(SignedRecord {
+            payload,
+            public_key: keypair.public().encode_protobuf(),
+            signature,
+        })
+    }
+
+    /// Check the signature, returning the PeerId of whoever signed it
+    pub fn verify(&self, context: &str) -> Result<PeerId, String> {
+        let public_key = PublicKey::try_decode_protobuf(&self.public_key)
+            .map_err(|e| format!("bad public key: {e}"))?;
+        if !public_key.verify(&signing_bytes(context, &self.payload), &self.signature) {
+            return Err("signature does not match".to_string());
+        }
+        Ok(public_key.to_peer_id())
+    }
+
+    /// Verify the signature and decode the payload, returning the signer along with the value
+    pub fn open<T: for<'de> Deserialize<'de>>(&self, context: &str) -> Result<(PeerId, T), String> {
+        let signer = self.verify(context)?;
+        let value = serde_cbor::from_slice::<T>(&self.payload)
+            .map_err(|e| format!("malformed payload: {e}"))?;
+        Ok((signer, value))
+    }
+}
+
+/// A list a peer publishes under its own key, its file index or wanted list. The sequence goes up
+/// every time the list is republished, so an old copy can't be stored over a newer one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerList<T> {
+    pub sequence: u64,
+    pub entries: Vec<T>,
+}
+
+/// Sign a value and wrap it in a DHT record under the given key
+pub fn signed_record<T: Serialize>(
+    keypair: &Keypair,
+    key: &str,
+    value: &T,
+) -> Result<kad::Record, Box<dyn Error>> {
+    let signed = SignedRecord::sign(keypair, key, value)?;
+    Ok(kad::Record {
+        key: kad::RecordKey::new(&key),
+        value: serde_cbor::to_vec(&signed)?,
+        publisher: Some(keypair.public().to_peer_id()),
+        expires: None,
+    })
+}
+
+/// Decode a signed value, verify it, and return the signer along with the value
+pub fn open<T: for<'de> Deserialize<'de>>(
+    context: &str,
+    bytes: &[u8],
+) -> Result<(PeerId, T), String> {
+    let signed = serde_cbor::from_slice::<SignedRecord>(bytes)
+        .map_err(|e| format!("not a signed record: {e}"))?;
+    signed.open(context)
+}
+
+/// Open signed file metadata stored under `file::<hash>`, checking it's for that hash and was
+/// signed by the file's owner
+pub fn open_file_metadata(key: &str, bytes: &[u8]) -> Result<(PeerId, FileMetadata), String> {
+    let (signer, metadata) = open::<FileMetadata>(key, bytes)?;
+    if metadata.hash != key["file::".len()..] {
+        return Err(format!("metadata for {} stored under {key}", metadata.hash));
+    }
+    if metadata.owner != signer.to_string() {
+        return Err(format!("metadata for {key} not signed by its owner"));
+    }
+    Ok((signer, metadata))
+}
+
+/// Check a DHT record is correctly signed, and that the signer is allowed to write that key.
+///  - `file::<hash>` must hold metadata for that hash, signed by the file's owner
+///  - `file_index::<peer>` and `wanted::<peer>` must be signed by that peer, and can't be older
+///    than the copy we already have stored
+pub fn validate_record(record: &kad::Record, stored: Option<&kad::Record>) -> Result<(), String> {
+    let key = std::str::from_utf8(record.key.as_ref()).map_err(|_| "key is not utf-8")?;
+
+    let signer = match key {
+        key if key.starts_with("file::") => open_file_metadata(key, &record.value)?.0,
+
+        key if key.starts_with("file_index::") => {
+            open_peer_list::<String>(key, "file_index::", record, stored)?
+        }
+
+        key if key.starts_with("wanted::") => {
+            open_peer_list::<Want>(key, "wanted::", record, stored)?
+        }
+
+        _ => return Err(format!("unknown record type {key}")),
+    };
+
+    // Replicated records keep their original publisher, which should be the signer
+    match record.publisher {
+        Some(publisher) if publisher != signer => Err(format!(
+            "{key} published by {publisher} but signed by {signer}"
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Open a peer's list, checking it was signed by the peer named in its key and isn't older than
+/// the one stored. Returns the signer.
+fn open_peer_list<T: for<'de> Deserialize<'de>>(
+    key: &str,
+    prefix: &str,
+    record: &kad::Record,
+    stored: Option<&kad::Record>,
+) -> Result<PeerId, String> {
+    let (signer, list) = open::<PeerList<T>>(key, &record.value)?;
+    if key[prefix.len()..] != signer.to_string() {
+        return Err(format!("{key} written by {signer}"));
+    }
+
+    // Whatever's stored was checked on the way in, an unreadable copy is simply replaced
+    let stored_sequence = stored
+        .and_then(|stored| open::<PeerList<T>>(key, &stored.value).ok())
+        .map(|(_, stored)| stored.sequence);
+    if let Some(stored_sequence) = stored_sequence.filter(|&stored| list.sequence < stored) {
+        return Err(format!(
+            "{key} is version {} but we already have version {stored_sequence}",
+            list.sequence
+        ));
+    }
+    Ok(signer)
+}
+
+fn signing_bytes(context: &str, payload: &[u8]) -> Vec<u8> {
+    let mut bytes = context.as_bytes().to_vec();
+    bytes.push(0);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::LocalFileStore;
+
+    fn file_record(keypair: &Keypair) -> kad::Record {
+        let mut store = LocalFileStore::new();
+        let owner = keypair.public().to_peer_id();
+        let hash = store.add_file(b"some bytes".to_vec(), "notes.txt", &owner, None);
+        let metadata = store.get_metadata(&hash).unwrap();
+        signed_record(keypair, &format!("file::{hash}"), metadata).unwrap()
+    }
+
+    fn list<T>(sequence: u64, entry: T) -> PeerList<T> {
+        PeerList {
+            sequence,
+            entries: vec![entry],
+        }
+    }
+
+    #[test]
+    fn accepts_a_valid_record() {
+        let keypair = Keypair::generate_ed25519();
+        assert_eq!(validate_record(&file_record(&keypair), None), Ok(()));
+
+        let key = format!("file_index::{}", keypair.public().to_peer_id());
+        let index = signed_record(&keypair, &key, &list(1, "abcd1234".to_string())).unwrap();
+        assert_eq!(validate_record(&index, None), Ok(()));
+    }
+
+    #[test]
+    fn rejects_a_bad_signature() {
+        let keypair = Keypair::generate_ed25519();
+        let mut record = file_record(&keypair);
+        let mut signed: SignedRecord = serde_cbor::from_slice(&record.value).unwrap();
+        signed.signature[0] ^= 1;
+        record.value = serde_cbor::to_vec(&signed).unwrap();
+        assert!(validate_record(&record, None).is_err());
+    }
+
+    #[test]
+    fn rejects_a_signature_replayed_under_another_key() {
+        let keypair = Keypair::generate_ed25519();
+        let mut record = file_record(&keypair);
+        record.key = kad::RecordKey::new(&"file::00000000");
+        assert!(validate_record(&record, None).is_err());
+    }
+
+    #[test]
+    fn rejects_a_publisher_other_than_the_signer() {
+        let keypair = Keypair::generate_ed25519();
+        let mut record = file_record(&keypair);
+        record.publisher = Some(PeerId::random());
+        assert!(validate_record(&record, None).is_err());
+
+        // Records without a publisher are only checked against their signature
+        record.publisher = None;
+        assert_eq!(validate_record(&record, None), Ok(()));
+    }
+
+    #[test]
+    fn rejects_metadata_for_someone_elses_file() {
+        let (owner, signer) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+        let record = file_record(&owner);
+        let signed: SignedRecord = serde_cbor::from_slice(&record.value).unwrap();
+        let metadata: FileMetadata = serde_cbor::from_slice(&signed.payload).unwrap();
+        let key = std::str::from_utf8(record.key.as_ref()).unwrap();
+        let forged = signed_record(&signer, key, &metadata).unwrap();
+        assert!(validate_record(&forged, None).is_err());
+    }
+
+    #[test]
+    fn rejects_an_index_for_another_peer() {
+        let keypair = Keypair::generate_ed25519();
+        let key = format!("file_index::{}", PeerId::random());
+        let record = signed_record(&keypair, &key, &list(1, "abcd1234".to_string())).unwrap();
+        assert!(validate_record(&record, None).is_err());
+    }
+
+    #[test]
+    fn rejects_an_unknown_key_prefix() {
+        let keypair = Keypair::generate_ed25519();
+        let key = format!("profile::{}", keypair.public().to_peer_id());
+        let record = signed_record(&keypair, &key, &Vec::<String>::new()).unwrap();
+        assert!(validate_record(&record, None).is_err());
+    }
+
+    #[test]
+    fn wanted_lists_must_be_signed_by_their_peer() {
+        let keypair = Keypair::generate_ed25519();
+        let wants = list(1, Want::Terms(vec!["jazz".to_string()]));
+        let key = format!("wanted::{}", keypair.public().to_peer_id());
+        let record = signed_record(&keypair, &key, &wants).unwrap();
+        assert_eq!(validate_record(&record, None), Ok(()));
+
+        let key = format!("wanted::{}", PeerId::random());
+        let record = signed_record(&keypair, &key, &wants).unwrap();
+        assert!(validate_record(&record, None).is_err());
+    }
+
+    #[test]
+    fn older_lists_cant_replace_newer_ones() {
+        let keypair = Keypair::generate_ed25519();
+        let key = format!("file_index::{}", keypair.public().to_peer_id());
+        let old = signed_record(&keypair, &key, &list(1, "abcd1234".to_string())).unwrap();
+        let new = signed_record(&keypair, &key, &list(2, "ef567890".to_string())).unwrap();
+
+        assert_eq!(validate_record(&new, Some(&old)), Ok(()));
+        assert!(validate_record(&old, Some(&new)).is_err());
+        assert_eq!(validate_record(&new, Some(&new)), Ok(()));
+    }
+}
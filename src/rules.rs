@@ -0,0 +1,342 @@
+use libp2p::PeerId;
+use std::{
+    fmt, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::files::FileMetadata;
+use crate::utils::TradeRequest;
+use crate::wanted::WantedList;
+
+/// One check made against an incoming trade request
+#[derive(Debug, Clone)]
+enum Condition {
+    /// The offered file is on our wanted list
+    Wanted,
+    /// The request comes from this peer
+    Friend(PeerId),
+    /// The offered file is between min and max times the size of the one they want
+    SizeRatio(f64, f64),
+    /// The offered file's description has this tag (with or without a leading #)
+    Tag(String),
+}
+
+impl Condition {
+    fn parse(words: &[&str]) -> Result<Self, String> {
+        match words {
+            ["wanted"] => Ok(Condition::Wanted),
+            ["friend", peer] => peer
+                .parse()
+                .map(Condition::Friend)
+                .map_err(|_| format!("'{peer}' is not a peer ID")),
+            ["size_ratio", min, max] => {
+                let (Ok(min), Ok(max)) = (min.parse::<f64>(), max.parse::<f64>()) else {
+                    return Err("size_ratio needs two numbers".to_string());
+                };
+                if min > max {
+                    return Err("size_ratio minimum is bigger than the maximum".to_string());
+                }
+                Ok(Condition::SizeRatio(min, max))
+            }
+            ["tag", tag] => Ok(Condition::Tag(tag.trim_start_matches('#').to_lowercase())),
+            _ => Err(format!("unknown condition '{}'", words.join(" "))),
+        }
+    }
+
+    /// `offered` is the signed metadata of the offered file. Conditions about the file never hold
+    /// without it, as the copy in the trade request is whatever the peer chose to send.
+    fn matches(
+        &self,
+        peer_id: &PeerId,
+        offered: Option<&FileMetadata>,
+        requested_file: &FileMetadata,
+        wanted: &WantedList,
+    ) -> bool {
+        match self {
+            Condition::Friend(friend) => friend == peer_id,
+            Condition::Wanted => offered
+                .is_some_and(|offered| wanted.wants().iter().any(|want| want.matches(offered))),
+            Condition::SizeRatio(min, max) => {
+                let Some(offered) = offered else {
+                    return false;
+                };
+                if requested_file.size == 0 {
+                    return false;
+                }
+                let ratio = offered.size as f64 / requested_file.size as f64;
+                (*min..=*max).contains(&ratio)
+            }
+            Condition::Tag(tag) => offered.is_some_and(|offered| {
+                offered
+                    .description
+                    .as_deref()
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .any(|word| word.trim_start_matches('#').to_lowercase() == *tag)
+            }),
+        }
+    }
+}
+
+/// A line of the rules file, accepts a trade when all of its conditions hold
+#[derive(Debug, Clone)]
+pub struct Rule {
+    line: usize,
+    text: String,
+    conditions: Vec<Condition>,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.text)
+    }
+}
+
+/// Rules for accepting trades without the user having to type /trade_accept.
+/// Each non-empty line of the file is `accept <condition> [and <condition>...]`, lines starting
+/// with # are comments. Conditions are:
+///  - `wanted`: the offered file is on our wanted list
+///  - `friend <peer_id>`: the request comes from that peer
+///  - `size_ratio <min> <max>`: the offered file is min to max times the size of the requested one
+///  - `tag <tag>`: the offered file's description contains the tag
+pub struct TradeRules {
+    path: PathBuf,
+    log_path: PathBuf,
+    rules: Vec<Rule>,
+}
+
+impl TradeRules {
+    /// Load the rules file, reporting (and skipping) any lines that don't make sense.
+    /// A missing file just means no trades are accepted automatically.
+    pub fn load(path: PathBuf, data_dir: &Path) -> Self {
+        let mut trade_rules = TradeRules {
+            path,
+            log_path: data_dir.join("trade_decisions.log"),
+            rules: Vec::new(),
+        };
+        trade_rules.reload();
+        trade_rules
+    }
+
+    /// Read the rules file again, replacing the current rules
+    pub fn reload(&mut self) {
+        self.rules.clear();
+        let Ok(contents) = fs::read_to_string(&self.path) else {
+            return;
+        };
+
+        for (index, line) in contents.lines().enumerate() {
+            let text = line.trim();
+            if text.is_empty() || text.starts_with('#') {
+                continue;
+            }
+            match parse_rule(text) {
+                Ok(conditions) => self.rules.push(Rule {
+                    line: index + 1,
+                    text: text.to_string(),
+                    conditions,
+                }),
+                Err(e) => eprintln!(
+                    "Ignoring trade rule on line {} of {}: {e}",
+                    index + 1,
+                    self.path.display()
+                ),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// The first rule that accepts this trade, if any. `offered` is the signed metadata of the
+    /// offered file, or None if it hasn't been found, in which case only `friend` rules can match.
+    pub fn evaluate(
+        &self,
+        peer_id: &PeerId,
+        offered: Option<&FileMetadata>,
+        requested_file: &FileMetadata,
+        wanted: &WantedList,
+    ) -> Option<&Rule> {
+        self.rules.iter().find(|rule| {
+            rule.conditions
+                .iter()
+                .all(|condition| condition.matches(peer_id, offered, requested_file, wanted))
+        })
+    }
+
+    /// Append an automatic decision to the log, so it's clear later why a trade went through
+    pub fn log_decision(
+        &self,
+        peer_id: &PeerId,
+        request: &TradeRequest,
+        requested_file: &FileMetadata,
+        rule: &Rule,
+    ) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs())
+            .unwrap_or_default();
+        let entry = format!(
+            "{timestamp} accepted {} ({peer_id}) trading their {} ({}) for our {} ({}), matched {rule}\n",
+            request.nickname,
+            request.offered_file.filename,
+            request.offered_file.hash,
+            requested_file.filename,
+            requested_file.hash,
+        );
+
+        let result = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log_path)
+            .and_then(|mut file| file.write_all(entry.as_bytes()));
+        if let Err(e) = result {
+            eprintln!("Failed to log trade decision: {e}");
+        }
+    }
+}
+
+fn parse_rule(text: &str) -> Result<Vec<Condition>, String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let Some((&"accept", rest)) = words.split_first() else {
+        return Err("rules must start with 'accept'".to_string());
+    };
+    if rest.is_empty() {
+        return Err("no conditions given".to_string());
+    }
+    rest.split(|word| *word == "and")
+        .map(Condition::parse)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wanted::Want;
+    use tempfile::TempDir;
+
+    fn metadata(hash: &str, size: usize, description: Option<&str>) -> FileMetadata {
+        FileMetadata {
+            filename: format!("{hash}.txt"),
+            owner: PeerId::random().to_string(),
+            description: description.map(str::to_string),
+            hash: hash.to_string(),
+            size,
+            chunk_count: 1,
+            merkle_root: String::new(),
+            free: false,
+        }
+    }
+
+    /// A wanted list saved in its own temporary directory, removed when the directory is dropped
+    fn empty_wanted() -> (TempDir, WantedList) {
+        let dir = TempDir::new().unwrap();
+        let wanted = WantedList::load(dir.path());
+        (dir, wanted)
+    }
+
+    fn condition(text: &str) -> Condition {
+        Condition::parse(&text.split_whitespace().collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn friend_matches_without_metadata() {
+        let peer = PeerId::random();
+        let requested = metadata("aaaaaaaa", 100, None);
+        let (_dir, wanted) = empty_wanted();
+        let friend = condition(&format!("friend {peer}"));
+        assert!(friend.matches(&peer, None, &requested, &wanted));
+        assert!(!friend.matches(&PeerId::random(), None, &requested, &wanted));
+    }
+
+    #[test]
+    fn file_conditions_need_signed_metadata() {
+        let peer = PeerId::random();
+        let requested = metadata("aaaaaaaa", 100, None);
+        let (_dir, wanted) = empty_wanted();
+        for text in ["wanted", "size_ratio 0 100", "tag music"] {
+            assert!(!condition(text).matches(&peer, None, &requested, &wanted));
+        }
+    }
+
+    #[test]
+    fn size_ratio_is_inclusive() {
+        let peer = PeerId::random();
+        let requested = metadata("aaaaaaaa", 100, None);
+        let (_dir, wanted) = empty_wanted();
+        let ratio = condition("size_ratio 0.5 2");
+        for (size, expected) in [(49, false), (50, true), (200, true), (201, false)] {
+            let offered = metadata("bbbbbbbb", size, None);
+            assert_eq!(
+                ratio.matches(&peer, Some(&offered), &requested, &wanted),
+                expected,
+                "size {size}"
+            );
+        }
+        let empty = metadata("cccccccc", 0, None);
+        let offered = metadata("bbbbbbbb", 10, None);
+        assert!(!ratio.matches(&peer, Some(&offered), &empty, &wanted));
+    }
+
+    #[test]
+    fn tags_ignore_case_and_hash() {
+        let peer = PeerId::random();
+        let requested = metadata("aaaaaaaa", 100, None);
+        let (_dir, wanted) = empty_wanted();
+        let offered = metadata("bbbbbbbb", 100, Some("Some #Music from 1999"));
+        assert!(condition("tag #music").matches(&peer, Some(&offered), &requested, &wanted));
+        assert!(!condition("tag mus").matches(&peer, Some(&offered), &requested, &wanted));
+    }
+
+    #[test]
+    fn wanted_checks_the_wishlist() {
+        let peer = PeerId::random();
+        let requested = metadata("aaaaaaaa", 100, None);
+        let (_dir, mut wanted) = empty_wanted();
+        let offered = metadata("bbbbbbbb", 100, None);
+        assert!(!condition("wanted").matches(&peer, Some(&offered), &requested, &wanted));
+        wanted.add(Want::Hash("bbbbbbbb".to_string()));
+        assert!(condition("wanted").matches(&peer, Some(&offered), &requested, &wanted));
+    }
+
+    #[test]
+    fn rules_need_every_condition() {
+        let conditions = parse_rule("accept tag music and size_ratio 1 1").unwrap();
+        assert_eq!(conditions.len(), 2);
+        assert!(parse_rule("accept").is_err());
+        assert!(parse_rule("allow wanted").is_err());
+        assert!(parse_rule("accept size_ratio 2 1").is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("rules.txt");
+        let peer = PeerId::random();
+        let rules = format!(
+            "# comment\naccept tag music and size_ratio 1 1\naccept nonsense\naccept friend {peer}\n"
+        );
+        fs::write(&path, rules).unwrap();
+        let trade_rules = TradeRules::load(path, dir.path());
+        assert_eq!(trade_rules.rules().len(), 2);
+
+        let (_wanted_dir, wanted) = empty_wanted();
+        let requested = metadata("aaaaaaaa", 100, None);
+        let offered = metadata("bbbbbbbb", 100, Some("#music"));
+        let rule = trade_rules.evaluate(&peer, Some(&offered), &requested, &wanted);
+        assert_eq!(rule.unwrap().line, 2);
+        let rule = trade_rules.evaluate(&peer, None, &requested, &wanted);
+        assert_eq!(rule.unwrap().line, 4);
+        let stranger = PeerId::random();
+        assert!(trade_rules
+            .evaluate(&stranger, None, &requested, &wanted)
+            .is_none());
+    }
+}
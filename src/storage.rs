@@ -0,0 +1,611 @@
+use libp2p::{
+    identity::Keypair,
+    kad::{
+        self,
+        store::{MemoryStore, RecordStore},
+        ProviderRecord, Record,
+    },
+    pnet::PreSharedKey,
+    Multiaddr, PeerId,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+use crate::events::SwapBytesBehaviour;
+use crate::files::DirectMessage;
+use crate::utils::ChatState;
+
+/// Serializable copy of a kad::Record. Instants can't be saved, so expiry is stored as unix seconds.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    publisher: Option<Vec<u8>>,
+    expires: Option<u64>,
+}
+
+/// Serializable copy of a kad::ProviderRecord
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredProvider {
+    provider: Vec<u8>,
+    expires: Option<u64>,
+    addresses: Vec<String>,
+}
+
+/// Disk-backed Kademlia record store.
+/// Records live in a MemoryStore for fast lookups. Changed keys are noted and written out in
+/// batches by `flush`, so the event loop isn't held up by a file write on every change, and the
+/// store can be rebuilt when the node restarts.
+pub struct DiskStore {
+    inner: MemoryStore,
+    records_dir: PathBuf,
+    providers_dir: PathBuf,
+    dirty_records: HashSet<kad::RecordKey>,
+    dirty_providers: HashSet<kad::RecordKey>,
+}
+
+impl DiskStore {
+    /// Open the store in the given directory, loading any records saved by a previous session
+    pub fn new(local_id: PeerId, dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let records_dir = dir.join("records");
+        let providers_dir = dir.join("providers");
+        fs::create_dir_all(&records_dir)?;
+        fs::create_dir_all(&providers_dir)?;
+
+        let mut store = DiskStore {
+            inner: MemoryStore::new(local_id),
+            records_dir,
+            providers_dir,
+            dirty_records: HashSet::new(),
+            dirty_providers: HashSet::new(),
+        };
+        store.load();
+        Ok(store)
+    }
+
+    /// Read every saved record and provider back into memory, dropping any that have expired
+    fn load(&mut self) {
+        for path in read_dir_files(&self.records_dir) {
+            let Some(stored) = read_cbor::<StoredRecord>(&path) else {
+                continue;
+            };
+            let Some(expires) = to_instant(stored.expires) else {
+                let _ = fs::remove_file(&path);
+                continue;
+            };
+            let record = Record {
+                key: kad::RecordKey::from(stored.key),
+                value: stored.value,
+                publisher: stored
+                    .publisher
+                    .and_then(|bytes| PeerId::from_bytes(&bytes).ok()),
+                expires,
+            };
+            if let Err(e) = self.inner.put(record) {
+                eprintln!("Failed to load stored record: {e}");
+            }
+        }
+
+        for path in read_dir_files(&self.providers_dir) {
+            let Some(key) = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| hex::decode(name).ok())
+            else {
+                continue;
+            };
+            let Some(providers) = read_cbor::<Vec<StoredProvider>>(&path) else {
+                continue;
+            };
+            for stored in providers {
+                let (Ok(provider), Some(expires)) = (
+                    PeerId::from_bytes(&stored.provider),
+                    to_instant(stored.expires),
+                ) else {
+                    continue;
+                };
+                let record = ProviderRecord {
+                    key: kad::RecordKey::from(key.clone()),
+                    provider,
+                    expires,
+                    addresses: stored
+                        .addresses
+                        .iter()
+                        .filter_map(|addr| addr.parse().ok())
+                        .collect(),
+                };
+                if let Err(e) = self.inner.add_provider(record) {
+                    eprintln!("Failed to load stored provider: {e}");
+                }
+            }
+        }
+    }
+
+    fn record_path(&self, key: &kad::RecordKey) -> PathBuf {
+        self.records_dir.join(hex::encode(key.as_ref()))
+    }
+
+    fn providers_path(&self, key: &kad::RecordKey) -> PathBuf {
+        self.providers_dir.join(hex::encode(key.as_ref()))
+    }
+
+    /// Write every record and provider list that's changed since the last flush
+    pub fn flush(&mut self) {
+        for key in std::mem::take(&mut self.dirty_records) {
+            self.save_record(&key);
+        }
+        for key in std::mem::take(&mut self.dirty_providers) {
+            self.save_providers(&key);
+        }
+    }
+
+    /// Rewrite the record file for a key from what's currently in memory
+    fn save_record(&self, key: &kad::RecordKey) {
+        let path = self.record_path(key);
+        let Some(record) = self.inner.get(key) else {
+            let _ = fs::remove_file(path);
+            return;
+        };
+        let stored = StoredRecord {
+            key: record.key.to_vec(),
+            value: record.value.clone(),
+            publisher: record.publisher.map(|peer| peer.to_bytes()),
+            expires: from_instant(record.expires),
+        };
+        write_cbor(&path, &stored);
+    }
+
+    /// Rewrite the provider file for a key from what's currently in memory
+    fn save_providers(&self, key: &kad::RecordKey) {
+        let providers: Vec<StoredProvider> = self
+            .inner
+            .providers(key)
+            .into_iter()
+            .map(|record| StoredProvider {
+                provider: record.provider.to_bytes(),
+                expires: from_instant(record.expires),
+                addresses: record.addresses.iter().map(|a| a.to_string()).collect(),
+            })
+            .collect();
+
+        let path = self.providers_path(key);
+        if providers.is_empty() {
+            let _ = fs::remove_file(path);
+        } else {
+            write_cbor(&path, &providers);
+        }
+    }
+}
+
+impl RecordStore for DiskStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &kad::RecordKey) -> Option<Cow<'_, Record>> {
+        self.inner.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> kad::store::Result<()> {
+        let key = r.key.clone();
+        self.inner.put(r)?;
+        self.dirty_records.insert(key);
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &kad::RecordKey) {
+        self.inner.remove(k);
+        self.dirty_records.insert(k.clone());
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.inner.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> kad::store::Result<()> {
+        let key = record.key.clone();
+        self.inner.add_provider(record)?;
+        self.dirty_providers.insert(key);
+        Ok(())
+    }
+
+    fn providers(&self, key: &kad::RecordKey) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, k: &kad::RecordKey, p: &PeerId) {
+        self.inner.remove_provider(k, p);
+        self.dirty_providers.insert(k.clone());
+    }
+}
+
+/// Lock the data directory for as long as the returned file is kept open. Two peers sharing a
+/// directory would share an identity too, so a second one is turned away.
+pub fn lock_data_dir(data_dir: &Path) -> Result<File, Box<dyn Error>> {
+    fs::create_dir_all(data_dir)?;
+    let lock = File::create(data_dir.join("lock"))?;
+    if lock.try_lock().is_err() {
+        return Err(format!(
+            "{} is already in use by another swapbytes peer, give this one its own --data-dir",
+            data_dir.display()
+        )
+        .into());
+    }
+    Ok(lock)
+}
+
+/// The data directory used when --data-dir isn't given, kept with the user's other app data
+pub fn default_data_dir() -> PathBuf {
+    dirs::data_dir()
+        .map(|dir| dir.join("swapbytes"))
+        .unwrap_or_else(|| PathBuf::from("swapbytes_data"))
+}
+
+/// Load the node's keypair from disk, or create and save a new one.
+/// Keeping the same identity means peers can find us again at the same PeerId after a restart.
+pub fn load_or_create_identity(data_dir: &Path) -> Result<Keypair, Box<dyn Error>> {
+    let path = data_dir.join("identity.key");
+    if path.exists() {
+        let bytes = fs::read(&path)?;
+        return Ok(Keypair::from_protobuf_encoding(&bytes)?);
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    fs::create_dir_all(data_dir)?;
+    create_private_file(&path)?.write_all(&keypair.to_protobuf_encoding()?)?;
+    Ok(keypair)
+}
+
+/// Create a new file only we can read and write, failing if it's already there
+fn create_private_file(path: &Path) -> io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    options.open(path)
+}
+
+/// Replace a file's contents, keeping it readable and writable only by us
+fn write_private_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to new files, so one saved by an older version is tightened up too
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(bytes)
+}
+
+/// Read a swarm key file, in the same format go-libp2p and IPFS use
+pub fn load_psk(path: &Path) -> Result<PreSharedKey, Box<dyn Error>> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Couldn't read swarm key {}: {e}", path.display()))?;
+    Ok(contents
+        .parse()
+        .map_err(|e| format!("Invalid swarm key {}: {e}", path.display()))?)
+}
+
+/// Create a random swarm key and save it, refusing to overwrite an existing one
+pub fn generate_psk(path: &Path) -> Result<PreSharedKey, Box<dyn Error>> {
+    if path.exists() {
+        return Err(format!("{} already exists", path.display()).into());
+    }
+    let psk = PreSharedKey::new(rand::random());
+    fs::write(path, psk.to_string())?;
+    Ok(psk)
+}
+
+/// Write out the DHT changes made since the last save: new and expired records, and the address
+/// book if the routing table has changed
+pub fn save_dht(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, chat_state: &mut ChatState) {
+    swarm.behaviour_mut().kademlia.store_mut().flush();
+    if std::mem::take(&mut chat_state.routing_changed) {
+        save_address_book(swarm, &chat_state.data_dir);
+    }
+}
+
+/// Save every peer in the Kademlia routing table along with its known addresses
+pub fn save_address_book(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, data_dir: &Path) {
+    let mut address_book: HashMap<String, Vec<String>> = HashMap::new();
+    for bucket in swarm.behaviour_mut().kademlia.kbuckets() {
+        for entry in bucket.iter() {
+            address_book.insert(
+                entry.node.key.preimage().to_string(),
+                entry.node.value.iter().map(|a| a.to_string()).collect(),
+            );
+        }
+    }
+    write_cbor(&data_dir.join("address_book.cbor"), &address_book);
+}
+
+/// Read the saved routing table back, returning each peer with the addresses it was last seen on
+pub fn load_address_book(data_dir: &Path) -> Vec<(PeerId, Vec<Multiaddr>)> {
+    let Some(address_book) =
+        read_cbor::<HashMap<String, Vec<String>>>(&data_dir.join("address_book.cbor"))
+    else {
+        return Vec::new();
+    };
+
+    address_book
+        .into_iter()
+        .filter_map(|(peer, addresses)| {
+            let peer = peer.parse::<PeerId>().ok()?;
+            let addresses = addresses.iter().filter_map(|a| a.parse().ok()).collect();
+            Some((peer, addresses))
+        })
+        .collect()
+}
+
+/// Save the DMs still waiting to be delivered, so they survive a restart
+pub fn save_outbox(data_dir: &Path, outbox: &HashMap<PeerId, Vec<DirectMessage>>) {
+    let outbox: HashMap<String, &Vec<DirectMessage>> = outbox
+        .iter()
+        .map(|(peer, messages)| (peer.to_string(), messages))
+        .collect();
+    write_private_cbor(&data_dir.join("outbox.cbor"), &outbox);
+}
+
+/// Read back the DMs that hadn't been delivered when we last shut down
+pub fn load_outbox(data_dir: &Path) -> HashMap<PeerId, Vec<DirectMessage>> {
+    read_cbor::<HashMap<String, Vec<DirectMessage>>>(&data_dir.join("outbox.cbor"))
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(peer, messages)| Some((peer.parse().ok()?, messages)))
+        .collect()
+}
+
+/// Serialize a value to a file, reporting (but otherwise ignoring) failures
+pub fn write_cbor<T: Serialize>(path: &Path, value: &T) {
+    let result = serde_cbor::to_vec(value)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| fs::write(path, bytes).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        eprintln!("Failed to save {}: {e}", path.display());
+    }
+}
+
+/// Like `write_cbor`, for files holding keys. Only we can read them.
+pub fn write_private_cbor<T: Serialize>(path: &Path, value: &T) {
+    let result = serde_cbor::to_vec(value)
+        .map_err(|e| e.to_string())
+        .and_then(|bytes| write_private_file(path, &bytes).map_err(|e| e.to_string()));
+    if let Err(e) = result {
+        eprintln!("Failed to save {}: {e}", path.display());
+    }
+}
+
+/// Deserialize a value from a file, None if the file is missing or unreadable
+pub fn read_cbor<T: for<'de> Deserialize<'de>>(path: &Path) -> Option<T> {
+    let bytes = fs::read(path).ok()?;
+    serde_cbor::from_slice(&bytes).ok()
+}
+
+fn read_dir_files(dir: &Path) -> Vec<PathBuf> {
+    fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.is_file())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Convert an expiry Instant to unix seconds
+fn from_instant(instant: Option<Instant>) -> Option<u64> {
+    let remaining = instant?.saturating_duration_since(Instant::now());
+    let expires_at = SystemTime::now() + remaining;
+    Some(expires_at.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Convert unix seconds back to an expiry Instant.
+/// The outer None means the record has already expired and should be thrown away.
+fn to_instant(expires: Option<u64>) -> Option<Option<Instant>> {
+    let Some(expires) = expires else {
+        return Some(None);
+    };
+    let expires_at = UNIX_EPOCH + Duration::from_secs(expires);
+    let remaining = expires_at.duration_since(SystemTime::now()).ok()?;
+    Some(Some(Instant::now() + remaining))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn record(key: &str, expires: Option<Instant>) -> Record {
+        Record {
+            key: kad::RecordKey::new(&key),
+            value: key.as_bytes().to_vec(),
+            publisher: Some(PeerId::random()),
+            expires,
+        }
+    }
+
+    /// Open the store again as if the node had restarted
+    fn reopen(mut store: DiskStore, local_id: PeerId, dir: &Path) -> DiskStore {
+        store.flush();
+        drop(store);
+        DiskStore::new(local_id, dir).unwrap()
+    }
+
+    #[test]
+    fn records_survive_a_restart() {
+        let dir = TempDir::new().unwrap();
+        let local_id = PeerId::random();
+        let mut store = DiskStore::new(local_id, dir.path()).unwrap();
+        let saved = record(
+            "file::abc",
+            Some(Instant::now() + Duration::from_secs(3600)),
+        );
+        store.put(saved.clone()).unwrap();
+        store.put(record("file::def", None)).unwrap();
+        store.remove(&kad::RecordKey::new(&"file::def"));
+
+        let store = reopen(store, local_id, dir.path());
+        let loaded = store.get(&saved.key).unwrap();
+        assert_eq!(loaded.value, saved.value);
+        assert_eq!(loaded.publisher, saved.publisher);
+        assert!(loaded.expires.is_some());
+        assert!(store.get(&kad::RecordKey::new(&"file::def")).is_none());
+    }
+
+    #[test]
+    fn expired_records_are_dropped_on_load() {
+        let dir = TempDir::new().unwrap();
+        let local_id = PeerId::random();
+        let mut store = DiskStore::new(local_id, dir.path()).unwrap();
+        let expired = record("file::old", Some(Instant::now()));
+        store.put(expired.clone()).unwrap();
+
+        let store = reopen(store, local_id, dir.path());
+        assert!(store.get(&expired.key).is_none());
+        assert!(read_dir_files(&dir.path().join("records")).is_empty());
+    }
+
+    #[test]
+    fn providers_survive_a_restart() {
+        let dir = TempDir::new().unwrap();
+        let local_id = PeerId::random();
+        let mut store = DiskStore::new(local_id, dir.path()).unwrap();
+        let key = kad::RecordKey::new(&"abc");
+        let (kept, removed) = (PeerId::random(), PeerId::random());
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/4001".parse().unwrap();
+        for provider in [kept, removed] {
+            let mut record = ProviderRecord::new(key.clone(), provider, vec![address.clone()]);
+            record.expires = Some(Instant::now() + Duration::from_secs(3600));
+            store.add_provider(record).unwrap();
+        }
+        store.remove_provider(&key, &removed);
+
+        let store = reopen(store, local_id, dir.path());
+        let providers = store.providers(&key);
+        assert_eq!(providers.len(), 1);
+        assert_eq!(providers[0].provider, kept);
+        assert_eq!(providers[0].addresses, vec![address]);
+    }
+
+    #[test]
+    fn instants_round_trip_through_unix_seconds() {
+        assert_eq!(to_instant(from_instant(None)), Some(None));
+
+        let later = Instant::now() + Duration::from_secs(600);
+        let restored = to_instant(from_instant(Some(later))).unwrap().unwrap();
+        let drift = if restored > later {
+            restored - later
+        } else {
+            later - restored
+        };
+        assert!(drift <= Duration::from_secs(1));
+
+        // Already in the past, so the record is thrown away
+        assert_eq!(to_instant(Some(1)), None);
+    }
+
+    #[test]
+    fn identity_is_kept_between_runs() {
+        let dir = TempDir::new().unwrap();
+        let first = load_or_create_identity(dir.path()).unwrap();
+        let second = load_or_create_identity(dir.path()).unwrap();
+        assert_eq!(first.public(), second.public());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn identity_is_only_readable_by_us() {
+        let dir = TempDir::new().unwrap();
+        load_or_create_identity(dir.path()).unwrap();
+        let metadata = fs::metadata(dir.path().join("identity.key")).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn outbox_is_only_readable_by_us() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("outbox.cbor");
+        // Saved by a version that didn't restrict it
+        fs::write(&path, b"").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        save_outbox(dir.path(), &HashMap::new());
+        let metadata = fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert!(load_outbox(dir.path()).is_empty());
+    }
+
+    #[test]
+    fn address_book_skips_unreadable_entries() {
+        let dir = TempDir::new().unwrap();
+        assert!(load_address_book(dir.path()).is_empty());
+
+        let peer = PeerId::random();
+        let address_book = HashMap::from([
+            (
+                peer.to_string(),
+                vec!["/ip4/10.0.0.1/tcp/4001".to_string(), "junk".to_string()],
+            ),
+            ("not a peer".to_string(), vec![]),
+        ]);
+        write_cbor(&dir.path().join("address_book.cbor"), &address_book);
+
+        let loaded = load_address_book(dir.path());
+        assert_eq!(
+            loaded,
+            vec![(peer, vec!["/ip4/10.0.0.1/tcp/4001".parse().unwrap()])]
+        );
+    }
+
+    #[test]
+    fn changes_wait_for_a_flush() {
+        let dir = TempDir::new().unwrap();
+        let local_id = PeerId::random();
+        let mut store = DiskStore::new(local_id, dir.path()).unwrap();
+        let key = kad::RecordKey::new(&"file::abc");
+        store.put(record("file::abc", None)).unwrap();
+        assert!(read_dir_files(&dir.path().join("records")).is_empty());
+
+        store.flush();
+        assert_eq!(read_dir_files(&dir.path().join("records")).len(), 1);
+
+        store.remove(&key);
+        store.flush();
+        assert!(read_dir_files(&dir.path().join("records")).is_empty());
+    }
+
+    #[test]
+    fn outbox_survives_a_restart() {
+        let dir = TempDir::new().unwrap();
+        assert!(load_outbox(dir.path()).is_empty());
+
+        let peer = PeerId::random();
+        let message = DirectMessage {
+            sender_nickname: "sam".to_string(),
+            message: "are you still there?".to_string(),
+            group: None,
+        };
+        save_outbox(dir.path(), &HashMap::from([(peer, vec![message.clone()])]));
+        assert_eq!(
+            load_outbox(dir.path()),
+            HashMap::from([(peer, vec![message])])
+        );
+    }
+}
@@ -1,34 +1,161 @@
-use libp2p::{gossipsub::IdentTopic, kad, PeerId, Swarm};
+use futures::StreamExt;
+use libp2p::{
+    gossipsub::{self, IdentTopic},
+    identify,
+    identity::Keypair,
+    kad, rendezvous,
+    request_response::{self, Message, OutboundRequestId},
+    swarm::SwarmEvent,
+    PeerId, Swarm,
+};
 use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
+use std::error::Error;
 use std::io::{stdout, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::io;
 
-use crate::events::SwapBytesBehaviour;
-use crate::files::FileMetadata;
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct PeerInfo {
-    pub peerid: String,
-    pub nickname: String,
-}
+use crate::catalog::{Catalog, PUBLISHERS_KEY};
+use crate::chat::ChatLog;
+use crate::contacts::{BlockList, ContactBook};
+use crate::downloads::Downloads;
+use crate::events::{SwapBytesBehaviour, SwapBytesBehaviourEvent};
+use crate::files::{
+    DirectMessage, FileAnnouncement, FileMetadata, FileResponse, GiftMessage, LocalFileStore,
+};
+use crate::groups::{GroupKey, Groups};
+use crate::records::{self, PeerList, SignedRecord};
+use crate::rules::TradeRules;
+use crate::storage;
+use crate::validation::{self, GossipValidator, ANNOUNCEMENT_TOPIC, CHAT_TOPIC, NICKNAME_TOPIC};
+use crate::wanted::WantedList;
 
 pub struct NicknameMap {
     inner: HashMap<String, String>,
+    /// The last peer seen using each nickname. Used to notice when a name changes hands, and so
+    /// old names still find the right peer after a rename.
+    owners: HashMap<String, String>,
+    /// Names each peer has used before their current one, oldest first
+    history: HashMap<String, Vec<String>>,
+    /// The latest signed nickname claim from each peer
+    claims: HashMap<PeerId, NicknameClaim>,
+    contacts: ContactBook,
 }
 
 impl NicknameMap {
-    pub fn new() -> Self {
+    pub fn new(contacts: ContactBook) -> Self {
         NicknameMap {
             inner: HashMap::new(),
+            owners: HashMap::new(),
+            history: HashMap::new(),
+            claims: HashMap::new(),
+            contacts,
+        }
+    }
+
+    pub fn contacts(&self) -> &ContactBook {
+        &self.contacts
+    }
+
+    pub fn contacts_mut(&mut self) -> &mut ContactBook {
+        &mut self.contacts
+    }
+
+    /// Copy a peer's current nickname into their contact entry, for when they've just been added
+    pub fn refresh_contact(&mut self, peer: &PeerId) {
+        if let Some(nickname) = self.inner.get(&peer.to_string()) {
+            self.contacts.saw_nickname(peer, nickname);
+        }
+    }
+
+    /// Record the nickname a peer is using. It must come from a claim that's passed
+    /// `verify_claim`, so an old name can't be replayed over a newer one.
+    /// Returns a warning if it looks like they're impersonating someone.
+    pub fn insert(&mut self, peer: PeerId, nickname: String) -> Option<String> {
+        let peer_id_str = peer.to_string();
+        let current_holder = self
+            .inner
+            .iter()
+            .find(|(holder, name)| **name == nickname && **holder != peer_id_str)
+            .map(|(holder, _)| holder.clone());
+
+        let warning = if !nickname.ends_with(&format!(".{}", nickname_suffix(&peer))) {
+            Some(format!(
+                "'{nickname}' doesn't match the ID of the peer using it ({peer}), they may be impersonating someone"
+            ))
+        } else if let Some(holder) = current_holder {
+            Some(format!(
+                "'{nickname}' is being used by both {holder} and {peer}"
+            ))
+        } else {
+            self.owners
+                .get(&nickname)
+                .filter(|owner| **owner != peer_id_str)
+                .map(|owner| {
+                    format!("'{nickname}' used to belong to {owner}, it's now used by {peer}")
+                })
+        };
+
+        self.contacts.saw_nickname(&peer, &nickname);
+        self.owners.insert(nickname.clone(), peer_id_str.clone());
+        if let Some(previous) = self.inner.insert(peer_id_str.clone(), nickname.clone()) {
+            if previous != nickname {
+                self.history.entry(peer_id_str).or_default().push(previous);
+            }
+        }
+        warning
+    }
+
+    /// The nickname a peer is currently using, if we know it
+    pub fn current(&self, peer: &PeerId) -> Option<&str> {
+        self.inner.get(&peer.to_string()).map(String::as_str)
+    }
+
+    /// Names a peer has used before, oldest first
+    pub fn history(&self, peer: &PeerId) -> &[String] {
+        self.history
+            .get(&peer.to_string())
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Check a signed nickname claim came from the peer it's about and is no older than the last
+    /// one we saw from them, then keep it, returning the claimed nickname for the caller to insert.
+    /// Without the sequence check an old claim could be replayed over a newer name.
+    pub fn verify_claim(&mut self, peer: PeerId, claim: NicknameUpdate) -> Result<String, String> {
+        let (signer, claim) = claim.open()?;
+        if signer != peer {
+            return Err(format!("nickname claim from {peer} was signed by {signer}"));
+        }
+        if let Some(latest) = self.claims.get(&peer) {
+            if claim.sequence < latest.sequence {
+                return Err(format!(
+                    "claim for '{}' is older than their claim for '{}'",
+                    claim.nickname, latest.nickname
+                ));
+            }
         }
+        let nickname = claim.nickname.clone();
+        self.claims.insert(peer, claim);
+        Ok(nickname)
     }
 
-    pub fn insert(&mut self, key: String, value: String) {
-        self.inner.insert(key, value);
+    /// Whether we've had a signed nickname claim from the peer
+    pub fn is_signed(&self, peer: &PeerId) -> bool {
+        self.claims.contains_key(peer)
     }
 
+    /// The name to show for a peer: our petname for them, then their nickname, then their ID
     pub fn get<'a>(&'a self, key: &'a str) -> &'a str {
+        let petname = key
+            .parse::<PeerId>()
+            .ok()
+            .and_then(|peer| self.contacts.get(&peer))
+            .and_then(|contact| contact.petname.as_deref());
+        if let Some(petname) = petname {
+            return petname;
+        }
         let poss_value = self.inner.get(key);
         match poss_value {
             Some(value) => value,
@@ -36,13 +163,35 @@ impl NicknameMap {
         }
     }
 
-    pub fn get_key_from_value(&self, value: &str) -> Option<String> {
-        for (key, val) in &self.inner {
-            if *val == value {
-                return Some(key.clone());
-            }
+    /// Every peer a name could refer to. Petnames win outright, then a peer ID, then peers using
+    /// it as their nickname (just our contacts if any of them are), and finally whoever last used
+    /// it if they've renamed. More than one result means the caller has to ask which was meant.
+    pub fn lookup(&self, value: &str) -> Vec<String> {
+        if let Some(peer) = self.contacts.find_petname(value) {
+            return vec![peer.to_string()];
         }
-        None
+        if value.parse::<PeerId>().is_ok() {
+            return vec![value.to_string()];
+        }
+
+        let mut matches: Vec<String> = self
+            .inner
+            .iter()
+            .filter(|(_, val)| **val == value)
+            .map(|(key, _)| key.clone())
+            .collect();
+        let is_contact = |key: &String| {
+            key.parse::<PeerId>()
+                .is_ok_and(|peer| self.contacts.contains(&peer))
+        };
+        if matches.iter().any(is_contact) {
+            matches.retain(is_contact);
+        }
+        if matches.is_empty() {
+            matches.extend(self.owners.get(value).cloned());
+        }
+        matches.sort();
+        matches
     }
 }
 
@@ -53,34 +202,215 @@ pub struct TradeRequest {
     pub nickname: String,
 }
 
+/// What we've learned about a peer from identify and ping, shown by /whois.
+/// Only kept while we're connected to them.
+#[derive(Debug, Default)]
+pub struct PeerDetails {
+    pub info: Option<identify::Info>,
+    pub rtt: Option<Duration>,
+}
+
 pub struct ChatState {
-    pub pending_keys: HashSet<kad::QueryId>,
     pub nicknames: NicknameMap,
     pub current_topic: IdentTopic,
+    pub announcement_topic: IdentTopic,
+    pub nickname_topic: IdentTopic,
     pub incoming_trades: HashMap<String, TradeRequest>,
     pub outgoing_trades: HashMap<String, TradeRequest>,
+    pub incoming_gifts: HashMap<String, FileMetadata>,
+    pub outgoing_gifts: HashMap<String, String>,
+    pub get_queries: HashMap<kad::QueryId, String>,
+    /// Free files and gifts we've asked for, by the hash we asked for
+    pub free_requests: HashMap<OutboundRequestId, String>,
     pub nickname: String,
+    /// Sent with our signed nickname, so peers can tell our latest name from an older one
+    nickname_sequence: u64,
     pub rendezvous: PeerId,
+    pub provider_queries: HashMap<kad::QueryId, (String, HashSet<PeerId>)>,
+    pub downloads: Downloads,
+    pub data_dir: PathBuf,
+    /// The routing table has changed since the address book was last saved
+    pub routing_changed: bool,
+    pub keypair: Keypair,
+    pub reshare: bool,
+    pub wanted: WantedList,
+    pub want_queries: HashMap<kad::QueryId, (PeerId, FileMetadata)>,
+    pub trade_rules: TradeRules,
+    /// Metadata lookups for files offered in trades, so the trade rules can be checked against it
+    pub rule_queries: HashMap<kad::QueryId, PeerId>,
+    pub catalog: Catalog,
+    pub peer_details: HashMap<PeerId, PeerDetails>,
+    /// Set by /quit, the main loop shuts down once it sees it
+    pub quit: bool,
+    /// DMs waiting to be acknowledged, and ones to send again when the peer reconnects.
+    /// Undelivered DMs are saved, so they're still sent if we restart in the meantime.
+    pub pending_dms: HashMap<OutboundRequestId, (PeerId, DirectMessage)>,
+    undelivered_dms: HashMap<PeerId, Vec<DirectMessage>>,
+    /// Peers whose nickname exchange failed, retried when they reconnect
+    pub nickname_retries: HashSet<PeerId>,
+    /// Peers we've asked for a signed nickname after seeing them in chat, so they're asked once
+    pub claim_requests: HashSet<PeerId>,
+    pub blocked: BlockList,
+    pub chat_log: ChatLog,
+    pub gossip_validator: GossipValidator,
+    pub groups: Groups,
 }
 
 impl ChatState {
-    pub fn new(nickname: String) -> ChatState {
+    pub fn new(
+        nickname: String,
+        data_dir: PathBuf,
+        keypair: Keypair,
+        reshare: bool,
+        rules_path: PathBuf,
+    ) -> ChatState {
+        let groups = Groups::load(&data_dir);
+        let mut gossip_validator = GossipValidator::new();
+        for group in groups.all() {
+            gossip_validator.add_group(group.topic().hash());
+        }
+
         ChatState {
-            pending_keys: HashSet::new(),
-            nicknames: NicknameMap::new(),
-            current_topic: IdentTopic::new("chat"),
+            nicknames: NicknameMap::new(ContactBook::load(&data_dir)),
+            current_topic: IdentTopic::new(CHAT_TOPIC),
+            announcement_topic: IdentTopic::new(ANNOUNCEMENT_TOPIC),
+            nickname_topic: IdentTopic::new(NICKNAME_TOPIC),
             incoming_trades: HashMap::new(),
             outgoing_trades: HashMap::new(),
+            incoming_gifts: HashMap::new(),
+            outgoing_gifts: HashMap::new(),
+            get_queries: HashMap::new(),
+            free_requests: HashMap::new(),
             nickname,
+            nickname_sequence: unix_millis(),
             rendezvous: "12D3KooWDpJ7As7BWAwRMfu1VU2WCqNjvq387JEYKDBj4kx6nXTN"
                 .parse::<PeerId>()
                 .unwrap(),
+            provider_queries: HashMap::new(),
+            downloads: Downloads::new(),
+            wanted: WantedList::load(&data_dir),
+            want_queries: HashMap::new(),
+            trade_rules: TradeRules::load(rules_path, &data_dir),
+            rule_queries: HashMap::new(),
+            catalog: Catalog::new(),
+            peer_details: HashMap::new(),
+            quit: false,
+            pending_dms: HashMap::new(),
+            undelivered_dms: storage::load_outbox(&data_dir),
+            nickname_retries: HashSet::new(),
+            claim_requests: HashSet::new(),
+            blocked: BlockList::load(&data_dir),
+            chat_log: ChatLog::new(),
+            gossip_validator,
+            groups,
+            data_dir,
+            routing_changed: false,
+            keypair,
+            reshare,
         }
     }
 }
 
+/// A nickname signed by the peer using it, so it can't be claimed on someone else's behalf
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NicknameUpdate(pub SignedRecord);
+
+/// The signed part of a nickname update. The sequence goes up every time the peer changes name.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct NicknameUpdate(pub String);
+pub struct NicknameClaim {
+    pub nickname: String,
+    pub sequence: u64,
+}
+
+impl NicknameUpdate {
+    pub fn new(keypair: &Keypair, nickname: &str, sequence: u64) -> Result<Self, Box<dyn Error>> {
+        let claim = NicknameClaim {
+            nickname: nickname.to_string(),
+            sequence,
+        };
+        Ok(NicknameUpdate(SignedRecord::sign(
+            keypair, "nickname", &claim,
+        )?))
+    }
+
+    /// The peer that signed the claim and what it claims
+    pub fn open(&self) -> Result<(PeerId, NicknameClaim), String> {
+        self.0.open("nickname")
+    }
+}
+
+/// Milliseconds since the Unix epoch, used to start the nickname sequence somewhere above
+/// anything we sent in an earlier session, and to version the lists we publish on the DHT
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+impl ChatState {
+    /// Keep a DM to send when the peer next connects
+    pub fn queue_dm(&mut self, peer: PeerId, message: DirectMessage) {
+        self.undelivered_dms.entry(peer).or_default().push(message);
+        storage::save_outbox(&self.data_dir, &self.undelivered_dms);
+    }
+
+    /// Take the DMs waiting for a peer, to try sending them again
+    pub fn take_undelivered_dms(&mut self, peer: &PeerId) -> Option<Vec<DirectMessage>> {
+        let messages = self.undelivered_dms.remove(peer)?;
+        storage::save_outbox(&self.data_dir, &self.undelivered_dms);
+        Some(messages)
+    }
+
+    /// Forget the DMs waiting for a peer, returning how many there were
+    pub fn drop_undelivered_dms(&mut self, peer: &PeerId) -> usize {
+        let Some(messages) = self.undelivered_dms.remove(peer) else {
+            return 0;
+        };
+        storage::save_outbox(&self.data_dir, &self.undelivered_dms);
+        messages.len()
+    }
+
+    /// Peers with DMs waiting for them
+    pub fn undelivered_peers(&self) -> Vec<PeerId> {
+        self.undelivered_dms.keys().cloned().collect()
+    }
+
+    /// Whether to show a DM from a peer. Anyone can DM us during a trade, and contacts can
+    /// outside of one if we've allowed it.
+    pub fn accepts_dm_from(&self, peer: &PeerId) -> bool {
+        let peer_id_str = peer.to_string();
+        if self.incoming_trades.contains_key(&peer_id_str)
+            || self.outgoing_trades.contains_key(&peer_id_str)
+        {
+            return true;
+        }
+        self.nicknames.contacts().open_dms() && self.nicknames.contacts().contains(peer)
+    }
+
+    /// Change our nickname, moving the sequence on so peers prefer it over the old one
+    pub fn set_nickname(&mut self, nickname: String) {
+        self.nickname = nickname;
+        self.nickname_sequence = unix_millis().max(self.nickname_sequence + 1);
+    }
+
+    /// Our current nickname, signed for sending to peers
+    pub fn nickname_update(&self) -> Option<NicknameUpdate> {
+        match NicknameUpdate::new(&self.keypair, &self.nickname, self.nickname_sequence) {
+            Ok(update) => Some(update),
+            Err(e) => {
+                eprintln!("Failed to sign nickname: {e}");
+                None
+            }
+        }
+    }
+}
+
+/// The end of a peer's ID, added to nicknames so the same name can be told apart between peers
+pub fn nickname_suffix(peer_id: &PeerId) -> String {
+    let peer_id = peer_id.to_string();
+    peer_id[peer_id.len().saturating_sub(5)..].to_string()
+}
 
 /// Prompts the user for a nickname until it gets a valid one, then sets it with a confirmation message
 pub async fn prompt_for_nickname(
@@ -97,21 +427,539 @@ pub async fn prompt_for_nickname(
 
         match stdin.next_line().await {
             Ok(Some(line)) if !line.trim().is_empty() => {
-                nickname = line.trim().to_string();
-                is_valid = true;
+                match process_nickname(swarm, line.trim()) {
+                    Ok(processed) => {
+                        nickname = processed;
+                        is_valid = true;
+                    }
+                    Err(e) => println!("{e}"),
+                }
             }
             Ok(Some(_)) => println!("Nickname cannot be empty. Please enter a valid nickname."),
             Ok(None) => println!("No input received. Please try again."),
             Err(e) => println!("Error reading input: {e}. Please try again."),
         }
     }
-    let new_nickname = process_nickname(swarm, &nickname);
-    println!("Nickname set to '{}'", new_nickname);
-    new_nickname
+    println!("Nickname set to '{}'", nickname);
+    nickname
+}
+
+/// Adds our suffix to a nickname, failing if it would be too long for peers to accept
+pub fn process_nickname(
+    swarm: &mut libp2p::Swarm<SwapBytesBehaviour>,
+    nickname: &str,
+) -> Result<String, String> {
+    let suffix = nickname_suffix(swarm.local_peer_id());
+    let max_length = validation::MAX_NICKNAME_LENGTH - suffix.len() - 1;
+    if nickname.chars().count() > max_length {
+        return Err(format!("Nicknames can be at most {max_length} characters"));
+    }
+    Ok(nickname.to_owned() + "." + &suffix)
+}
+
+/// Tell everyone about our new nickname, directly to the peers we're connected to and over gossip
+/// for anyone further away
+pub fn broadcast_nickname(swarm: &mut Swarm<SwapBytesBehaviour>, chat_state: &ChatState) {
+    let Some(update) = chat_state.nickname_update() else {
+        return;
+    };
+
+    let peers: Vec<PeerId> = swarm.connected_peers().cloned().collect();
+    for peer in peers {
+        swarm
+            .behaviour_mut()
+            .nickname_update
+            .send_request(&peer, update.clone());
+    }
+
+    let bytes = match serde_cbor::to_vec(&update) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Failed to encode nickname: {e}");
+            return;
+        }
+    };
+    match swarm
+        .behaviour_mut()
+        .chat
+        .gossipsub
+        .publish(chat_state.nickname_topic.clone(), bytes)
+    {
+        Ok(_) | Err(gossipsub::PublishError::InsufficientPeers) => {}
+        Err(e) => eprintln!("Failed to announce nickname change: {e}"),
+    }
+}
+
+/// Send a DM, keeping hold of it until it's acknowledged so it can be sent again if it fails
+pub fn send_dm(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &mut ChatState,
+    peer_id: PeerId,
+    message: DirectMessage,
+) {
+    let request_id = swarm
+        .behaviour_mut()
+        .direct_message
+        .send_request(&peer_id, message.clone());
+    chat_state
+        .pending_dms
+        .insert(request_id, (peer_id, message));
+}
+
+/// Send a DM now if they're connected, otherwise keep it until they are (even after a restart).
+/// Returns true if it had to be kept.
+pub fn deliver_dm(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &mut ChatState,
+    peer_id: PeerId,
+    message: DirectMessage,
+) -> bool {
+    if swarm.is_connected(&peer_id) {
+        send_dm(swarm, chat_state, peer_id, message);
+        return false;
+    }
+    chat_state.queue_dm(peer_id, message);
+    let _ = swarm.dial(peer_id);
+    true
+}
+
+/// Start listening to a private group, or switch to its new key if we're already in it
+pub fn join_group(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &mut ChatState,
+    group: GroupKey,
+) -> Result<(), String> {
+    let topic = group.topic();
+    chat_state.groups.insert(group)?;
+    let gossipsub = &mut swarm.behaviour_mut().chat.gossipsub;
+    if let Err(e) = gossipsub.subscribe(&topic) {
+        return Err(format!("Failed to join the group's topic: {e}"));
+    }
+    let _ = gossipsub.set_topic_params(topic.clone(), validation::topic_score_params());
+    chat_state.gossip_validator.add_group(topic.hash());
+    Ok(())
+}
+
+/// Stop listening to a private group and forget its key
+pub fn leave_group(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &mut ChatState,
+    name: &str,
+) -> Option<GroupKey> {
+    let group = chat_state.groups.remove(name)?;
+    let topic = group.topic();
+    swarm.behaviour_mut().chat.gossipsub.unsubscribe(&topic);
+    chat_state.gossip_validator.remove_group(&topic.hash());
+    Some(group)
+}
+
+/// Announce on the DHT that we hold a file, so others can find every holder of a hash
+pub fn provide_file(swarm: &mut Swarm<SwapBytesBehaviour>, hash: &str) {
+    let key = kad::RecordKey::new(&format!("file::{}", hash));
+    if let Err(e) = swarm.behaviour_mut().kademlia.start_providing(key) {
+        eprintln!("Failed to announce file {hash}: {e}");
+    }
+}
+
+/// Send our half of an incoming trade. The trade stays pending until their file arrives back.
+pub fn accept_trade(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    peer_id: &PeerId,
+    trade_request: &TradeRequest,
+    file_store: &LocalFileStore,
+) -> Result<(), String> {
+    // Check the requested file exists. This should have already been checked, but just incase
+    let Some(requested_file) = file_store.get_file(&trade_request.requested_file) else {
+        return Err("The requested file doesn't exist. Something has gone wrong.".to_string());
+    };
+
+    // Fetch the metadata
+    let Some(metadata) = file_store.get_metadata(&trade_request.requested_file) else {
+        return Err("Failed to get the metadata of the requested file.".to_string());
+    };
+
+    let response = FileResponse {
+        file: requested_file,
+        metadata: metadata.clone(),
+    };
+
+    swarm
+        .behaviour_mut()
+        .file_transfer
+        .send_request(peer_id, Some(response));
+    Ok(())
+}
+
+/// Tell everyone listening that we've uploaded a file, using the signed metadata from its DHT record
+pub fn announce_file(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    chat_state: &ChatState,
+    hash: &str,
+    record: Vec<u8>,
+) {
+    let announcement = FileAnnouncement {
+        hash: hash.to_string(),
+        record,
+    };
+    let bytes = match serde_cbor::to_vec(&announcement) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Failed to encode announcement: {e}");
+            return;
+        }
+    };
+
+    match swarm
+        .behaviour_mut()
+        .chat
+        .gossipsub
+        .publish(chat_state.announcement_topic.clone(), bytes)
+    {
+        // Nobody to tell yet, they'll find it on the DHT instead
+        Ok(_) | Err(gossipsub::PublishError::InsufficientPeers) => {}
+        Err(e) => eprintln!("Failed to announce upload: {e}"),
+    }
+}
+
+/// Sign the metadata for one of our files and store it on the DHT under `file::<hash>`,
+/// returning the signed record so it can be announced as well
+pub fn publish_metadata(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    keypair: &Keypair,
+    metadata: &FileMetadata,
+) -> Result<Vec<u8>, String> {
+    let key = format!("file::{}", metadata.hash);
+    let record = records::signed_record(keypair, &key, metadata)
+        .map_err(|e| format!("Error signing metadata: {e}"))?;
+    let signed = record.value.clone();
+    swarm
+        .behaviour_mut()
+        .kademlia
+        .put_record(record, kad::Quorum::One)
+        .map_err(|e| format!("Error publishing metadata: {e}"))?;
+    Ok(signed)
 }
 
-/// Sets the nickname to the given value
-pub fn process_nickname(swarm: &mut libp2p::Swarm<SwapBytesBehaviour>, nickname: &str) -> String {
-    let peerid = swarm.local_peer_id().to_string();
-    nickname.to_owned() + "." + &peerid[47..]
+/// Update a set of what files we have on the DHT, makes it easier to query everyone's files.
+/// We also register as a publisher, so peers who aren't connected to us can still find the list.
+pub fn publish_file_index(
+    swarm: &mut Swarm<SwapBytesBehaviour>,
+    keypair: &Keypair,
+    file_store: &LocalFileStore,
+) {
+    if let Err(e) = swarm
+        .behaviour_mut()
+        .kademlia
+        .start_providing(kad::RecordKey::new(&PUBLISHERS_KEY))
+    {
+        eprintln!("Failed to register as a publisher: {e}");
+    }
+
+    let file_hashes = PeerList {
+        sequence: unix_millis(),
+        entries: file_store.all_hashes(),
+    };
+    let index_key = format!("file_index::{}", swarm.local_peer_id());
+    let record = match records::signed_record(keypair, &index_key, &file_hashes) {
+        Ok(record) => record,
+        Err(e) => {
+            eprintln!("Failed to sign file list: {e}");
+            return;
+        }
+    };
+
+    if swarm
+        .behaviour_mut()
+        .kademlia
+        .put_record(record, kad::Quorum::One)
+        .is_err()
+    {
+        eprintln!("Failed to update file list");
+    }
+}
+
+/// Publish our wishlist on the DHT so people know what we're after.
+/// Turning publishing off replaces it with an empty list.
+pub fn publish_wanted(swarm: &mut Swarm<SwapBytesBehaviour>, chat_state: &ChatState) {
+    let wants = PeerList {
+        sequence: unix_millis(),
+        entries: if chat_state.wanted.publish() {
+            chat_state.wanted.wants().to_vec()
+        } else {
+            Vec::new()
+        },
+    };
+    let key = format!("wanted::{}", swarm.local_peer_id());
+    let record = match records::signed_record(&chat_state.keypair, &key, &wants) {
+        Ok(record) => record,
+        Err(e) => {
+            eprintln!("Failed to sign wanted list: {e}");
+            return;
+        }
+    };
+
+    if swarm
+        .behaviour_mut()
+        .kademlia
+        .put_record(record, kad::Quorum::One)
+        .is_err()
+    {
+        eprintln!("Failed to publish wanted list");
+    }
+}
+
+/// Leave the network cleanly: turn down trades and gifts waiting on us, tell the people we've
+/// offered trades or gifts to that we're going, unregister from rendezvous and save what we know.
+/// The swarm is driven for a few seconds so those messages actually get sent.
+pub async fn shutdown(swarm: &mut Swarm<SwapBytesBehaviour>, chat_state: &mut ChatState) {
+    println!("Shutting down...");
+    let mut pending = 0;
+
+    for (peer_id_str, trade) in chat_state.incoming_trades.drain() {
+        let Ok(peer_id) = peer_id_str.parse::<PeerId>() else {
+            continue;
+        };
+        swarm
+            .behaviour_mut()
+            .file_transfer
+            .send_request(&peer_id, None);
+        pending += 1;
+        println!("Declined {}'s trade request", trade.nickname);
+    }
+
+    for (peer_id_str, trade) in chat_state.outgoing_trades.drain() {
+        let Ok(peer_id) = peer_id_str.parse::<PeerId>() else {
+            continue;
+        };
+        swarm.behaviour_mut().direct_message.send_request(
+            &peer_id,
+            DirectMessage {
+                sender_nickname: chat_state.nickname.clone(),
+                message: format!(
+                    "I've left, so my offer of '{}' is cancelled",
+                    trade.offered_file.filename
+                ),
+                group: None,
+            },
+        );
+        pending += 1;
+        println!("Cancelled your trade request with {}", trade.nickname);
+    }
+
+    for (peer_id_str, hash) in chat_state.outgoing_gifts.drain() {
+        let Ok(peer_id) = peer_id_str.parse::<PeerId>() else {
+            continue;
+        };
+        swarm.behaviour_mut().direct_message.send_request(
+            &peer_id,
+            DirectMessage {
+                sender_nickname: chat_state.nickname.clone(),
+                message: format!("I've left, so my gift of {hash} is withdrawn"),
+                group: None,
+            },
+        );
+        pending += 1;
+        println!(
+            "Withdrew your gift to {}",
+            chat_state.nicknames.get(&peer_id_str)
+        );
+    }
+
+    for (peer_id_str, gift) in chat_state.incoming_gifts.drain() {
+        let Ok(peer_id) = peer_id_str.parse::<PeerId>() else {
+            continue;
+        };
+        swarm
+            .behaviour_mut()
+            .gift
+            .send_request(&peer_id, GiftMessage::Decline { hash: gift.hash });
+        pending += 1;
+    }
+
+    if swarm.is_connected(&chat_state.rendezvous) {
+        swarm.behaviour_mut().rendezvous.rendezvous.unregister(
+            rendezvous::Namespace::from_static("rendezvous"),
+            chat_state.rendezvous,
+        );
+    }
+    swarm.behaviour_mut().kademlia.store_mut().flush();
+    storage::save_address_book(swarm, &chat_state.data_dir);
+
+    // Give the messages above a chance to be delivered
+    let _ = tokio::time::timeout(Duration::from_secs(3), async {
+        while pending > 0 || !chat_state.pending_dms.is_empty() {
+            match swarm.select_next_some().await {
+                SwarmEvent::Behaviour(SwapBytesBehaviourEvent::FileTransfer(
+                    request_response::Event::Message {
+                        message: Message::Response { .. },
+                        ..
+                    }
+                    | request_response::Event::OutboundFailure { .. },
+                ))
+                | SwarmEvent::Behaviour(SwapBytesBehaviourEvent::Gift(
+                    request_response::Event::Message {
+                        message: Message::Response { .. },
+                        ..
+                    }
+                    | request_response::Event::OutboundFailure { .. },
+                )) => pending -= 1,
+                // Our own DMs are tracked in pending_dms, any others are the cancellations above.
+                // Ours that fail are kept for next time rather than lost.
+                SwarmEvent::Behaviour(SwapBytesBehaviourEvent::DirectMessage(event)) => {
+                    let (request_id, failed) = match event {
+                        request_response::Event::Message {
+                            message: Message::Response { request_id, .. },
+                            ..
+                        } => (request_id, false),
+                        request_response::Event::OutboundFailure { request_id, .. } => {
+                            (request_id, true)
+                        }
+                        _ => continue,
+                    };
+                    match chat_state.pending_dms.remove(&request_id) {
+                        Some((peer_id, message)) if failed => chat_state.queue_dm(peer_id, message),
+                        Some(_) => {}
+                        None => pending -= 1,
+                    }
+                }
+                _ => {}
+            }
+        }
+    })
+    .await;
+
+    // DMs that still haven't gone through are kept for next time
+    let unsent: Vec<(PeerId, DirectMessage)> =
+        chat_state.pending_dms.drain().map(|(_, dm)| dm).collect();
+    for (peer_id, message) in unsent {
+        chat_state.queue_dm(peer_id, message);
+    }
+
+    // Then hang up on everyone
+    let peers: Vec<PeerId> = swarm.connected_peers().cloned().collect();
+    for peer in peers {
+        let _ = swarm.disconnect_peer_id(peer);
+    }
+    let _ = tokio::time::timeout(Duration::from_secs(1), async {
+        while swarm.connected_peers().next().is_some() {
+            swarm.select_next_some().await;
+        }
+    })
+    .await;
+    println!("Goodbye!");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// A nickname map with its contact book in a temporary directory, removed when it's dropped
+    fn nickname_map() -> (TempDir, NicknameMap) {
+        let dir = TempDir::new().unwrap();
+        let nicknames = NicknameMap::new(ContactBook::load(dir.path()));
+        (dir, nicknames)
+    }
+
+    #[test]
+    fn petname_wins_over_nickname() {
+        let (_dir, mut nicknames) = nickname_map();
+        let (friend, other) = (PeerId::random(), PeerId::random());
+        nicknames.insert(other, "sam".to_string());
+        nicknames
+            .contacts_mut()
+            .set_petname(friend, Some("sam".to_string()))
+            .unwrap();
+        assert_eq!(nicknames.lookup("sam"), vec![friend.to_string()]);
+    }
+
+    #[test]
+    fn peer_id_finds_itself() {
+        let (_dir, nicknames) = nickname_map();
+        let peer = PeerId::random().to_string();
+        assert_eq!(nicknames.lookup(&peer), vec![peer]);
+    }
+
+    #[test]
+    fn shared_nickname_prefers_contacts() {
+        let (_dir, mut nicknames) = nickname_map();
+        let (first, second) = (PeerId::random(), PeerId::random());
+        nicknames.insert(first, "sam".to_string());
+        nicknames.insert(second, "sam".to_string());
+
+        let mut both = vec![first.to_string(), second.to_string()];
+        both.sort();
+        assert_eq!(nicknames.lookup("sam"), both);
+
+        nicknames.contacts_mut().set_friend(second, true);
+        assert_eq!(nicknames.lookup("sam"), vec![second.to_string()]);
+    }
+
+    #[test]
+    fn old_nickname_finds_renamed_peer() {
+        let (_dir, mut nicknames) = nickname_map();
+        let peer = PeerId::random();
+        nicknames.insert(peer, "sam".to_string());
+        nicknames.insert(peer, "samuel".to_string());
+        assert_eq!(nicknames.lookup("sam"), vec![peer.to_string()]);
+        assert!(nicknames.lookup("nobody").is_empty());
+    }
+
+    /// A nickname with the right suffix for the peer using it
+    fn nickname_for(peer: &PeerId, name: &str) -> String {
+        format!("{name}.{}", nickname_suffix(peer))
+    }
+
+    #[test]
+    fn warns_when_the_suffix_doesnt_match() {
+        let (_dir, mut nicknames) = nickname_map();
+        let (peer, other) = (PeerId::random(), PeerId::random());
+        assert_eq!(nicknames.insert(peer, nickname_for(&peer, "sam")), None);
+        assert!(nicknames
+            .insert(peer, nickname_for(&other, "sam"))
+            .is_some());
+    }
+
+    #[test]
+    fn claims_must_be_signed_by_their_peer() {
+        let (_dir, mut nicknames) = nickname_map();
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().to_peer_id();
+        let nickname = nickname_for(&peer, "sam");
+        let claim = NicknameUpdate::new(&keypair, &nickname, 1).unwrap();
+
+        assert!(nicknames
+            .verify_claim(PeerId::random(), claim.clone())
+            .is_err());
+        assert!(!nicknames.is_signed(&peer));
+        assert_eq!(nicknames.verify_claim(peer, claim), Ok(nickname));
+        assert!(nicknames.is_signed(&peer));
+    }
+
+    #[test]
+    fn older_claims_are_rejected() {
+        let (_dir, mut nicknames) = nickname_map();
+        let keypair = Keypair::generate_ed25519();
+        let peer = keypair.public().to_peer_id();
+        let old = NicknameUpdate::new(&keypair, &nickname_for(&peer, "sam"), 1).unwrap();
+        let new = NicknameUpdate::new(&keypair, &nickname_for(&peer, "samuel"), 2).unwrap();
+
+        assert!(nicknames.verify_claim(peer, new.clone()).is_ok());
+        assert!(nicknames.verify_claim(peer, old).is_err());
+        assert!(nicknames.verify_claim(peer, new).is_ok());
+    }
+
+    #[test]
+    fn renames_are_kept_in_the_history() {
+        let (_dir, mut nicknames) = nickname_map();
+        let peer = PeerId::random();
+        nicknames.insert(peer, nickname_for(&peer, "sam"));
+        nicknames.insert(peer, nickname_for(&peer, "sam"));
+        nicknames.insert(peer, nickname_for(&peer, "samuel"));
+        assert_eq!(
+            nicknames.current(&peer),
+            Some(nickname_for(&peer, "samuel").as_str())
+        );
+        assert_eq!(nicknames.history(&peer), [nickname_for(&peer, "sam")]);
+    }
 }
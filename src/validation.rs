@@ -0,0 +1,550 @@
+use libp2p::{
+    gossipsub::{self, IdentTopic, MessageAcceptance, TopicHash},
+    PeerId,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::contacts::BlockList;
+use crate::files::FileAnnouncement;
+use crate::groups::GroupEnvelope;
+use crate::input::ChatMessage;
+use crate::records;
+use crate::utils::NicknameUpdate;
+
+pub const CHAT_TOPIC: &str = "chat";
+pub const ANNOUNCEMENT_TOPIC: &str = "announcements";
+pub const NICKNAME_TOPIC: &str = "nicknames";
+/// Not a real topic name, private groups each have their own topic that's checked the same way
+const GROUP_TOPIC: &str = "group";
+
+/// Uploads can't have names or descriptions longer than this, in characters
+pub const MAX_FILENAME_LENGTH: usize = 255;
+pub const MAX_DESCRIPTION_LENGTH: usize = 500;
+
+/// Gossipsub won't send or accept anything bigger than this. Announcements are the biggest thing
+/// we gossip: signed metadata holding a filename and description of up to 4 bytes a character,
+/// plus well under 1KiB of hashes, IDs, key and signature. The metadata is nested in two byte
+/// arrays, and CBOR can double the size of each.
+pub const MAX_TRANSMIT_SIZE: usize =
+    4 * (4 * (MAX_FILENAME_LENGTH + MAX_DESCRIPTION_LENGTH) + 1024);
+
+/// Chat is text only, so much less is allowed there
+const MAX_CHAT_SIZE: usize = 4 * 1024;
+pub const MAX_CHAT_LENGTH: usize = 1000;
+pub const MAX_NICKNAME_LENGTH: usize = 64;
+const MAX_MENTIONS: usize = 20;
+/// Room for the nonce, tag, key epoch and field names around an encrypted chat message
+const GROUP_OVERHEAD: usize = 64;
+/// Poly1305 tag added to every encrypted message
+const GROUP_TAG_SIZE: usize = 16;
+
+/// How often the rate limit records are swept for authors who've gone quiet
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How often each author can post to a topic before the rest are dropped
+struct RateLimit {
+    messages: usize,
+    window: Duration,
+}
+
+fn rate_limit(topic: &str) -> RateLimit {
+    match topic {
+        CHAT_TOPIC | GROUP_TOPIC => RateLimit {
+            messages: 5,
+            window: Duration::from_secs(10),
+        },
+        ANNOUNCEMENT_TOPIC => RateLimit {
+            messages: 20,
+            window: Duration::from_secs(60),
+        },
+        _ => RateLimit {
+            messages: 3,
+            window: Duration::from_secs(60),
+        },
+    }
+}
+
+/// Why a gossip message wasn't passed on
+pub enum Rejection {
+    /// Malformed or forged, whoever sent it gets penalised
+    Invalid(String),
+    /// The author is posting too fast. `first` is set for the first drop in a burst, so it's only
+    /// reported once.
+    RateLimited { first: bool },
+    /// From someone the user blocked
+    Blocked,
+    /// On a topic we don't know or have left, say a group we were just removed from. Whoever
+    /// forwarded it may still be in the group, so it isn't their fault.
+    UnknownTopic,
+}
+
+impl Rejection {
+    /// What to tell gossipsub. Messages over the rate limit are still valid, and peers don't
+    /// share our view of the limit, so they're ignored rather than counted against the sender.
+    pub fn acceptance(&self) -> MessageAcceptance {
+        match self {
+            Rejection::Invalid(_) => MessageAcceptance::Reject,
+            Rejection::RateLimited { .. } | Rejection::Blocked | Rejection::UnknownTopic => {
+                MessageAcceptance::Ignore
+            }
+        }
+    }
+}
+
+/// Checks every gossip message before it's shown or forwarded
+pub struct GossipValidator {
+    topics: HashMap<TopicHash, &'static str>,
+    /// When each author's recent messages on each topic arrived
+    recent: HashMap<(PeerId, TopicHash), VecDeque<Instant>>,
+    /// Authors currently being rate limited on each topic
+    limited: HashSet<(PeerId, TopicHash)>,
+    last_pruned: Instant,
+}
+
+impl GossipValidator {
+    pub fn new() -> Self {
+        GossipValidator {
+            topics: [CHAT_TOPIC, ANNOUNCEMENT_TOPIC, NICKNAME_TOPIC]
+                .into_iter()
+                .map(|topic| (IdentTopic::new(topic).hash(), topic))
+                .collect(),
+            recent: HashMap::new(),
+            limited: HashSet::new(),
+            last_pruned: Instant::now(),
+        }
+    }
+
+    /// Start accepting messages on a private group's topic
+    pub fn add_group(&mut self, topic: TopicHash) {
+        self.topics.insert(topic, GROUP_TOPIC);
+    }
+
+    pub fn remove_group(&mut self, topic: &TopicHash) {
+        self.topics.remove(topic);
+    }
+
+    pub fn check(
+        &mut self,
+        message: &gossipsub::Message,
+        blocked: &BlockList,
+    ) -> Result<(), Rejection> {
+        let Some(author) = message.source else {
+            return Err(Rejection::Invalid("message isn't signed".to_string()));
+        };
+        let Some(topic) = self.topics.get(&message.topic).copied() else {
+            return Err(Rejection::UnknownTopic);
+        };
+        if blocked.contains(&author) {
+            return Err(Rejection::Blocked);
+        }
+
+        check_payload(topic, author, &message.data).map_err(Rejection::Invalid)?;
+        self.prune();
+        self.check_rate(author, topic, &message.topic)
+    }
+
+    /// Check something we're about to publish is within the limits everyone else applies, so it
+    /// isn't dropped on the way
+    pub fn check_sending(
+        &self,
+        author: PeerId,
+        topic: &TopicHash,
+        data: &[u8],
+    ) -> Result<(), String> {
+        let Some(name) = self.topics.get(topic).copied() else {
+            return Err(format!("unknown topic {topic}"));
+        };
+        check_payload(name, author, data)
+    }
+
+    /// Count a message we're about to publish towards our rate limit, failing with the limit if
+    /// everyone else would drop it
+    pub fn check_sending_rate(&mut self, author: PeerId, topic: &TopicHash) -> Result<(), String> {
+        let Some(name) = self.topics.get(topic).copied() else {
+            return Err(format!("unknown topic {topic}"));
+        };
+        self.check_rate(author, name, topic).map_err(|_| {
+            let limit = rate_limit(name);
+            format!(
+                "peers accept at most {} every {} seconds",
+                limit.messages,
+                limit.window.as_secs()
+            )
+        })
+    }
+
+    /// Forget authors who haven't posted within their topic's window
+    fn prune(&mut self) {
+        let now = Instant::now();
+        if now.duration_since(self.last_pruned) < PRUNE_INTERVAL {
+            return;
+        }
+        self.last_pruned = now;
+        let topics = &self.topics;
+        self.recent.retain(|(_, topic), sent| {
+            let window = rate_limit(topics.get(topic).copied().unwrap_or_default()).window;
+            sent.retain(|sent| now.duration_since(*sent) <= window);
+            !sent.is_empty()
+        });
+        let recent = &self.recent;
+        self.limited.retain(|key| recent.contains_key(key));
+    }
+
+    /// Note another message from the author, failing if they've sent too many lately
+    fn check_rate(
+        &mut self,
+        author: PeerId,
+        topic: &str,
+        topic_hash: &TopicHash,
+    ) -> Result<(), Rejection> {
+        let limit = rate_limit(topic);
+        let now = Instant::now();
+        let key = (author, topic_hash.clone());
+        let recent = self.recent.entry(key.clone()).or_default();
+        while recent
+            .front()
+            .is_some_and(|sent| now.duration_since(*sent) > limit.window)
+        {
+            recent.pop_front();
+        }
+
+        if recent.len() >= limit.messages {
+            let first = self.limited.insert(key);
+            return Err(Rejection::RateLimited { first });
+        }
+        recent.push_back(now);
+        self.limited.remove(&key);
+        Ok(())
+    }
+}
+
+/// Check a message decodes as what its topic carries, and stays within that topic's limits
+fn check_payload(topic: &str, author: PeerId, data: &[u8]) -> Result<(), String> {
+    match topic {
+        CHAT_TOPIC => check_chat(data).map(|_| ()),
+
+        // Only members can read these, so the chat message inside is checked once it's decrypted
+        GROUP_TOPIC => {
+            if data.len() > MAX_CHAT_SIZE + GROUP_OVERHEAD {
+                return Err(format!("group message is {} bytes", data.len()));
+            }
+            let envelope = serde_cbor::from_slice::<GroupEnvelope>(data)
+                .map_err(|e| format!("not a group message: {e}"))?;
+            if envelope.ciphertext.len() > MAX_CHAT_SIZE + GROUP_TAG_SIZE {
+                return Err(format!(
+                    "group message holds {} bytes",
+                    envelope.ciphertext.len()
+                ));
+            }
+            Ok(())
+        }
+
+        ANNOUNCEMENT_TOPIC => {
+            let announcement = serde_cbor::from_slice::<FileAnnouncement>(data)
+                .map_err(|e| format!("not a file announcement: {e}"))?;
+            let key = format!("file::{}", announcement.hash);
+            let (_, metadata) = records::open_file_metadata(&key, &announcement.record)?;
+            check_upload(&metadata.filename, metadata.description.as_deref())
+        }
+
+        NICKNAME_TOPIC => {
+            let claim = serde_cbor::from_slice::<NicknameUpdate>(data)
+                .map_err(|e| format!("not a nickname claim: {e}"))?;
+            let (signer, claim) = claim.open()?;
+            if signer != author {
+                return Err(format!("nickname claim was signed by {signer}"));
+            }
+            if claim.nickname.chars().count() > MAX_NICKNAME_LENGTH {
+                return Err("nickname is too long".to_string());
+            }
+            Ok(())
+        }
+
+        _ => Err(format!("unknown topic {topic}")),
+    }
+}
+
+/// Check an upload's name and description are short enough to be announced
+pub fn check_upload(filename: &str, description: Option<&str>) -> Result<(), String> {
+    if filename.chars().count() > MAX_FILENAME_LENGTH {
+        return Err(format!(
+            "filename is longer than {MAX_FILENAME_LENGTH} characters"
+        ));
+    }
+    if description.is_some_and(|description| description.chars().count() > MAX_DESCRIPTION_LENGTH) {
+        return Err(format!(
+            "description is longer than {MAX_DESCRIPTION_LENGTH} characters"
+        ));
+    }
+    Ok(())
+}
+
+/// Decode a chat message, checking it stays within the chat limits
+pub fn check_chat(data: &[u8]) -> Result<ChatMessage, String> {
+    if data.len() > MAX_CHAT_SIZE {
+        return Err(format!(
+            "chat message is {} bytes, more than {MAX_CHAT_SIZE}",
+            data.len()
+        ));
+    }
+    let chat = serde_cbor::from_slice::<ChatMessage>(data)
+        .map_err(|e| format!("not a chat message: {e}"))?;
+    if chat.message.chars().count() > MAX_CHAT_LENGTH {
+        return Err(format!(
+            "chat message is longer than {MAX_CHAT_LENGTH} characters"
+        ));
+    }
+    if chat.nickname.chars().count() > MAX_NICKNAME_LENGTH {
+        return Err(format!(
+            "nickname is longer than {MAX_NICKNAME_LENGTH} characters"
+        ));
+    }
+    if chat.mentions.len() > MAX_MENTIONS {
+        return Err(format!("more than {MAX_MENTIONS} mentions"));
+    }
+    Ok(chat)
+}
+
+/// Peer scoring for our topics. Peers sending invalid messages lose score quickly, and are
+/// graylisted (everything they send is ignored) after a handful. Chat is quiet, so peers aren't
+/// penalised for not delivering enough messages.
+pub fn peer_score_params() -> (gossipsub::PeerScoreParams, gossipsub::PeerScoreThresholds) {
+    let mut params = gossipsub::PeerScoreParams {
+        // Several peers on one machine or behind one router is normal for us
+        ip_colocation_factor_weight: 0.0,
+        ..Default::default()
+    };
+    for topic in [CHAT_TOPIC, ANNOUNCEMENT_TOPIC, NICKNAME_TOPIC] {
+        params
+            .topics
+            .insert(IdentTopic::new(topic).hash(), topic_score_params());
+    }
+    (params, gossipsub::PeerScoreThresholds::default())
+}
+
+/// Scoring for one topic, private groups are set up with it as they're joined
+pub fn topic_score_params() -> gossipsub::TopicScoreParams {
+    gossipsub::TopicScoreParams {
+        topic_weight: 1.0,
+        // A small reward for staying in the mesh and for new messages, capped well below the
+        // penalty for a few invalid ones
+        time_in_mesh_weight: 0.01,
+        time_in_mesh_quantum: Duration::from_secs(1),
+        time_in_mesh_cap: 1000.0,
+        first_message_deliveries_weight: 0.5,
+        first_message_deliveries_cap: 20.0,
+        mesh_message_deliveries_weight: 0.0,
+        mesh_failure_penalty_weight: 0.0,
+        invalid_message_deliveries_weight: -10.0,
+        invalid_message_deliveries_decay: 0.5,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::files::FileMetadata;
+    use crate::groups::GroupKey;
+    use libp2p::identity::Keypair;
+    use tempfile::TempDir;
+
+    fn chat_topic() -> TopicHash {
+        IdentTopic::new(CHAT_TOPIC).hash()
+    }
+
+    fn message(author: PeerId, topic: TopicHash) -> gossipsub::Message {
+        gossipsub::Message {
+            source: Some(author),
+            data: Vec::new(),
+            sequence_number: None,
+            topic,
+        }
+    }
+
+    #[test]
+    fn rate_limit_drops_messages_over_the_limit() {
+        let mut validator = GossipValidator::new();
+        let author = PeerId::random();
+        let limit = rate_limit(CHAT_TOPIC);
+        for _ in 0..limit.messages {
+            assert!(validator
+                .check_rate(author, CHAT_TOPIC, &chat_topic())
+                .is_ok());
+        }
+        assert!(matches!(
+            validator.check_rate(author, CHAT_TOPIC, &chat_topic()),
+            Err(Rejection::RateLimited { first: true })
+        ));
+        assert!(matches!(
+            validator.check_rate(author, CHAT_TOPIC, &chat_topic()),
+            Err(Rejection::RateLimited { first: false })
+        ));
+    }
+
+    #[test]
+    fn rate_limit_is_per_author() {
+        let mut validator = GossipValidator::new();
+        let (busy, quiet) = (PeerId::random(), PeerId::random());
+        for _ in 0..rate_limit(CHAT_TOPIC).messages {
+            let _ = validator.check_rate(busy, CHAT_TOPIC, &chat_topic());
+        }
+        assert!(validator
+            .check_rate(busy, CHAT_TOPIC, &chat_topic())
+            .is_err());
+        assert!(validator
+            .check_rate(quiet, CHAT_TOPIC, &chat_topic())
+            .is_ok());
+    }
+
+    #[test]
+    fn rate_limit_is_per_topic() {
+        let mut validator = GossipValidator::new();
+        let author = PeerId::random();
+        let announcements = IdentTopic::new(ANNOUNCEMENT_TOPIC).hash();
+        for _ in 0..=rate_limit(CHAT_TOPIC).messages {
+            let _ = validator.check_rate(author, CHAT_TOPIC, &chat_topic());
+        }
+        assert!(matches!(
+            validator.check_rate(author, ANNOUNCEMENT_TOPIC, &announcements),
+            Ok(())
+        ));
+
+        // Going quiet on one topic doesn't reset the other
+        let limit = rate_limit(ANNOUNCEMENT_TOPIC).messages;
+        for _ in 1..=limit {
+            let _ = validator.check_rate(author, ANNOUNCEMENT_TOPIC, &announcements);
+        }
+        assert!(matches!(
+            validator.check_rate(author, ANNOUNCEMENT_TOPIC, &announcements),
+            Err(Rejection::RateLimited { first: false })
+        ));
+        assert!(matches!(
+            validator.check_rate(author, CHAT_TOPIC, &chat_topic()),
+            Err(Rejection::RateLimited { first: false })
+        ));
+    }
+
+    #[test]
+    fn quiet_authors_are_forgotten() {
+        let mut validator = GossipValidator::new();
+        let author = PeerId::random();
+        for _ in 0..=rate_limit(CHAT_TOPIC).messages {
+            let _ = validator.check_rate(author, CHAT_TOPIC, &chat_topic());
+        }
+        assert!(validator.limited.contains(&(author, chat_topic())));
+
+        // Pretend it all happened a while ago
+        let long_ago = Instant::now() - 2 * PRUNE_INTERVAL;
+        for sent in validator.recent.values_mut().flatten() {
+            *sent = long_ago;
+        }
+        validator.last_pruned = long_ago;
+        validator.prune();
+        assert!(validator.recent.is_empty());
+        assert!(validator.limited.is_empty());
+    }
+
+    #[test]
+    fn largest_group_message_fits() {
+        let group = GroupKey::generate("friends".to_string(), PeerId::random());
+        let envelope = group.encrypt(&[0xff; MAX_CHAT_SIZE]).unwrap();
+        let data = serde_cbor::to_vec(&envelope).unwrap();
+        assert!(check_payload(GROUP_TOPIC, PeerId::random(), &data).is_ok());
+
+        let too_big = group.encrypt(&[0xff; MAX_CHAT_SIZE + 1]).unwrap();
+        let data = serde_cbor::to_vec(&too_big).unwrap();
+        assert!(check_payload(GROUP_TOPIC, PeerId::random(), &data).is_err());
+    }
+
+    #[test]
+    fn largest_announcement_fits() {
+        let keypair = Keypair::generate_ed25519();
+        let owner = keypair.public().to_peer_id();
+        let hash = "f".repeat(64);
+        // Four bytes a character in UTF-8
+        let metadata = FileMetadata {
+            filename: "𝄞".repeat(MAX_FILENAME_LENGTH),
+            owner: owner.to_string(),
+            description: Some("𝄞".repeat(MAX_DESCRIPTION_LENGTH)),
+            hash: hash.clone(),
+            size: usize::MAX,
+            chunk_count: usize::MAX,
+            merkle_root: "f".repeat(64),
+            free: true,
+        };
+        let key = format!("file::{hash}");
+        let record = records::signed_record(&keypair, &key, &metadata).unwrap();
+        let announcement = FileAnnouncement {
+            hash,
+            record: record.value,
+        };
+        let data = serde_cbor::to_vec(&announcement).unwrap();
+        assert!(check_payload(ANNOUNCEMENT_TOPIC, owner, &data).is_ok());
+        // Leaving room for gossipsub's own envelope
+        assert!(
+            data.len() + 1024 <= MAX_TRANSMIT_SIZE,
+            "{} bytes",
+            data.len()
+        );
+    }
+
+    #[test]
+    fn malformed_payloads_are_invalid() {
+        let dir = TempDir::new().unwrap();
+        let blocked = BlockList::load(dir.path());
+        let mut validator = GossipValidator::new();
+        let mut malformed = message(PeerId::random(), chat_topic());
+        malformed.data = b"not cbor".to_vec();
+        let rejection = validator.check(&malformed, &blocked).unwrap_err();
+        assert!(matches!(rejection, Rejection::Invalid(_)));
+        assert!(matches!(rejection.acceptance(), MessageAcceptance::Reject));
+
+        let author = PeerId::random();
+        assert!(check_payload(CHAT_TOPIC, author, b"not cbor").is_err());
+        assert!(check_payload(CHAT_TOPIC, author, &[0; MAX_CHAT_SIZE + 1]).is_err());
+        assert!(check_payload(ANNOUNCEMENT_TOPIC, author, b"not cbor").is_err());
+    }
+
+    #[test]
+    fn nickname_claims_must_come_from_their_author() {
+        let keypair = Keypair::generate_ed25519();
+        let claim = NicknameUpdate::new(&keypair, "sam", 1).unwrap();
+        let data = serde_cbor::to_vec(&claim).unwrap();
+        let author = keypair.public().to_peer_id();
+        assert!(check_payload(NICKNAME_TOPIC, author, &data).is_ok());
+        assert!(check_payload(NICKNAME_TOPIC, PeerId::random(), &data).is_err());
+    }
+
+    #[test]
+    fn blocked_authors_and_unknown_topics_are_ignored() {
+        let dir = TempDir::new().unwrap();
+        let mut blocked = BlockList::load(dir.path());
+        let mut validator = GossipValidator::new();
+        let (friend, eve) = (PeerId::random(), PeerId::random());
+        blocked.block(eve);
+
+        let elsewhere = message(friend, IdentTopic::new("elsewhere").hash());
+        let rejection = validator.check(&elsewhere, &blocked).unwrap_err();
+        assert!(matches!(rejection, Rejection::UnknownTopic));
+        assert!(matches!(rejection.acceptance(), MessageAcceptance::Ignore));
+
+        // A group we've just left is unknown too
+        let group = GroupKey::generate("friends".to_string(), PeerId::random());
+        validator.add_group(group.topic().hash());
+        validator.remove_group(&group.topic().hash());
+        let left = message(friend, group.topic().hash());
+        assert!(matches!(
+            validator.check(&left, &blocked),
+            Err(Rejection::UnknownTopic)
+        ));
+
+        let from_eve = message(eve, chat_topic());
+        let rejection = validator.check(&from_eve, &blocked).unwrap_err();
+        assert!(matches!(rejection, Rejection::Blocked));
+        assert!(matches!(rejection.acceptance(), MessageAcceptance::Ignore));
+    }
+}
@@ -0,0 +1,251 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use crate::files::{FileMetadata, LocalFileStore};
+use crate::storage::{read_cbor, write_cbor};
+
+/// Something we're hunting for, either an exact file or a set of search terms
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Want {
+    Hash(String),
+    Terms(Vec<String>),
+}
+
+impl Want {
+    /// Hashes are 8 hex characters, anything else is treated as search terms
+    pub fn parse(input: &str) -> Option<Self> {
+        let input = input.trim();
+        if input.len() == 8 && input.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Some(Want::Hash(input.to_lowercase()));
+        }
+
+        let terms: Vec<String> = input.split_whitespace().map(str::to_lowercase).collect();
+        if terms.is_empty() {
+            None
+        } else {
+            Some(Want::Terms(terms))
+        }
+    }
+
+    /// A file matches if it has the wanted hash, or its name and description contain every term
+    pub fn matches(&self, metadata: &FileMetadata) -> bool {
+        match self {
+            Want::Hash(hash) => metadata.hash == *hash,
+            Want::Terms(terms) => {
+                let haystack = format!(
+                    "{} {}",
+                    metadata.filename,
+                    metadata.description.as_deref().unwrap_or_default()
+                )
+                .to_lowercase();
+                terms.iter().all(|term| haystack.contains(term))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Want {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Want::Hash(hash) => write!(f, "file {hash}"),
+            Want::Terms(terms) => write!(f, "\"{}\"", terms.join(" ")),
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct SavedWants {
+    wants: Vec<Want>,
+    publish: bool,
+}
+
+/// Our wishlist, saved between sessions and optionally published on the DHT
+pub struct WantedList {
+    wants: Vec<Want>,
+    publish: bool,
+    path: PathBuf,
+    /// Files we've already told the user about this session
+    notified: HashSet<String>,
+}
+
+impl WantedList {
+    pub fn load(data_dir: &Path) -> Self {
+        let path = data_dir.join("wanted.cbor");
+        let saved = read_cbor::<SavedWants>(&path).unwrap_or_default();
+        WantedList {
+            wants: saved.wants,
+            publish: saved.publish,
+            path,
+            notified: HashSet::new(),
+        }
+    }
+
+    fn save(&self) {
+        let saved = SavedWants {
+            wants: self.wants.clone(),
+            publish: self.publish,
+        };
+        write_cbor(&self.path, &saved);
+    }
+
+    pub fn wants(&self) -> &[Want] {
+        &self.wants
+    }
+
+    pub fn publish(&self) -> bool {
+        self.publish
+    }
+
+    pub fn set_publish(&mut self, publish: bool) {
+        self.publish = publish;
+        self.save();
+    }
+
+    /// Add to the wishlist, false if it's already on there
+    pub fn add(&mut self, want: Want) -> bool {
+        if self.wants.contains(&want) {
+            return false;
+        }
+        self.wants.push(want);
+        self.save();
+        true
+    }
+
+    /// Remove by its position in the list (starting at 1, as shown by /wants)
+    pub fn remove(&mut self, position: usize) -> Option<Want> {
+        if position == 0 || position > self.wants.len() {
+            return None;
+        }
+        let want = self.wants.remove(position - 1);
+        self.save();
+        Some(want)
+    }
+
+    /// The want a newly seen file satisfies, if we haven't already been told about it
+    pub fn check(&mut self, metadata: &FileMetadata) -> Option<Want> {
+        if self.notified.contains(&metadata.hash) {
+            return None;
+        }
+        let want = self
+            .wants
+            .iter()
+            .find(|want| want.matches(metadata))?
+            .clone();
+        self.notified.insert(metadata.hash.clone());
+        Some(want)
+    }
+}
+
+/// Pick which of our files to offer someone for their file.
+/// Files on their wishlist come first, otherwise the ones closest in size to what we're getting.
+pub fn suggest_offers<'a>(
+    file_store: &'a LocalFileStore,
+    their_wants: &[Want],
+    wanted_file: &FileMetadata,
+) -> Vec<&'a FileMetadata> {
+    let mut ours: Vec<&FileMetadata> = file_store
+        .all_hashes()
+        .iter()
+        .filter_map(|hash| file_store.get_metadata(hash))
+        .collect();
+
+    let wanted_by_them: Vec<&FileMetadata> = ours
+        .iter()
+        .filter(|metadata| their_wants.iter().any(|want| want.matches(metadata)))
+        .copied()
+        .collect();
+    if !wanted_by_them.is_empty() {
+        return wanted_by_them;
+    }
+
+    ours.sort_by_key(|metadata| metadata.size.abs_diff(wanted_file.size));
+    ours.truncate(3);
+    ours
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::PeerId;
+    use tempfile::TempDir;
+
+    fn metadata(store: &mut LocalFileStore, size: usize, filename: &str) -> FileMetadata {
+        let data = vec![size as u8; size];
+        let hash = store.add_file(data, filename, &PeerId::random(), Some("Live set".into()));
+        store.get_metadata(&hash).unwrap().clone()
+    }
+
+    #[test]
+    fn parses_hashes_and_terms() {
+        assert_eq!(Want::parse("ABCD1234"), Some(Want::Hash("abcd1234".into())));
+        assert_eq!(
+            Want::parse(" Jazz  Live "),
+            Some(Want::Terms(vec!["jazz".into(), "live".into()]))
+        );
+        assert_eq!(Want::parse("   "), None);
+    }
+
+    #[test]
+    fn terms_match_name_and_description() {
+        let mut store = LocalFileStore::new();
+        let file = metadata(&mut store, 10, "Jazz.mp3");
+        assert!(Want::parse("jazz live").unwrap().matches(&file));
+        assert!(!Want::parse("jazz studio").unwrap().matches(&file));
+        assert!(Want::Hash(file.hash.clone()).matches(&file));
+    }
+
+    #[test]
+    fn wants_are_saved_between_sessions() {
+        let dir = TempDir::new().unwrap();
+        let mut wanted = WantedList::load(dir.path());
+        assert!(wanted.add(Want::parse("jazz").unwrap()));
+        assert!(!wanted.add(Want::parse("jazz").unwrap()));
+        assert!(wanted.add(Want::parse("blues").unwrap()));
+        wanted.set_publish(true);
+        assert_eq!(wanted.remove(1), Some(Want::parse("jazz").unwrap()));
+        assert_eq!(wanted.remove(5), None);
+
+        let wanted = WantedList::load(dir.path());
+        assert_eq!(wanted.wants(), [Want::parse("blues").unwrap()]);
+        assert!(wanted.publish());
+    }
+
+    #[test]
+    fn each_match_is_only_reported_once() {
+        let dir = TempDir::new().unwrap();
+        let mut store = LocalFileStore::new();
+        let file = metadata(&mut store, 10, "jazz.mp3");
+        let mut wanted = WantedList::load(dir.path());
+        wanted.add(Want::parse("jazz").unwrap());
+
+        assert_eq!(wanted.check(&file), Some(Want::parse("jazz").unwrap()));
+        assert_eq!(wanted.check(&file), None);
+    }
+
+    #[test]
+    fn offers_prefer_their_wants_then_similar_sizes() {
+        let mut ours = LocalFileStore::new();
+        for (size, name) in [
+            (10, "a.txt"),
+            (100, "b.txt"),
+            (1000, "c.txt"),
+            (5000, "d.txt"),
+        ] {
+            metadata(&mut ours, size, name);
+        }
+        let mut theirs = LocalFileStore::new();
+        let wanted_file = metadata(&mut theirs, 90, "theirs.txt");
+
+        let offers = suggest_offers(&ours, &[], &wanted_file);
+        let names: Vec<&str> = offers.iter().map(|m| m.filename.as_str()).collect();
+        assert_eq!(names, ["b.txt", "a.txt", "c.txt"]);
+
+        let offers = suggest_offers(&ours, &[Want::parse("d.txt").unwrap()], &wanted_file);
+        assert_eq!(offers.len(), 1);
+        assert_eq!(offers[0].filename, "d.txt");
+    }
+}